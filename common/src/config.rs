@@ -1,8 +1,8 @@
-use crate::mwu::{mwu_multiplier, AsReturn, Delta, WeightUpdate};
-use crate::util::{serde_black_box, SerdeLevelFilter};
+use crate::mwu::{mwu_multiplier, AsReturn, Delta, PerformanceNormalization, WeightUpdate};
+use crate::util::{serde_black_box, DateSerdeWrapper, MoneyFormat, SerdeLevelFilter};
 use anyhow::{anyhow, Context};
 use log::LevelFilter;
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
@@ -24,22 +24,51 @@ static GLOBAL_CONFIG: OnceLock<Config> = OnceLock::new();
 const ALPACA_KEY_ID_ENV_VAR: &str = "ALPACA_KEY_ID";
 const ALPACA_SECRET_KEY_ENV_VAR: &str = "ALPACA_SECRET_KEY";
 const FORCE_OPEN_ENV_VAR: &str = "FORCE_OPEN";
+const PROFILE_ENV_VAR: &str = "PROFILE";
 const CONFIG_PATH: &str = "./config.json";
 
 #[derive(Serialize)]
 pub struct Config {
     #[serde(serialize_with = "serde_black_box")]
     pub keys: ApiKeys,
+    pub profile: Option<String>,
     pub urls: Urls,
     pub trading: TradingConfig,
     pub indicator_periods: IndicatorPeriodConfig,
+    pub database_file: String,
+    pub intraday_dir: String,
     #[serde(serialize_with = "serde_black_box")]
     pub utc_offset: LocalOffset,
     pub force_open: bool,
     #[serde(with = "SerdeLevelFilter")]
     pub log_level_filter: LevelFilter,
+    pub log_buffer_lines: usize,
     pub request_rate_limit: usize,
     pub minimum_request_rate: usize,
+    pub adaptive_rate_limit: bool,
+    pub account_fetch_retries: u32,
+    pub stream_failover_attempts: u32,
+    pub stream_stable_connection_minutes: u64,
+    pub enable_fractional_trading: bool,
+    pub history_batch_size: usize,
+    pub repair_batch_size: usize,
+    pub skip_history_update_on_failure: bool,
+    pub history_update_threads: usize,
+    pub bar_audit_dir: Option<String>,
+    #[serde(serialize_with = "serde_black_box")]
+    pub notify_webhook_url: Option<String>,
+    pub observe_until: Option<DateSerdeWrapper>,
+    pub dump_pretty: bool,
+    pub dump_compressed: bool,
+    pub money_format: MoneyFormat,
+    pub equity_curve_file: Option<String>,
+    pub performance_normalization: PerformanceNormalization,
+    pub performance_half_life_days: Option<f64>,
+    pub decimal_change_percent: bool,
+    pub interpolated_indicators: InterpolatedIndicators,
+    pub metadata_decimal_scale: u32,
+    pub debug_commands: bool,
+    pub indicator_debug_symbols: HashSet<Symbol>,
     extra: HashMap<String, Value>,
 }
 
@@ -48,12 +77,12 @@ impl Config {
         GLOBAL_CONFIG.get().expect("Config not set")
     }
 
-    pub fn init() -> anyhow::Result<()> {
-        let keys = ApiKeys::from_env()?;
-
+    /// Loads the config from disk. If a profile is given, or set via the `PROFILE` env var, its
+    /// keys, URLs and trading parameters replace the top-level ones.
+    pub fn init(profile: Option<String>) -> anyhow::Result<()> {
         let config_path = Path::new(CONFIG_PATH);
 
-        let on_disk_config = if config_path.exists() {
+        let mut on_disk_config = if config_path.exists() {
             let mut config_file = OpenOptions::new()
                 .read(true)
                 .write(false)
@@ -86,6 +115,42 @@ impl Config {
             default
         };
 
+        let profile = match profile {
+            Some(profile) => Some(profile),
+            None => read_opt_env_var(PROFILE_ENV_VAR)?,
+        };
+
+        let mut profile_keys = None;
+        let mut profile_database_file = None;
+        if let Some(name) = &profile {
+            let profile = on_disk_config
+                .profiles
+                .remove(name)
+                .ok_or_else(|| anyhow!("No profile named {name} in config"))?;
+
+            if let Some(urls) = profile.urls {
+                on_disk_config.urls = urls;
+            }
+
+            if let Some(trading) = profile.trading {
+                on_disk_config.trading = trading;
+            }
+
+            profile_keys = profile.keys;
+            profile_database_file = profile.database_file;
+        }
+
+        // Each profile gets its own database by default so that instances for different accounts
+        // can run from the same directory
+        let database_file = profile_database_file
+            .or(on_disk_config.database_file)
+            .unwrap_or_else(|| match &profile {
+                Some(name) => format!("./market-data-{name}.db"),
+                None => "./market-data.db".to_owned(),
+            });
+
+        let keys = ApiKeys::from_env(profile_keys)?;
+
         let utc_offset = match UtcOffset::current_local_offset() {
             Ok(offset) => LocalOffset::new(offset),
             Err(_) => on_disk_config
@@ -117,16 +182,181 @@ impl Config {
             ));
         }
 
+        if on_disk_config.stream_failover_attempts == 0 {
+            return Err(anyhow!("Stream failover attempts must be positive"));
+        }
+
+        // Decimals support at most 28 decimal places
+        if on_disk_config.metadata_decimal_scale > 28 {
+            return Err(anyhow!("Metadata decimal scale must be at most 28"));
+        }
+
+        if on_disk_config.history_batch_size == 0 {
+            return Err(anyhow!("History batch size must be positive"));
+        }
+
+        if on_disk_config.repair_batch_size == 0 {
+            return Err(anyhow!("Repair batch size must be positive"));
+        }
+
+        if on_disk_config.indicator_periods.so_d == 0 {
+            return Err(anyhow!("Stochastic oscillator %D period must be positive"));
+        }
+
+        if let Err(error) = Symbol::from_str(&on_disk_config.trading.beta_benchmark) {
+            return Err(anyhow!("Invalid beta benchmark symbol: {error}"));
+        }
+
+        if on_disk_config.indicator_periods.avg_span_period < 2 {
+            return Err(anyhow!("Average span period must be at least 2"));
+        }
+
+        if on_disk_config.trading.max_position_equity_fraction
+            <= on_disk_config.trading.minimum_position_equity_fraction
+        {
+            return Err(anyhow!(
+                "Maximum position equity fraction must be greater than the minimum"
+            ));
+        }
+
+        if let Some((key, _)) = on_disk_config
+            .trading
+            .strategy_max_allocation
+            .iter()
+            .find(|(_, &cap)| cap <= Decimal::ZERO || cap > Decimal::ONE)
+        {
+            return Err(anyhow!(
+                "Maximum allocation of strategy {key} must be in (0, 1]"
+            ));
+        }
+
+        let default_avg_span = on_disk_config.trading.default_avg_span;
+        if default_avg_span <= 0.0 || !default_avg_span.is_finite() {
+            return Err(anyhow!("Default average span must be positive"));
+        }
+
+        if on_disk_config.trading.minimum_price < Decimal::ZERO {
+            return Err(anyhow!("Minimum price must be non-negative"));
+        }
+
+        if on_disk_config.trading.minimum_trade_position_fraction < Decimal::ZERO {
+            return Err(anyhow!(
+                "Minimum trade position fraction must be non-negative"
+            ));
+        }
+
+        if on_disk_config.trading.atr_multiplier <= 0.0
+            || !on_disk_config.trading.atr_multiplier.is_finite()
+        {
+            return Err(anyhow!("ATR multiplier must be positive"));
+        }
+
+        let bands = &on_disk_config.trading.trailing_stop_bands;
+        if bands.iter().any(|band| {
+            !(band.threshold_multiple > 0.0 && band.threshold_multiple.is_finite())
+                || band.sell_fraction <= Decimal::ZERO
+                || band.sell_fraction > Decimal::ONE
+        }) {
+            return Err(anyhow!(
+                "Trailing stop band multiples must be positive and sell fractions must be in (0, 1]"
+            ));
+        }
+
+        if bands
+            .windows(2)
+            .any(|window| window[0].threshold_multiple >= window[1].threshold_multiple)
+        {
+            return Err(anyhow!(
+                "Trailing stop bands must be in order of increasing threshold multiple"
+            ));
+        }
+
+        // Ticks are scheduled across the session, so a zero tick would never advance and a tick
+        // longer than an hour would rarely fire at all
+        if !(1..=3600).contains(&on_disk_config.trading.seconds_per_tick) {
+            return Err(anyhow!(
+                "Seconds per tick must be between 1 and 3600 (one hour)"
+            ));
+        }
+
+        if on_disk_config.trading.atr_period == 0 {
+            return Err(anyhow!("ATR period must be positive"));
+        }
+
+        if on_disk_config.trading.max_qty_decimal_places > 28 {
+            return Err(anyhow!("Max quantity decimal places must be at most 28"));
+        }
+
+        if on_disk_config.trading.minimum_cash_absolute < Decimal::ZERO {
+            return Err(anyhow!("Minimum absolute cash must be non-negative"));
+        }
+
+        if on_disk_config.trading.overnight_gap_risk_threshold < Decimal::ZERO {
+            return Err(anyhow!("Overnight gap risk threshold must be non-negative"));
+        }
+
+        if on_disk_config.trading.max_entry_spread_fraction < Decimal::ZERO {
+            return Err(anyhow!(
+                "Maximum entry spread fraction must be non-negative"
+            ));
+        }
+
+        let margin_utilization = on_disk_config.trading.margin_utilization;
+        if margin_utilization <= Decimal::ZERO || margin_utilization > Decimal::ONE {
+            return Err(anyhow!("Margin utilization must be in (0, 1]"));
+        }
+
+        if let Some(half_life) = on_disk_config.performance_half_life_days {
+            if half_life <= 0.0 || !half_life.is_finite() {
+                return Err(anyhow!("Performance half-life must be positive"));
+            }
+        }
+
+        if let PerformanceNormalization::Softmax { temperature } =
+            on_disk_config.performance_normalization
+        {
+            if temperature <= 0.0 || !temperature.is_finite() {
+                return Err(anyhow!("Softmax temperature must be positive"));
+            }
+        }
+
         let me = Self {
             keys,
+            profile,
             urls: on_disk_config.urls,
             trading: on_disk_config.trading,
             indicator_periods: on_disk_config.indicator_periods,
+            database_file,
+            intraday_dir: on_disk_config.intraday_dir,
             utc_offset,
             force_open,
             log_level_filter: on_disk_config.log_level_filter,
+            log_buffer_lines: on_disk_config.log_buffer_lines,
             request_rate_limit: on_disk_config.request_rate_limit,
             minimum_request_rate: on_disk_config.minimum_request_rate,
+            adaptive_rate_limit: on_disk_config.adaptive_rate_limit,
+            account_fetch_retries: on_disk_config.account_fetch_retries,
+            stream_failover_attempts: on_disk_config.stream_failover_attempts,
+            stream_stable_connection_minutes: on_disk_config.stream_stable_connection_minutes,
+            enable_fractional_trading: on_disk_config.enable_fractional_trading,
+            history_batch_size: on_disk_config.history_batch_size,
+            repair_batch_size: on_disk_config.repair_batch_size,
+            skip_history_update_on_failure: on_disk_config.skip_history_update_on_failure,
+            history_update_threads: on_disk_config.history_update_threads,
+            bar_audit_dir: on_disk_config.bar_audit_dir,
+            notify_webhook_url: on_disk_config.notify_webhook_url,
+            observe_until: on_disk_config.observe_until,
+            dump_pretty: on_disk_config.dump_pretty,
+            dump_compressed: on_disk_config.dump_compressed,
+            money_format: on_disk_config.money_format,
+            equity_curve_file: on_disk_config.equity_curve_file,
+            performance_normalization: on_disk_config.performance_normalization,
+            performance_half_life_days: on_disk_config.performance_half_life_days,
+            decimal_change_percent: on_disk_config.decimal_change_percent,
+            interpolated_indicators: on_disk_config.interpolated_indicators,
+            metadata_decimal_scale: on_disk_config.metadata_decimal_scale,
+            debug_commands: on_disk_config.debug_commands,
+            indicator_debug_symbols: on_disk_config.indicator_debug_symbols,
             extra: on_disk_config.extra,
         };
 
@@ -142,6 +372,14 @@ impl Config {
         mwu_multiplier(delta, Self::get().trading.eta)
     }
 
+    /// Applies one day of the configured performance decay toward 1.0, if any.
+    pub fn decay_performance(performance: f64) -> f64 {
+        match Self::get().performance_half_life_days {
+            Some(half_life) => performance.powf(0.5f64.powf(1.0 / half_life)),
+            None => performance,
+        }
+    }
+
     pub fn localize(datetime: OffsetDateTime) -> OffsetDateTime {
         datetime.to_offset(Self::get().utc_offset.get())
     }
@@ -172,16 +410,22 @@ impl Config {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ApiKeys {
     pub alpaca_key_id: String,
     pub alpaca_secret_key: String,
 }
 
 impl ApiKeys {
-    fn from_env() -> anyhow::Result<Self> {
-        let alpaca_key_id = read_env_var(ALPACA_KEY_ID_ENV_VAR)?;
-        let alpaca_secret_key = read_env_var(ALPACA_SECRET_KEY_ENV_VAR)?;
+    // The env vars take precedence over any keys specified by the active profile
+    fn from_env(profile_keys: Option<ApiKeys>) -> anyhow::Result<Self> {
+        let (profile_key_id, profile_secret_key) = match profile_keys {
+            Some(keys) => (Some(keys.alpaca_key_id), Some(keys.alpaca_secret_key)),
+            None => (None, None),
+        };
+
+        let alpaca_key_id = read_env_var_or(ALPACA_KEY_ID_ENV_VAR, profile_key_id)?;
+        let alpaca_secret_key = read_env_var_or(ALPACA_SECRET_KEY_ENV_VAR, profile_secret_key)?;
 
         Ok(Self {
             alpaca_key_id,
@@ -195,6 +439,9 @@ pub struct Urls {
     pub alpaca_api_base: String,
     pub alpaca_data_api: String,
     pub alpaca_stream_url: String,
+    // Stream URLs tried in order when the primary one keeps failing
+    #[serde(default)]
+    pub alpaca_stream_fallback_urls: Vec<String>,
     pub alpaca_stream_endpoint: String,
 }
 
@@ -204,13 +451,16 @@ impl Default for Urls {
             alpaca_api_base: "https://api.alpaca.markets/v2".to_owned(),
             alpaca_data_api: "https://data.alpaca.markets/v2".to_owned(),
             alpaca_stream_url: "wss://stream.data.alpaca.markets/v2".to_owned(),
+            alpaca_stream_fallback_urls: Vec::new(),
             alpaca_stream_endpoint: "iex".to_owned(),
         }
     }
 }
 
-fn read_env_var(env_var: &str) -> anyhow::Result<String> {
-    read_opt_env_var(env_var)?.ok_or_else(|| anyhow!("Missing required env var {env_var}"))
+fn read_env_var_or(env_var: &str, default: Option<String>) -> anyhow::Result<String> {
+    read_opt_env_var(env_var)?
+        .or(default)
+        .ok_or_else(|| anyhow!("Missing required env var {env_var}"))
 }
 
 fn read_opt_env_var(env_var: &str) -> anyhow::Result<Option<String>> {
@@ -271,18 +521,135 @@ impl<'de> Deserialize<'de> for LocalOffset {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct TradingConfig {
     pub pre_open_hours_offset: u8,
     pub seconds_per_tick: u64,
     pub minimum_median_volume: u64,
+    // Symbols whose last close is below this price are excluded from market-wide candidates
+    pub minimum_price: Decimal,
+    // Buys are skipped when the bid-ask spread of the latest quote exceeds this fraction of the mid
+    // price. Sells are never checked so that risk can always be reduced. Set to 0 to disable.
+    pub max_entry_spread_fraction: Decimal,
     pub minimum_cash_fraction: Decimal,
+    // Dollar amount of cash which is never deployed. The effective floor is the larger of this
+    // and minimum_cash_fraction of equity
+    pub minimum_cash_absolute: Decimal,
+    // Whether margin accounts deploy capital based on buying power rather than cash. Has no
+    // effect on cash accounts.
+    pub use_margin: bool,
+    // Fraction of buying power which may be deployed when use_margin is set
+    pub margin_utilization: Decimal,
     pub target_cash_fraction: Decimal,
     pub minimum_position_equity_fraction: Decimal,
+    // Upper bound on the fraction of usable equity placed in a single symbol. Any excess is held
+    // as cash.
+    pub max_position_equity_fraction: Decimal,
+    // Upper bound on the share of usable equity commanded by each listed strategy, keyed by
+    // strategy key. The excess is redistributed to the other strategies in proportion to their
+    // weights, or held as cash if every strategy is capped.
+    pub strategy_max_allocation: HashMap<String, Decimal>,
     pub minimum_trade_equity_fraction: Decimal,
+    // Trades changing an existing position by less than this fraction of its current value are
+    // skipped, in addition to the equity-based minimum
+    pub minimum_trade_position_fraction: Decimal,
     pub tsl_kill_threshold: Decimal,
+    // The kill threshold isn't checked for this many minutes after the first tick of each session,
+    // so that a stale high water mark loaded from the metadata can't liquidate everything at once
+    pub watchdog_grace_minutes: u32,
     pub eta: Decimal,
+    // Whether to resubmit a rejected buy order once, shrunk to fit the available buying power
+    pub retry_rejected_orders: bool,
+    // Whether to refuse orders which contradict the current position or an order still in flight
+    // for the same symbol, such as a buy while a sell is pending
+    pub order_side_safety: bool,
+    // Minimum number of minutes between buy/sell triggers for the same symbol
+    pub trigger_cooldown_minutes: u32,
+    // Open orders older than this are cancelled. Any orders still open at close are cancelled
+    // regardless of age.
+    pub order_max_age_minutes: u32,
+    // If no minute bars arrive from the stream for this many minutes while the market is open, the
+    // latest bars of held symbols are polled over REST until the stream recovers. Set to 0 to
+    // disable.
+    pub stream_stale_minutes: u32,
+    // Minute bars whose start time is more than this many minutes in the past when received, such
+    // as those delivered late after a reconnect, are dropped. Set to 0 to disable.
+    pub max_bar_age_minutes: u32,
+    // Symbols sold at a loss within this many days are not considered as candidates. Set to 0 to
+    // disable.
+    pub wash_sale_window_days: u32,
+    // Maximum number of symbols not already held which may be bought in a single session. Adding
+    // to existing positions is unaffected. Unlimited if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_new_positions_per_day: Option<usize>,
+    // Minimum number of daily bars a symbol needs in local history before strategies which rank
+    // symbols by their recent returns will consider it. Strategy-specific minimums still apply.
+    pub min_candidate_history_days: usize,
+    // Number of days of returns the correlations between held positions are computed over
+    pub correlation_lookback_days: usize,
+    // Pairs of positions correlated above this are reported as a concentration risk
+    pub correlation_warning_threshold: f64,
+    // Symbol whose daily returns the portfolio beta is measured against, over the correlation
+    // lookback
+    pub beta_benchmark: String,
+    // Average span used for the trailing thresholds of a symbol when its metadata could not be
+    // read. Symbols which have no metadata at all are not triggered.
+    pub default_avg_span: f64,
+    // Whether the trailing thresholds are derived from the average span or the average true range
+    // of a symbol. Symbols with no ATR fall back to the span.
+    pub trailing_mode: TrailingMode,
+    // How target equities and buy notionals are rounded to cents. Buy notionals are always capped
+    // at the available cash after rounding, and sell notionals are always rounded toward zero.
+    pub money_rounding: MoneyRounding,
+    // What the watchdog does when the account reports zero or negative equity. This is usually a
+    // transient glitch or an unfunded account rather than a real total loss.
+    pub non_positive_equity_action: NonPositiveEquityAction,
+    // In ATR mode, the trailing threshold is this many ATRs below the high or above the low
+    pub atr_multiplier: f64,
+    // Number of days the average true range is taken over
+    pub atr_period: usize,
+    // Trailing stop bands, as multiples of the trailing threshold below the high water mark, each
+    // with the fraction of the position sold once the loss reaches it. A band is acted on once per
+    // session, and deeper bands supersede shallower ones so a fast drop is still caught. When
+    // empty, a loss between one and two thresholds triggers a rebalance near the close instead.
+    pub trailing_stop_bands: Vec<TrailingStopBand>,
+    // Whether to stop opening new positions intraday when an account subject to pattern day
+    // trading rules is close to its day trade limit. Sells are never blocked by this.
+    pub pdt_protection: bool,
+    // Number of day trades to keep in reserve before PDT protection kicks in
+    pub pdt_reserved_daytrades: u32,
+    // Whether to stop opening positions for the rest of the session once an account subject to
+    // pattern day trading rules has only one day trade left, so that no new round trips can be
+    // made. Unlike PDT protection, this stays in effect until the next open even if the count
+    // drops.
+    pub pdt_no_round_trips: bool,
+    // No new positions are opened and no partial sells are made within this many minutes after the
+    // open or before the close. Positions may still be liquidated. Set to 0 to disable. Since
+    // triggers only fire in the final seconds of the session, any before-close window suppresses
+    // all trades other than liquidations.
+    pub no_trade_after_open_minutes: u32,
+    pub no_trade_before_close_minutes: u32,
+    // Positions are flagged at the close when an overnight gap of their typical daily range (the
+    // average span, or ATR in ATR mode) would lose more than this fraction of equity. Set to 0 to
+    // disable.
+    pub overnight_gap_risk_threshold: Decimal,
+    // Whether flagged positions are trimmed down to the threshold in the final seconds of the
+    // session, rather than only warned about
+    pub trim_overnight_gap_risk: bool,
+    // Whether all but the most recent of several open orders for the same symbol and side are
+    // cancelled when reconciling orders, rather than only warned about. Duplicates can be left
+    // behind by a crash partway through resubmitting an order. Only day orders submitted by the
+    // engine are cancelled; manual and GTC orders are never touched.
+    pub cancel_duplicate_orders: bool,
+    // Maximum number of decimal places in the quantity of an order for a fractionable asset.
+    // Quantities for other assets are always rounded down to whole shares.
+    pub max_qty_decimal_places: u32,
     #[serde(default, skip_serializing_if = "HashSet::is_empty")]
     pub blacklist: HashSet<Symbol>,
+    // Symbols which are always considered as candidates and subscribed to, bypassing the volume
+    // and price filters. Blacklisted and untradable symbols are still excluded.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub always_include: HashSet<Symbol>,
 }
 
 impl Default for TradingConfig {
@@ -291,18 +658,112 @@ impl Default for TradingConfig {
             pre_open_hours_offset: 3,
             seconds_per_tick: 10,
             minimum_median_volume: 750_000,
+            minimum_price: Decimal::ONE,
+            max_entry_spread_fraction: Decimal::ZERO,
             minimum_cash_fraction: Decimal::new(1, 2),
+            minimum_cash_absolute: Decimal::ZERO,
+            use_margin: false,
+            margin_utilization: Decimal::new(5, 1),
             target_cash_fraction: Decimal::new(25, 3),
             minimum_position_equity_fraction: Decimal::new(5, 2),
+            max_position_equity_fraction: Decimal::ONE,
+            strategy_max_allocation: HashMap::new(),
             minimum_trade_equity_fraction: Decimal::new(1, 2),
+            minimum_trade_position_fraction: Decimal::ZERO,
             tsl_kill_threshold: Decimal::new(5, 1),
+            watchdog_grace_minutes: 5,
             eta: Decimal::ONE,
+            retry_rejected_orders: false,
+            order_side_safety: true,
+            trigger_cooldown_minutes: 5,
+            order_max_age_minutes: 30,
+            stream_stale_minutes: 3,
+            max_bar_age_minutes: 5,
+            wash_sale_window_days: 30,
+            max_new_positions_per_day: None,
+            min_candidate_history_days: 2,
+            correlation_lookback_days: 60,
+            correlation_warning_threshold: 0.8,
+            beta_benchmark: "SPY".to_owned(),
+            default_avg_span: 0.02,
+            trailing_mode: TrailingMode::Span,
+            money_rounding: MoneyRounding::Floor,
+            non_positive_equity_action: NonPositiveEquityAction::Skip,
+            atr_multiplier: 0.5,
+            atr_period: 14,
+            trailing_stop_bands: Vec::new(),
+            pdt_protection: true,
+            pdt_reserved_daytrades: 1,
+            pdt_no_round_trips: false,
+            no_trade_after_open_minutes: 0,
+            no_trade_before_close_minutes: 0,
+            overnight_gap_risk_threshold: Decimal::ZERO,
+            trim_overnight_gap_risk: false,
+            cancel_duplicate_orders: false,
+            max_qty_decimal_places: 9,
             blacklist: HashSet::new(),
+            always_include: HashSet::new(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// `Recompute` feeds the repeated bar through the indicator calculations as if it were a real, flat
+/// day, which pulls averages towards it and attributes the previous day's volume to it in the
+/// volume-weighted indicators. `CarryForward` copies the previous day's indicators and leaves the
+/// metadata untouched, which is the sounder choice since no new information arrived that day.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InterpolatedIndicators {
+    #[default]
+    Recompute,
+    CarryForward,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct TrailingStopBand {
+    pub threshold_multiple: f64,
+    pub sell_fraction: Decimal,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingMode {
+    Span,
+    Atr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MoneyRounding {
+    // Toward zero, so amounts are never rounded up
+    Floor,
+    // To the nearest cent, with ties to even
+    Bankers,
+}
+
+impl MoneyRounding {
+    pub fn round(self, amount: Decimal) -> Decimal {
+        let strategy = match self {
+            Self::Floor => RoundingStrategy::ToZero,
+            Self::Bankers => RoundingStrategy::MidpointNearestEven,
+        };
+        amount.round_dp_with_strategy(2, strategy)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonPositiveEquityAction {
+    // Skip the trailing stop loss check until the equity is positive again
+    Skip,
+    // Enter safety mode without liquidating
+    SafetyMode,
+    // Treat it as a real loss, which trips the trailing stop loss kill threshold
+    Liquidate,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct IndicatorPeriodConfig {
     // Accumulation/distribution line
     pub adl: usize,
@@ -315,8 +776,14 @@ pub struct IndicatorPeriodConfig {
     pub rsi: usize,
     // Stochastic oscillator
     pub so: usize,
+    // Smoothing period of the stochastic oscillator %D
+    pub so_d: usize,
     // How far back to look when calculating performance
     pub perf: usize,
+    // Period N of the EMA used for the average daily span, which scales trailing stop thresholds.
+    // Each day's span is weighted by 2/N, so N must be at least 2. Stored metadata only reflects a
+    // change after running repair_records, which recomputes the same EMA over the symbol's history
+    pub avg_span_period: usize,
 }
 
 impl IndicatorPeriodConfig {
@@ -327,6 +794,7 @@ impl IndicatorPeriodConfig {
             .max(self.obv)
             .max(self.rsi)
             .max(self.so)
+            .max(self.so_d)
             .max(self.perf)
     }
 }
@@ -340,22 +808,106 @@ impl Default for IndicatorPeriodConfig {
             obv: 28,
             rsi: 14,
             so: 14,
+            so_d: 3,
             perf: 5,
+            avg_span_period: 30,
         }
     }
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 struct OnDiskConfig {
     urls: Urls,
     trading: TradingConfig,
     indicator_periods: IndicatorPeriodConfig,
+    // Path of the SQLite market data database. Defaults to ./market-data.db, or
+    // ./market-data-<profile>.db when a profile is active.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_file: Option<String>,
+    // Directory the intraday price data is dumped into at each close. Created at startup if missing.
+    intraday_dir: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     utc_offset: Option<LocalOffset>,
     #[serde(with = "SerdeLevelFilter")]
     log_level_filter: LevelFilter,
+    // Number of recent log records kept in memory for the tail command. Set to 0 to disable.
+    log_buffer_lines: usize,
     request_rate_limit: usize,
     minimum_request_rate: usize,
+    // Whether requests are also throttled when the remaining request budget reported by Alpaca
+    // runs low, rather than only by our own count of recent requests
+    adaptive_rate_limit: bool,
+    // Number of times fetching positions and the account is retried, with exponential backoff,
+    // at startup and at each pre-open and open before giving up. The fetches on each tick and at
+    // the close are not retried
+    account_fetch_retries: u32,
+    // Number of consecutive failed or short-lived stream connections after which the next stream
+    // URL is tried
+    stream_failover_attempts: u32,
+    // A stream connection which lasts at least this long counts as successful, so the next
+    // reconnect goes back to the primary URL
+    stream_stable_connection_minutes: u64,
+    // Whether to turn on fractional trading for the account at pre-open if it's found disabled.
+    // Notional orders, which the engine relies on, are rejected without it.
+    enable_fractional_trading: bool,
+    // Maximum number of symbols requested at once when fetching bar history, which bounds the
+    // length of the request URL
+    history_batch_size: usize,
+    // Number of symbols whose history is fetched and repaired together when repairing records.
+    // A failure only loses the batch it occurred in.
+    repair_batch_size: usize,
+    // Whether the pre-open continues with the existing market data when updating the history
+    // fails, rather than failing and entering safety mode
+    skip_history_update_on_failure: bool,
+    // Number of threads used to compute each day's indicators when updating the history. Set to 0
+    // to use one per available core.
+    history_update_threads: usize,
+    // Directory to which the raw bar responses from Alpaca are appended, along with the parameters
+    // of the request, in one file per day. This is verbose, so it's disabled if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bar_audit_dir: Option<String>,
+    // Webhook to which a JSON body of the form {"text": "..."} is posted when the engine enters
+    // safety mode. Notifications are disabled if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notify_webhook_url: Option<String>,
+    // Orders are only logged, not submitted, before this date (in the local timezone). This lets a
+    // new deployment run its full pipeline for a few days before it's trusted to trade.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    observe_until: Option<DateSerdeWrapper>,
+    // Whether the state dump written to disk should be human-readable
+    dump_pretty: bool,
+    // Whether the state dump is gzipped, in which case each dump is written to its own .json.gz
+    // file, named after its label or the time of the dump
+    dump_compressed: bool,
+    money_format: MoneyFormat,
+    // CSV file to which the account equity is appended at each close
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    equity_curve_file: Option<String>,
+    performance_normalization: PerformanceNormalization,
+    // If set, each symbol's performance is pulled back toward 1.0 on every update so that a
+    // deviation halves in this many days, in log space. This makes the market-wide strategies
+    // favor recent winners over symbols whose performance comes mostly from a distant run.
+    // Existing metadata only reflects a change after running repair_records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    performance_half_life_days: Option<f64>,
+    // Whether daily change percents and performance multipliers are computed in Decimal rather
+    // than f64, which reduces drift in the accumulated performance
+    decimal_change_percent: bool,
+    // How indicators are updated on days where a symbol's market data is missing and its previous
+    // bar is repeated in its place
+    interpolated_indicators: InterpolatedIndicators,
+    // Number of decimal places to which strategy weights, fractions and equities are rounded when
+    // written to the metadata file. Values in memory keep their full precision.
+    metadata_decimal_scale: u32,
+    // Enables commands meant for manual testing, such as injecting clock events. These should
+    // never be enabled in production.
+    debug_commands: bool,
+    // Symbols whose computed indicators are logged on each history update
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    indicator_debug_symbols: HashSet<Symbol>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    profiles: HashMap<String, Profile>,
     #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
@@ -376,11 +928,51 @@ impl Default for OnDiskConfig {
             urls: Urls::default(),
             trading: TradingConfig::default(),
             indicator_periods: IndicatorPeriodConfig::default(),
+            database_file: None,
+            intraday_dir: "intraday".to_owned(),
             utc_offset: None,
             log_level_filter: LevelFilter::Trace,
+            log_buffer_lines: 500,
             request_rate_limit: 200,
             minimum_request_rate: 120,
+            adaptive_rate_limit: true,
+            account_fetch_retries: 3,
+            stream_failover_attempts: 3,
+            stream_stable_connection_minutes: 10,
+            enable_fractional_trading: false,
+            history_batch_size: 200,
+            repair_batch_size: 25,
+            skip_history_update_on_failure: true,
+            history_update_threads: 0,
+            bar_audit_dir: None,
+            notify_webhook_url: None,
+            observe_until: None,
+            dump_pretty: true,
+            dump_compressed: false,
+            money_format: MoneyFormat::default(),
+            equity_curve_file: None,
+            performance_normalization: PerformanceNormalization::default(),
+            performance_half_life_days: None,
+            decimal_change_percent: false,
+            interpolated_indicators: InterpolatedIndicators::default(),
+            metadata_decimal_scale: 8,
+            debug_commands: false,
+            indicator_debug_symbols: HashSet::new(),
+            profiles: HashMap::new(),
             extra: HashMap::new(),
         }
     }
 }
+
+// Per-account overrides selected at startup. Any section left out falls back to the top-level one.
+#[derive(Serialize, Deserialize)]
+struct Profile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keys: Option<ApiKeys>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    urls: Option<Urls>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trading: Option<TradingConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_file: Option<String>,
+}
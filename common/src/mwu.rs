@@ -1,4 +1,5 @@
 use rust_decimal::{Decimal, MathematicalOps};
+use serde::{Deserialize, Serialize};
 
 pub trait AsReturn {
     fn as_return(&self) -> Self;
@@ -78,3 +79,125 @@ impl WeightUpdate<f64> for Decimal {
         clamp_return(*self).powf(eta)
     }
 }
+
+/// How symbol performance is rescaled across the market when it is read. The stored performance is
+/// the raw running product of MWU multipliers, which can grow or shrink without bound, so the
+/// normalized values are derived from it rather than written back.
+///
+/// Every mode preserves the ordering of symbols, so the symbols `MwuMarketTop5` selects are
+/// unaffected. What changes is the relative size of the initial weights it assigns them: `L2`
+/// keeps the ratios between performances, whereas `Softmax` exaggerates (low temperature) or
+/// flattens (high temperature) the gaps between them.
+#[derive(Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum PerformanceNormalization {
+    #[default]
+    None,
+    L2,
+    Softmax {
+        temperature: f64,
+    },
+}
+
+impl PerformanceNormalization {
+    pub fn normalize(&self, values: &mut [f64]) {
+        match *self {
+            Self::None => (),
+            Self::L2 => {
+                let norm = values.iter().map(|value| value * value).sum::<f64>().sqrt();
+
+                if norm > 0.0 {
+                    values.iter_mut().for_each(|value| *value /= norm);
+                }
+            }
+            Self::Softmax { temperature } => {
+                // Subtract the max before exponentiating to avoid overflow
+                let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                values
+                    .iter_mut()
+                    .for_each(|value| *value = ((*value - max) / temperature).exp());
+
+                let sum = values.iter().sum::<f64>();
+                if sum > 0.0 {
+                    values.iter_mut().for_each(|value| *value /= sum);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic returns in [-0.05, 0.05) from a linear congruential generator
+    fn synthetic_returns(seed: u64) -> impl Iterator<Item = f64> {
+        let mut state = seed;
+        std::iter::from_fn(move || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            Some((state >> 11) as f64 / (1u64 << 53) as f64 * 0.1 - 0.05)
+        })
+    }
+
+    // Runs the raw performance of a small market forward for many days, checking the normalized
+    // view derived from it each day. Symbol 0 has a steady edge over the others.
+    fn check_bounded(normalization: PerformanceNormalization) {
+        const SYMBOLS: usize = 20;
+        const DAYS: usize = 2500;
+
+        let mut raw = vec![1.0f64; SYMBOLS];
+        let mut returns = synthetic_returns(42);
+        for _ in 0..DAYS {
+            for (index, performance) in raw.iter_mut().enumerate() {
+                let edge = if index == 0 { 0.01 } else { 0.0 };
+                *performance *= 1.0 + returns.next().unwrap() + edge;
+            }
+
+            let mut view = raw.clone();
+            normalization.normalize(&mut view);
+
+            assert!(view
+                .iter()
+                .all(|value| value.is_finite() && (0.0..=1.0).contains(value)));
+            // Normalizing preserves the ordering, so the best raw performer stays on top
+            let best_raw = (0..SYMBOLS)
+                .max_by(|&a, &b| raw[a].total_cmp(&raw[b]))
+                .unwrap();
+            let best_view = (0..SYMBOLS)
+                .max_by(|&a, &b| view[a].total_cmp(&view[b]))
+                .unwrap();
+            assert_eq!(view[best_raw], view[best_view]);
+        }
+
+        // The stored values keep their spread rather than collapsing toward uniform
+        let mut view = raw.clone();
+        normalization.normalize(&mut view);
+        let min = view.iter().copied().fold(f64::INFINITY, f64::min);
+        assert!(view[0] > min);
+    }
+
+    #[test]
+    fn l2_performance_stays_bounded() {
+        check_bounded(PerformanceNormalization::L2);
+    }
+
+    #[test]
+    fn softmax_performance_stays_bounded() {
+        check_bounded(PerformanceNormalization::Softmax { temperature: 1.0 });
+    }
+
+    #[test]
+    fn normalizing_the_same_raw_values_is_stable() {
+        let raw = [3.0, 1.5, 0.75, 1.0];
+        let normalization = PerformanceNormalization::Softmax { temperature: 0.5 };
+
+        let mut first = raw.to_vec();
+        normalization.normalize(&mut first);
+        let mut second = raw.to_vec();
+        normalization.normalize(&mut second);
+
+        assert_eq!(first, second);
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
     any::type_name_of_val,
     cmp::Ordering,
+    collections::HashMap,
     fmt::{self, Display, Formatter},
 };
 
+use crate::config::Config;
 use log::{warn, LevelFilter};
 use once_cell::sync::Lazy;
 use rust_decimal::{prelude::FromPrimitive, Decimal};
@@ -12,12 +14,58 @@ use serde::{
     Deserialize, Deserializer, Serialize, Serializer,
 };
 use time::{
+    error::ComponentRange,
     format_description::{self, FormatItem},
-    Date, Month,
+    Date, Month, OffsetDateTime, UtcOffset,
 };
 
 pub const SECONDS_TO_DAYS: i64 = 24 * 60 * 60;
 
+/// Returns the pulldate, the number of days since the Unix epoch, of the trading day the given
+/// time falls on in US/Eastern rather than in UTC. Alpaca stamps daily bars at midnight Eastern,
+/// so a daily bar's pulldate is its session date.
+pub fn pulldate_of(time: OffsetDateTime) -> i64 {
+    date_to_pulldate(time.to_offset(eastern_offset(time)).date())
+}
+
+/// Returns the US/Eastern offset in effect at the given time. Daylight saving time runs from 2am
+/// local time on the second Sunday of March to 2am local time on the first Sunday of November.
+pub fn eastern_offset(time: OffsetDateTime) -> UtcOffset {
+    const EST: i8 = -5;
+    const EDT: i8 = -4;
+
+    let year = time.to_offset(UtcOffset::UTC).year();
+    // 2am EST and 2am EDT respectively
+    let dst_start = nth_sunday(year, Month::March, 2)
+        .with_hms(7, 0, 0)
+        .map(|time| time.assume_utc());
+    let dst_end = nth_sunday(year, Month::November, 1)
+        .with_hms(6, 0, 0)
+        .map(|time| time.assume_utc());
+
+    let hours = match (dst_start, dst_end) {
+        (Ok(start), Ok(end)) if time >= start && time < end => EDT,
+        _ => EST,
+    };
+    UtcOffset::from_hms(hours, 0, 0).unwrap_or(UtcOffset::UTC)
+}
+
+// The nth Sunday of the month, counting from 1
+fn nth_sunday(year: i32, month: Month, n: u8) -> Date {
+    let first = Date::from_calendar_date(year, month, 1).unwrap_or(Date::MIN);
+    let days_until_sunday = (7 - first.weekday().number_days_from_sunday()) % 7;
+    let day = 1 + days_until_sunday + 7 * (n - 1);
+    Date::from_calendar_date(year, month, day).unwrap_or(first)
+}
+
+pub fn date_to_pulldate(date: Date) -> i64 {
+    date.midnight().assume_utc().unix_timestamp() / SECONDS_TO_DAYS
+}
+
+pub fn pulldate_to_date(pulldate: i64) -> Result<Date, ComponentRange> {
+    OffsetDateTime::from_unix_timestamp(pulldate * SECONDS_TO_DAYS).map(|time| time.date())
+}
+
 pub static TIME_FORMAT: Lazy<Vec<FormatItem<'static>>> = Lazy::new(|| {
     format_description::parse("[hour repr:24]:[minute]:[second]")
         .expect("Invalid time format description")
@@ -28,7 +76,7 @@ pub static DATE_FORMAT: Lazy<Vec<FormatItem<'static>>> =
 
 #[inline]
 pub fn f64_to_decimal(float: f64) -> Result<Decimal, DecimalConversionError> {
-    Decimal::from_f64(float).ok_or(DecimalConversionError)
+    Decimal::from_f64(float).ok_or(DecimalConversionError { value: float })
 }
 
 #[inline]
@@ -39,12 +87,67 @@ pub fn decimal_to_f64(x: Decimal) -> f64 {
     })
 }
 
+// Controls how monetary values are rendered in human-facing output
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MoneyFormat {
+    pub precision: usize,
+    pub group_thousands: bool,
+}
+
+impl MoneyFormat {
+    pub fn format(&self, value: Decimal) -> String {
+        self.group(format!("{:.*}", self.precision, value))
+    }
+
+    pub fn format_f64(&self, value: f64) -> String {
+        self.group(format!("{:.*}", self.precision, value))
+    }
+
+    fn group(&self, formatted: String) -> String {
+        if !self.group_thousands {
+            return formatted;
+        }
+
+        let (sign, unsigned) = match formatted.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", &*formatted),
+        };
+        let (integer, fraction) = match unsigned.find('.') {
+            Some(index) => unsigned.split_at(index),
+            None => (unsigned, ""),
+        };
+
+        let mut grouped = String::with_capacity(formatted.len() + integer.len() / 3);
+        grouped.push_str(sign);
+        for (index, digit) in integer.chars().enumerate() {
+            if index > 0 && (integer.len() - index) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(digit);
+        }
+        grouped.push_str(fraction);
+        grouped
+    }
+}
+
+impl Default for MoneyFormat {
+    fn default() -> Self {
+        Self {
+            precision: 2,
+            group_thousands: false,
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct DecimalConversionError;
+pub struct DecimalConversionError {
+    pub value: f64,
+}
 
 impl Display for DecimalConversionError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Failed to convert f64 to Decmal")
+        write!(f, "Failed to convert f64 value {} to Decimal", self.value)
     }
 }
 
@@ -159,3 +262,123 @@ where
 {
     Serialize::serialize(type_name_of_val(value), serializer)
 }
+
+fn round_for_metadata(value: Decimal) -> Decimal {
+    value.round_dp(Config::get().metadata_decimal_scale)
+}
+
+/// Serializes a decimal rounded to the configured metadata scale, so that persisted values don't
+/// churn in the last few digits between runs.
+pub fn serialize_rounded_decimal<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    round_for_metadata(*value).serialize(serializer)
+}
+
+/// Like `serialize_rounded_decimal`, but for each value of a map.
+pub fn serialize_rounded_decimal_map<K, S>(
+    map: &HashMap<K, Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    K: Serialize,
+    S: Serializer,
+{
+    serializer.collect_map(
+        map.iter()
+            .map(|(key, &value)| (key, round_for_metadata(value))),
+    )
+}
+
+/// Like `serialize_rounded_decimal`, but for each value of a map of maps.
+pub fn serialize_rounded_decimal_nested_map<K1, K2, S>(
+    map: &HashMap<K1, HashMap<K2, Decimal>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    K1: Serialize,
+    K2: Serialize,
+    S: Serializer,
+{
+    struct RoundedMap<'a, K>(&'a HashMap<K, Decimal>);
+
+    impl<K: Serialize> Serialize for RoundedMap<'_, K> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_rounded_decimal_map(self.0, serializer)
+        }
+    }
+
+    serializer.collect_map(map.iter().map(|(key, inner)| (key, RoundedMap(inner))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(year: i32, month: Month, day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day)
+            .unwrap()
+            .with_hms(hour, minute, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    fn date(year: i32, month: Month, day: u8) -> i64 {
+        date_to_pulldate(Date::from_calendar_date(year, month, day).unwrap())
+    }
+
+    #[test]
+    fn eastern_offset_switches_at_dst_boundaries() {
+        let est = UtcOffset::from_hms(-5, 0, 0).unwrap();
+        let edt = UtcOffset::from_hms(-4, 0, 0).unwrap();
+
+        // 2024 DST ran from March 10 to November 3
+        assert_eq!(eastern_offset(utc(2024, Month::March, 10, 6, 59)), est);
+        assert_eq!(eastern_offset(utc(2024, Month::March, 10, 7, 0)), edt);
+        assert_eq!(eastern_offset(utc(2024, Month::November, 3, 5, 59)), edt);
+        assert_eq!(eastern_offset(utc(2024, Month::November, 3, 6, 0)), est);
+        assert_eq!(eastern_offset(utc(2024, Month::January, 15, 12, 0)), est);
+        assert_eq!(eastern_offset(utc(2024, Month::July, 15, 12, 0)), edt);
+    }
+
+    #[test]
+    fn daily_bars_map_to_their_session_date_across_dst() {
+        // Daily bars are stamped at midnight Eastern, which is 05:00Z in winter and 04:00Z in
+        // summer
+        assert_eq!(
+            pulldate_of(utc(2024, Month::March, 8, 5, 0)),
+            date(2024, Month::March, 8)
+        );
+        assert_eq!(
+            pulldate_of(utc(2024, Month::March, 11, 4, 0)),
+            date(2024, Month::March, 11)
+        );
+        assert_eq!(
+            pulldate_of(utc(2024, Month::November, 1, 4, 0)),
+            date(2024, Month::November, 1)
+        );
+        assert_eq!(
+            pulldate_of(utc(2024, Month::November, 4, 5, 0)),
+            date(2024, Month::November, 4)
+        );
+    }
+
+    #[test]
+    fn evening_times_stay_on_the_eastern_day() {
+        // 8pm EDT and 7:30pm EST are already the next day in UTC
+        assert_eq!(
+            pulldate_of(utc(2024, Month::July, 2, 0, 0)),
+            date(2024, Month::July, 1)
+        );
+        assert_eq!(
+            pulldate_of(utc(2024, Month::December, 3, 0, 30)),
+            date(2024, Month::December, 2)
+        );
+        // Just after midnight EDT is the new day
+        assert_eq!(
+            pulldate_of(utc(2024, Month::July, 2, 4, 1)),
+            date(2024, Month::July, 2)
+        );
+    }
+}
@@ -7,25 +7,30 @@ use super::{
 use crate::{
     engine::tax::TaxReport,
     event::{
+        command,
         stream::{StreamRequest, StreamRequestSender},
-        ClockEvent, Command, EngineEvent, EventReceiver, StreamEvent,
+        ClockEvent, Command, EngineEvent, EventReceiver, OrderAmount, StreamEvent,
     },
-    PortfolioStrategySubcommand, TaxSubcommand,
+    logging, PortfolioStrategySubcommand, TaxSubcommand,
 };
 use anyhow::Context;
-use common::{config::Config, util::serde_black_box};
+use common::{
+    config::{Config, NonPositiveEquityAction, TrailingMode},
+    util::{f64_to_decimal, serde_black_box, DATE_FORMAT, TIME_FORMAT},
+};
 use entity::{
     data::Bar,
-    trading::{Account, AssetStatus, Position},
+    trading::{Account, AssetStatus, Equity, OrderRequest, OrderSide, Position},
 };
-use history::{LocalHistory, LocalHistoryImpl};
+use flate2::{write::GzEncoder, Compression};
+use history::{LocalHistory, LocalHistoryImpl, Timeframe};
 use log::{debug, error, info, log, trace, warn, Level};
-use rest::AlpacaRestApi;
-use rust_decimal::Decimal;
+use rest::{AlpacaRestApi, RequestOrderStatus};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     fs,
     io::{self, Cursor, Write},
     path::Path,
@@ -38,8 +43,11 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     task,
 };
+use uuid::Uuid;
 
 const METADATA_FILE: &str = "metadata.json";
+const METADATA_BACKUP_FILE: &str = "metadata.json.bak";
+const UNIVERSE_CACHE_FILE: &str = "universe_cache.json";
 
 #[derive(Serialize)]
 pub struct Engine {
@@ -53,11 +61,19 @@ pub struct Engine {
     pub liquidate: bool,
     pub clock_info: ClockInfo,
     pub account_hwm: Decimal,
+    // Symbols which Alpaca no longer lists as active. Positions in these need manual review.
+    pub delisted: HashSet<Symbol>,
+    // Symbols whose records are repaired at the next pre-open rather than mid-session
+    pub repair_queue: BTreeSet<Symbol>,
 }
 
 #[derive(Serialize)]
 pub struct IntradayTracker {
-    pub blacklist: HashSet<Symbol>,
+    pub blacklist: HashMap<Symbol, BlacklistReason>,
+    // Symbols known to be tradable, either from the pre-open asset list or a later check
+    pub universe: HashSet<Symbol>,
+    // Symbols sold at a loss recently, which are excluded from the candidate set
+    pub recent_loss_sales: HashSet<Symbol>,
     pub price_tracker: PriceTracker,
     pub order_manager: OrderManager,
     pub portfolio_manager: PortfolioManager,
@@ -65,6 +81,31 @@ pub struct IntradayTracker {
     pub stream: StreamRequestSender,
     pub last_position_map: HashMap<Symbol, Position>,
     pub last_account: Account,
+    // Time of the most recent buy or sell trigger for each symbol
+    pub last_triggers: HashMap<Symbol, OffsetDateTime>,
+    // Index of the deepest trailing stop band acted on for each symbol this session
+    pub trailing_bands_hit: HashMap<Symbol, usize>,
+    // Symbols not previously held which were bought since the last open
+    pub new_positions: HashSet<Symbol>,
+    // When the most recent minute bar arrived from the stream
+    pub last_stream_bar: Option<OffsetDateTime>,
+    // Whether bars are being polled because the stream stopped delivering them
+    pub polling_bars: bool,
+    // Time of the last polled bar for each symbol, so the same bar isn't recorded twice
+    pub polled_bars: HashMap<Symbol, OffsetDateTime>,
+    // Whether new positions are blocked until the next open because the account is one day trade
+    // away from the PDT limit
+    pub no_round_trips: bool,
+    // Whether positions have been trimmed for overnight gap risk this session, and which ones. The
+    // trimmed symbols aren't bought back before the close.
+    pub gap_risk_trimmed: bool,
+    pub gap_trimmed_symbols: HashSet<Symbol>,
+    // The time of the first watchdog check this session, from which its grace period runs
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub first_watchdog_tick: Option<OffsetDateTime>,
+    // Whether the account last reported zero or negative equity, so that it's only warned about
+    // when it changes
+    pub non_positive_equity: bool,
 }
 
 #[derive(Serialize, Default)]
@@ -82,6 +123,12 @@ pub struct EngineMetadata {
     pub tax_tracker: TaxTracker,
     #[serde(default)]
     pub account_hwm: Option<Decimal>,
+    #[serde(default)]
+    pub delisted: HashSet<Symbol>,
+    #[serde(default)]
+    pub gtc_orders: HashSet<Uuid>,
+    #[serde(default)]
+    pub repair_queue: BTreeSet<Symbol>,
 }
 
 impl EngineMetadata {
@@ -103,8 +150,29 @@ impl EngineMetadata {
                 .await
                 .context("Failed to read metadata file")?;
 
-            serde_json::from_str(&buf)
-                .with_context(|| format!("Failed to parse {METADATA_FILE}"))?
+            if buf.trim().is_empty() {
+                warn!("{METADATA_FILE} is empty, starting with default metadata");
+                Self::default()
+            } else {
+                match serde_json::from_str(&buf) {
+                    Ok(meta) => meta,
+                    Err(error) => {
+                        error!("Failed to parse {METADATA_FILE}: {error:?}");
+                        tokio::fs::copy(metadata_path, METADATA_BACKUP_FILE)
+                            .await
+                            .with_context(|| {
+                                format!(
+                                    "Failed to back up {METADATA_FILE} to {METADATA_BACKUP_FILE}"
+                                )
+                            })?;
+                        warn!(
+                            "Backed up corrupt metadata to {METADATA_BACKUP_FILE}, starting with \
+                             default metadata"
+                        );
+                        Self::default()
+                    }
+                }
+            }
         } else {
             Self::default()
         };
@@ -129,6 +197,33 @@ impl EngineMetadata {
     }
 }
 
+// The tradable universe and blacklist built from the last successful fetch of all US equities. This
+// is used in place of the full equity list if fetching it fails during pre-open.
+#[derive(Serialize, Deserialize)]
+struct UniverseCache {
+    #[serde(with = "time::serde::rfc3339")]
+    fetched_at: OffsetDateTime,
+    universe: HashSet<Symbol>,
+    blacklist: HashMap<Symbol, BlacklistReason>,
+    #[serde(default)]
+    fractionable: HashSet<Symbol>,
+}
+
+impl UniverseCache {
+    fn load() -> anyhow::Result<Self> {
+        let json = fs::read_to_string(UNIVERSE_CACHE_FILE)
+            .with_context(|| format!("Failed to read {UNIVERSE_CACHE_FILE}"))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Failed to parse {UNIVERSE_CACHE_FILE}"))
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize universe cache")?;
+        fs::write(UNIVERSE_CACHE_FILE, json)
+            .with_context(|| format!("Failed to write {UNIVERSE_CACHE_FILE}"))
+    }
+}
+
 pub async fn run(events: EventReceiver, rest: AlpacaRestApi, stream: StreamRequestSender) {
     let metadata = match EngineMetadata::load().await {
         Ok(meta) => meta,
@@ -138,7 +233,7 @@ pub async fn run(events: EventReceiver, rest: AlpacaRestApi, stream: StreamReque
         }
     };
 
-    let local_history = match history::init_local_history().await {
+    let local_history = match history::init_local_history(&Config::get().database_file).await {
         Ok(hist) => Arc::new(hist),
         Err(error) => {
             error!("Failed to initialize local history: {error:?}");
@@ -146,13 +241,26 @@ pub async fn run(events: EventReceiver, rest: AlpacaRestApi, stream: StreamReque
         }
     };
 
-    let order_manager = OrderManager::new(rest.clone());
+    let intraday_dir = &Config::get().intraday_dir;
+    if let Err(error) = fs::create_dir_all(intraday_dir) {
+        error!(
+            "Failed to create intraday data directory {intraday_dir}, intraday data will not be \
+             saved at close: {error}"
+        );
+    }
 
-    let (last_position_map, last_account) = match (rest.position_map().await, rest.account().await)
-    {
-        (Ok(position_map), Ok(account)) => (position_map, account),
-        _ => {
-            error!("Failed to fetch initial data from alpaca");
+    let mut order_manager = OrderManager::new(rest.clone(), metadata.gtc_orders);
+    order_manager.update_observe_mode();
+    // Use the last known fractionable symbols until the next pre-open refreshes them
+    if let Ok(cache) = UniverseCache::load() {
+        order_manager.set_fractionable_symbols(cache.fractionable);
+    }
+
+    let max_retries = Config::get().account_fetch_retries;
+    let (last_position_map, last_account) = match fetch_account_info(&rest, max_retries).await {
+        Ok(info) => info,
+        Err(error) => {
+            error!("Failed to fetch initial data from alpaca: {error:?}");
             return;
         }
     };
@@ -166,32 +274,68 @@ pub async fn run(events: EventReceiver, rest: AlpacaRestApi, stream: StreamReque
     };
 
     let account_hwm = metadata.account_hwm.unwrap_or(last_account.equity);
+    if last_account.equity <= Decimal::ZERO {
+        warn!(
+            "Account reported non-positive equity {} at startup",
+            last_account.equity
+        );
+    } else if account_hwm > Decimal::ZERO
+        && last_account.equity / account_hwm <= Config::get().trading.tsl_kill_threshold
+    {
+        warn!(
+            "Current equity {} is below the kill threshold relative to the stored high water mark \
+             {}; the watchdog will liquidate once the grace period ends unless this is resolved",
+            last_account.equity, account_hwm
+        );
+    }
+    local_history.set_delisted(metadata.delisted.clone()).await;
 
     let mut engine = Engine {
         rest,
         local_history,
         intraday: IntradayTracker {
-            blacklist: HashSet::new(),
+            blacklist: HashMap::new(),
+            universe: HashSet::new(),
+            recent_loss_sales: HashSet::new(),
             price_tracker: PriceTracker::new(),
             order_manager,
             portfolio_manager,
             stream,
             last_position_map,
             last_account,
+            last_triggers: HashMap::new(),
+            trailing_bands_hit: HashMap::new(),
+            new_positions: HashSet::new(),
+            last_stream_bar: None,
+            polling_bars: false,
+            polled_bars: HashMap::new(),
+            no_round_trips: false,
+            gap_risk_trimmed: false,
+            gap_trimmed_symbols: HashSet::new(),
+            first_watchdog_tick: None,
+            non_positive_equity: false,
         },
         tax_tracker: metadata.tax_tracker,
         in_safety_mode: false,
         liquidate: false,
         clock_info: ClockInfo::default(),
         account_hwm,
+        delisted: metadata.delisted,
+        repair_queue: metadata.repair_queue,
     };
 
     engine.run(events).await;
 
+    let local_history = Arc::clone(&engine.local_history);
     let metadata = engine.into_metadata();
     if let Err(error) = metadata.save().await {
         error!("Failed to save engine metadata: {error}");
     }
+
+    match Arc::try_unwrap(local_history) {
+        Ok(local_history) => local_history.close().await,
+        Err(_) => warn!("Could not close local history since it is still in use"),
+    }
 }
 
 impl Engine {
@@ -200,13 +344,27 @@ impl Engine {
             portfolio_metadata: self.intraday.portfolio_manager.into_metadata(),
             tax_tracker: self.tax_tracker,
             account_hwm: Some(self.account_hwm),
+            delisted: self.delisted,
+            gtc_orders: self.intraday.order_manager.gtc_orders().clone(),
+            repair_queue: self.repair_queue,
         }
     }
 
     fn enter_safety_mode(&mut self) {
         warn!("Entering safety mode");
+        let was_in_safety_mode = self.in_safety_mode;
         self.in_safety_mode = true;
         self.intraday.stream.send(StreamRequest::Close);
+
+        // The watchdog can call this on every tick, so only notify on the way in
+        if !was_in_safety_mode && Config::get().notify_webhook_url.is_some() {
+            let rest = self.rest.clone();
+            task::spawn(async move {
+                if let Err(error) = rest.notify("Stonkbot entered safety mode").await {
+                    warn!("Failed to send safety mode notification: {error:?}");
+                }
+            });
+        }
     }
 
     fn liquidate(&mut self) {
@@ -245,18 +403,100 @@ impl Engine {
         }
     }
 
-    async fn update_account_info(&mut self) -> anyhow::Result<()> {
-        self.intraday.last_position_map = self.rest.position_map().await?;
-        self.intraday.last_account = self.rest.account().await?;
+    // Symbols which accept fractional orders as of the last pre-open
+    pub fn fractionable_symbols(&self) -> &HashSet<Symbol> {
+        self.intraday.order_manager.fractionable_symbols()
+    }
+
+    // Only the pre-open and open updates retry, since a retry on every tick would hold up the tick
+    // loop for the whole backoff; a failed tick update is simply tried again on the next tick
+    async fn update_account_info(&mut self, retry: bool) -> anyhow::Result<()> {
+        let max_retries = if retry {
+            Config::get().account_fetch_retries
+        } else {
+            0
+        };
+        let (position_map, account) = fetch_account_info(&self.rest, max_retries).await?;
+        self.intraday.last_position_map = position_map;
+        self.intraday.last_account = account;
+        self.check_pdt_lockout();
         Ok(())
     }
 
+    fn check_pdt_lockout(&mut self) {
+        if !Config::get().trading.pdt_no_round_trips || self.intraday.no_round_trips {
+            return;
+        }
+
+        if let Some(remaining) = remaining_daytrades(&self.intraday.last_account) {
+            if remaining <= 1 {
+                warn!(
+                    "Only {remaining} day trades remaining under PDT rules; no new positions will \
+                     be opened until the next session"
+                );
+                self.intraday.no_round_trips = true;
+            }
+        }
+    }
+
+    // Fetches all US equities and splits them into tradable and non-tradable symbols, falling back
+    // to the last successful result if the request fails
+    async fn build_universe(&self) -> anyhow::Result<UniverseCache> {
+        let equities = match self.rest.us_equities().await {
+            Ok(equities) => equities,
+            Err(error) => {
+                let cache = UniverseCache::load().with_context(|| {
+                    format!(
+                        "Failed to fetch equities ({error:?}) and no cached universe is available"
+                    )
+                })?;
+                warn!(
+                    "Failed to fetch equities, using cached universe from {}: {error:?}",
+                    cache.fetched_at
+                );
+                return Ok(cache);
+            }
+        };
+
+        let mut cache = UniverseCache {
+            fetched_at: OffsetDateTime::now_utc(),
+            universe: HashSet::new(),
+            blacklist: HashMap::new(),
+            fractionable: HashSet::new(),
+        };
+        for equity in equities {
+            let symbol = match equity.symbol.to_symbol() {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+
+            if equity.fractionable {
+                cache.fractionable.insert(symbol);
+            }
+
+            match BlacklistReason::for_equity(&equity) {
+                Some(reason) => {
+                    cache.blacklist.insert(symbol, reason);
+                }
+                None => {
+                    cache.universe.insert(symbol);
+                }
+            }
+        }
+
+        if let Err(error) = cache.save() {
+            warn!("Failed to cache universe: {error:?}");
+        }
+
+        Ok(cache)
+    }
+
     async fn handle_clock_event(&mut self, event: ClockEvent) {
         match event {
             ClockEvent::PreOpen => {
                 debug!("Received pre-open event");
 
-                if let Err(error) = self.on_pre_open().await {
+                if let Err(error) = self.on_pre_open(false).await {
                     error!("Failed to run pre-open tasks: {error:?}");
                     self.enter_safety_mode();
                 }
@@ -322,7 +562,9 @@ impl Engine {
         }
     }
 
-    async fn on_pre_open(&mut self) -> anyhow::Result<()> {
+    // Fails if the history could not be updated after retrying, unless configured to continue
+    // with the existing data
+    async fn update_history_for_pre_open(&mut self) -> anyhow::Result<()> {
         let mut retries = 0;
 
         loop {
@@ -331,41 +573,121 @@ impl Engine {
                 .update_history_to_present(&self.rest, None)
                 .await
             {
-                Ok(()) => break,
+                Ok(()) => return Ok(()),
                 Err(error) => {
                     retries += 1;
                     error!("Failed to update database history: {error:?}. Retry {retries}/3");
 
-                    match Arc::get_mut(&mut self.local_history) {
-                        Some(hist) => {
-                            if let Err(error) = hist.refresh_connection().await {
-                                error!("Failed to refresh database connection: {error:?}");
-                            }
-                        }
-                        None => {
-                            warn!("Could not refresh database connecton due to concurrent tasks")
-                        }
+                    if let Err(error) = self.local_history.refresh_connection().await {
+                        error!("Failed to refresh database connection: {error:?}");
                     }
 
                     if retries >= 3 {
-                        break;
+                        if !Config::get().skip_history_update_on_failure {
+                            return Err(error.context("Failed to update database history"));
+                        }
+
+                        warn!(
+                            "CONTINUING PRE-OPEN WITHOUT UPDATING HISTORY; market data may be a \
+                             day or more behind"
+                        );
+                        return Ok(());
                     }
                 }
             }
         }
+    }
+
+    async fn check_account_config(&self) -> anyhow::Result<()> {
+        let mut account_config = self.rest.account_config().await?;
+
+        if !account_config.fractional_trading && Config::get().enable_fractional_trading {
+            if self.intraday.order_manager.is_observing() {
+                info!("Observing only, would enable fractional trading for the account");
+            } else {
+                info!("Fractional trading is disabled for the account, enabling it");
+                account_config = self
+                    .rest
+                    .set_fractional_trading(true)
+                    .await
+                    .context("Failed to enable fractional trading")?;
+            }
+        }
+
+        if !account_config.fractional_trading {
+            warn!(
+                "Fractional trading is disabled for the account; notional orders will be rejected"
+            );
+        }
+
+        if account_config.suspend_trade {
+            warn!("Trading is suspended for the account; all orders will be rejected");
+        }
+
+        Ok(())
+    }
+
+    async fn on_pre_open(&mut self, skip_update: bool) -> anyhow::Result<()> {
+        if skip_update {
+            warn!("Skipping the history update; market data may be a day or more behind");
+        } else {
+            self.update_history_for_pre_open().await?;
+        }
 
-        self.update_account_info().await?;
+        self.drain_repair_queue().await;
+
+        self.update_account_info(true).await?;
+
+        // The account config is changed before the open, so it has to know whether we're observing
+        // today
+        self.intraday.order_manager.update_observe_mode();
+        if let Err(error) = self.check_account_config().await {
+            warn!("Failed to check account configuration: {error:?}");
+        }
 
         // Construct the blacklist
-        let equities = self.rest.us_equities().await?;
-        self.intraday.blacklist = equities
-            .into_iter()
-            .filter(|equity| {
-                !(equity.tradable && equity.fractionable && equity.status == AssetStatus::Active)
+        let UniverseCache {
+            universe,
+            blacklist,
+            fractionable,
+            ..
+        } = self.build_universe().await?;
+        self.intraday.universe = universe;
+        self.intraday
+            .order_manager
+            .set_fractionable_symbols(fractionable);
+        self.intraday.blacklist = blacklist;
+        self.intraday.blacklist.extend(
+            Config::get()
+                .trading
+                .blacklist
+                .iter()
+                .map(|&symbol| (symbol, BlacklistReason::Config)),
+        );
+
+        // Held symbols missing from the list of active equities may have been delisted, while
+        // symbols flagged earlier are re-checked in case they've been listed again
+        let unlisted_symbols = self
+            .intraday
+            .last_position_map
+            .keys()
+            .filter(|symbol| {
+                !self.intraday.universe.contains(symbol)
+                    && !self.intraday.blacklist.contains_key(symbol)
             })
-            .flat_map(|equity| equity.symbol.to_symbol())
-            .chain(Config::get().trading.blacklist.iter().cloned())
-            .collect();
+            .chain(self.delisted.iter())
+            .copied()
+            .collect::<HashSet<_>>();
+        for symbol in unlisted_symbols {
+            if let Err(error) = self.check_delisted(symbol).await {
+                warn!("Failed to check whether {symbol} was delisted: {error:?}");
+            }
+        }
+        self.intraday.blacklist.extend(
+            self.delisted
+                .iter()
+                .map(|&symbol| (symbol, BlacklistReason::Delisted)),
+        );
 
         self.portfolio_manager_on_pre_open().await?;
 
@@ -375,14 +697,28 @@ impl Engine {
     }
 
     async fn on_open(&mut self) -> anyhow::Result<()> {
-        self.update_account_info().await?;
+        self.intraday.new_positions.clear();
+        self.intraday.no_round_trips = false;
+        self.intraday.gap_risk_trimmed = false;
+        self.intraday.gap_trimmed_symbols.clear();
+        self.intraday.first_watchdog_tick = None;
+        self.intraday.order_manager.update_observe_mode();
+        // Give the stream a chance to connect before considering it down
+        self.intraday.last_stream_bar = Some(OffsetDateTime::now_utc());
+        self.update_account_info(true).await?;
+
+        if let Err(error) = self.intraday.order_manager.reconcile_gtc_orders().await {
+            warn!("Failed to reconcile GTC orders: {error:?}");
+        }
+
         self.position_manager_on_open().await;
         Ok(())
     }
 
     async fn on_tick(&mut self) -> anyhow::Result<()> {
-        self.update_account_info().await?;
+        self.update_account_info(false).await?;
         self.tick_watchdog().await;
+        self.poll_bars_if_stream_down().await;
 
         if let Err(error) = self.intraday.order_manager.on_tick().await {
             warn!("Failed to tick order manager: {error}");
@@ -404,28 +740,116 @@ impl Engine {
             let current_equity = self.intraday.last_account.equity;
             self.account_hwm = Decimal::max(self.account_hwm, current_equity);
 
-            if self.account_hwm == Decimal::ZERO {
-                return;
+            let trading = &Config::get().trading;
+            let non_positive_equity = current_equity <= Decimal::ZERO;
+            if non_positive_equity != self.intraday.non_positive_equity {
+                if non_positive_equity {
+                    warn!("Account reported non-positive equity {current_equity}");
+                } else {
+                    info!("Account equity is positive again: {current_equity}");
+                }
+                self.intraday.non_positive_equity = non_positive_equity;
             }
 
-            let loss = current_equity / self.account_hwm;
-            let threshold = Config::get().trading.tsl_kill_threshold;
-            if loss <= threshold {
-                warn!("Trailing stop loss kill threshold reached: {loss} <= {threshold}");
-                self.liquidate();
+            // The grace period runs from the first tick of the session rather than from startup, so
+            // a bot started well before the open still gets it
+            let now = OffsetDateTime::now_utc();
+            let first_tick = *self.intraday.first_watchdog_tick.get_or_insert(now);
+            let grace = Duration::minutes(i64::from(trading.watchdog_grace_minutes));
+
+            match watchdog_action(
+                current_equity,
+                self.account_hwm,
+                trading.non_positive_equity_action,
+                trading.tsl_kill_threshold,
+                now - first_tick < grace,
+            ) {
+                WatchdogAction::Continue => {}
+                WatchdogAction::EnterSafetyMode => {
+                    if !self.in_safety_mode {
+                        self.enter_safety_mode();
+                    }
+                }
+                WatchdogAction::Liquidate { loss } => {
+                    error!(
+                        "Trailing stop loss kill threshold reached: {loss} <= {}. Equity: \
+                         {current_equity}, high water mark: {}. Liquidating all positions",
+                        trading.tsl_kill_threshold, self.account_hwm
+                    );
+                    self.liquidate();
+                }
             }
         }
     }
 
+    // Without minute bars from the stream the trailing stops go blind, so if the stream has been
+    // quiet for too long we poll the latest bars of held symbols instead
+    async fn poll_bars_if_stream_down(&mut self) {
+        let stale_minutes = Config::get().trading.stream_stale_minutes;
+        let last_stream_bar = match self.intraday.last_stream_bar {
+            Some(last_stream_bar) if stale_minutes > 0 => last_stream_bar,
+            _ => return,
+        };
+
+        let quiet_for = OffsetDateTime::now_utc() - last_stream_bar;
+        if quiet_for < Duration::minutes(i64::from(stale_minutes)) {
+            if self.intraday.polling_bars {
+                info!("Stream bars resumed, no longer polling bars");
+                self.intraday.polling_bars = false;
+                self.intraday.polled_bars.clear();
+            }
+            return;
+        }
+
+        if !self.intraday.polling_bars {
+            warn!(
+                "No stream bars received in {} minutes, polling bars for held symbols",
+                quiet_for.whole_minutes()
+            );
+            self.intraday.polling_bars = true;
+        }
+
+        let symbols = self
+            .intraday
+            .last_position_map
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        for symbol in symbols {
+            let bar = match self.rest.latest_bar::<Bar>(symbol).await {
+                Ok(Some(bar)) => bar,
+                Ok(None) => continue,
+                Err(error) => {
+                    warn!("Failed to poll latest bar for {symbol}: {error:?}");
+                    continue;
+                }
+            };
+
+            match self.intraday.polled_bars.get(&symbol) {
+                Some(&last_time) if last_time >= bar.time => continue,
+                _ => {
+                    self.intraday.polled_bars.insert(symbol, bar.time);
+                }
+            }
+
+            self.handle_stream_minute_bar(symbol, bar).await;
+        }
+    }
+
     async fn liquidate_open_positions(&mut self) {
-        for &symbol in self.intraday.last_position_map.keys() {
+        for (&symbol, position) in &self.intraday.last_position_map {
             if self
                 .intraday
                 .order_manager
                 .trade_status(symbol)
                 .is_sell_daytrade_safe()
             {
-                if let Err(error) = self.intraday.order_manager.liquidate(symbol).await {
+                if let Err(error) = self
+                    .intraday
+                    .order_manager
+                    .liquidate(symbol, Some(position))
+                    .await
+                {
                     error!("Failed to liquidate position in {symbol}: {error}");
                 }
             }
@@ -433,34 +857,138 @@ impl Engine {
     }
 
     async fn on_close(&mut self) -> anyhow::Result<()> {
+        self.intraday.order_manager.cancel_open_orders().await;
         self.intraday.order_manager.clear();
 
         let price_tracker_json = self.intraday.price_tracker.patched_json();
-        let file = format!(
-            "intraday/{}.json",
+        let file = Path::new(&Config::get().intraday_dir).join(format!(
+            "{}.json",
             Config::localize(OffsetDateTime::now_utc()).date()
-        );
+        ));
         match fs::write(&file, price_tracker_json) {
-            Ok(()) => info!("Wrote intraday data to {file}"),
-            Err(_) => {
-                info!("Could not write intraday data to {file}, does its parent directory exit?")
-            }
+            Ok(()) => info!("Wrote intraday data to {}", file.display()),
+            Err(error) => error!(
+                "Could not write intraday data to {}: {error}",
+                file.display()
+            ),
         }
 
         self.intraday.price_tracker.clear();
+        self.intraday.last_triggers.clear();
+        self.intraday.trailing_bands_hit.clear();
+        self.intraday.last_stream_bar = None;
+        self.intraday.polling_bars = false;
+        self.intraday.polled_bars.clear();
 
-        self.update_account_info().await?;
+        self.update_account_info(false).await?;
         self.portfolio_manager_on_close();
+        self.log_overnight_gap_risk().await;
+
+        if let Err(error) = self.log_equity_curve() {
+            error!("Failed to append to equity curve: {error:?}");
+        }
 
         Ok(())
     }
 
-    pub async fn get_avg_span(&mut self, symbol: Symbol) -> f64 {
+    // Repairs the records queued during previous sessions. Symbols stay queued if the repair fails.
+    async fn drain_repair_queue(&mut self) {
+        if self.repair_queue.is_empty() {
+            return;
+        }
+
+        let symbols = self.repair_queue.iter().copied().collect::<Vec<_>>();
+        info!("Repairing {} queued symbol(s)", symbols.len());
+        match self
+            .local_history
+            .repair_records(&self.rest, &symbols)
+            .await
+        {
+            Ok(()) => self.repair_queue.clear(),
+            Err(error) => error!("Failed to repair queued records: {error:?}"),
+        }
+    }
+
+    /// Checks whether Alpaca still lists the symbol as an active asset. The symbol is only recorded
+    /// as delisted if Alpaca doesn't know of it or reports it as inactive, and is un-flagged if it
+    /// has since become active again. Returns the asset if it is still listed.
+    pub async fn check_delisted(&mut self, symbol: Symbol) -> anyhow::Result<Option<Equity>> {
+        let asset = self
+            .rest
+            .asset(symbol)
+            .await
+            .context("Failed to fetch asset status")?;
+
+        match asset.as_ref().map(|asset| asset.status) {
+            Some(AssetStatus::Active) => {
+                if self.delisted.contains(&symbol) {
+                    info!("{symbol} is listed again");
+                    self.clear_delisted(&[symbol]).await;
+                }
+                Ok(asset)
+            }
+            Some(AssetStatus::Inactive) | None => {
+                if self.delisted.insert(symbol) {
+                    warn!("{symbol} has been delisted; it will no longer be traded");
+                    self.intraday
+                        .blacklist
+                        .insert(symbol, BlacklistReason::Delisted);
+                    self.local_history.set_delisted(self.delisted.clone()).await;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes the given symbols from the delisted set, or every symbol if none are given, so that
+    /// they can be traded again.
+    async fn clear_delisted(&mut self, symbols: &[Symbol]) -> usize {
+        let cleared = if symbols.is_empty() {
+            self.delisted.drain().collect::<Vec<_>>()
+        } else {
+            symbols
+                .iter()
+                .copied()
+                .filter(|symbol| self.delisted.remove(symbol))
+                .collect()
+        };
+
+        for symbol in &cleared {
+            if self.intraday.blacklist.get(symbol) == Some(&BlacklistReason::Delisted) {
+                self.intraday.blacklist.remove(symbol);
+            }
+        }
+
+        if !cleared.is_empty() {
+            self.local_history.set_delisted(self.delisted.clone()).await;
+        }
+
+        cleared.len()
+    }
+
+    // Returns None if the symbol has no metadata, in which case it should not be triggered
+    pub async fn get_avg_span(&mut self, symbol: Symbol) -> Option<f64> {
         match self.local_history.get_symbol_avg_span(symbol).await {
             Ok(span) => span,
             Err(error) => {
-                warn!("Failed to fetch span for {symbol}: {error:?}");
-                0.02
+                let default = Config::get().trading.default_avg_span;
+                warn!("Failed to fetch span for {symbol}, using default of {default}: {error:?}");
+                Some(default)
+            }
+        }
+    }
+
+    // Only fetched in ATR mode. Returns None if the span should be used instead.
+    pub async fn get_atr(&mut self, symbol: Symbol) -> Option<f64> {
+        if Config::get().trading.trailing_mode != TrailingMode::Atr {
+            return None;
+        }
+
+        match self.local_history.get_symbol_atr(symbol).await {
+            Ok(atr) => atr,
+            Err(error) => {
+                warn!("Failed to fetch ATR for {symbol}, using average span instead: {error:?}");
+                None
             }
         }
     }
@@ -472,7 +1000,26 @@ impl Engine {
             .unwrap_or(false)
     }
 
+    /// Whether the market opened or will close within the configured no-trade windows
+    pub fn in_no_trade_window(&self) -> bool {
+        let config = &Config::get().trading;
+        let after_open = Duration::minutes(i64::from(config.no_trade_after_open_minutes));
+        let before_close = Duration::minutes(i64::from(config.no_trade_before_close_minutes));
+
+        let near_open = self
+            .clock_info
+            .duration_since_open
+            .map(|since_open| since_open < after_open)
+            .unwrap_or(false);
+        near_open || self.within_duration_of_close(before_close)
+    }
+
     async fn handle_command(&mut self, command: Command) {
+        if self.in_safety_mode && !command.is_permitted_in_safety_mode() {
+            warn!("Ignoring {command:?}; the engine is in safety mode");
+            return;
+        }
+
         match command {
             Command::BuyToggle { allow } => {
                 if allow == self.intraday.order_manager.allow_buying {
@@ -491,6 +1038,79 @@ impl Engine {
                     }
                 }
             }
+            Command::Beta => {
+                if let Err(error) = self.log_beta().await {
+                    error!("Failed to compute portfolio beta: {error:?}");
+                }
+            }
+            Command::CheckDatabase => match self.local_history.integrity_check().await {
+                Ok(health) => {
+                    if health.is_ok() {
+                        info!("Database integrity check passed");
+                    } else {
+                        error!(
+                            "Database integrity check failed:\n{}",
+                            health.integrity.join("\n")
+                        );
+                    }
+
+                    for (table, count) in &health.row_counts {
+                        info!("{table}: {count} rows");
+                    }
+
+                    match health.date_range {
+                        Some((first, last)) => info!("Stored bars cover {first} to {last}"),
+                        None => info!("No bars are stored"),
+                    }
+                }
+                Err(error) => error!("Failed to check database: {error:?}"),
+            },
+            Command::CheckGaps { symbols } => {
+                for symbol in symbols {
+                    let gaps = match self
+                        .local_history
+                        .detect_gaps(symbol, Timeframe::After(OffsetDateTime::UNIX_EPOCH))
+                        .await
+                    {
+                        Ok(gaps) => gaps,
+                        Err(error) => {
+                            error!("Failed to check {symbol} for gaps: {error:?}");
+                            continue;
+                        }
+                    };
+
+                    if gaps.is_empty() {
+                        info!("No gaps in history for {symbol}");
+                        continue;
+                    }
+
+                    let gaps_string = gaps
+                        .iter()
+                        .map(|(start, end)| {
+                            if start == end {
+                                start.to_string()
+                            } else {
+                                format!("{start} to {end}")
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    warn!(
+                        "Found {} gaps in history for {symbol}: {gaps_string}",
+                        gaps.len()
+                    );
+                }
+            }
+            Command::ClearDelisted { symbols } => {
+                let cleared = self.clear_delisted(&symbols).await;
+                info!("Cleared {cleared} symbol(s) from the delisted set");
+            }
+            Command::Clock => self.log_clock(),
+            Command::Correlations => {
+                if let Err(error) = self.log_correlations().await {
+                    error!("Failed to compute correlations: {error:?}");
+                }
+            }
             Command::CurrentTrackedSymbols => {
                 let mut iter = self.intraday.price_tracker.tracked_symbols();
                 let mut cts_string = match iter.next() {
@@ -509,14 +1129,110 @@ impl Engine {
                 info!("Currently tracked symbols: {cts_string}")
             }
             // When the stream responds to this request we'll write the data out
-            Command::DumpState => self.intraday.stream.send(StreamRequest::DumpState),
+            Command::DumpState { label } => self
+                .intraday
+                .stream
+                .send(StreamRequest::DumpState { label }),
+            Command::EvalSymbols { symbols } => {
+                if let Err(error) = self.log_symbol_evaluations(&symbols).await {
+                    error!("Failed to evaluate symbols: {error:?}");
+                }
+            }
+            Command::FlushHistoryCache => {
+                self.local_history.invalidate_cache().await;
+                info!("Flushed local history cache");
+            }
+            Command::Indicators { symbol, date } => {
+                match self.local_history.get_indicators(symbol, date).await {
+                    Ok(Some(indicators)) => {
+                        info!("Indicators for {symbol} on {date}: {indicators:#?}")
+                    }
+                    Ok(None) => info!("No indicators stored for {symbol} on {date}"),
+                    Err(error) => error!("Failed to fetch indicators for {symbol}: {error:?}"),
+                }
+            }
+            Command::InjectClock { event } => {
+                info!("Injecting clock event {event:?}");
+                self.handle_clock_event(event).await;
+            }
             Command::Liquidate => self.liquidate(),
+            Command::LiquidateSymbol { symbol } => {
+                if let Err(error) = self.liquidate_symbol(symbol).await {
+                    error!("Failed to liquidate position in {symbol}: {error:?}");
+                }
+            }
+            Command::Order {
+                symbol,
+                side,
+                amount,
+                order_type,
+                limit_price,
+                time_in_force,
+                extended_hours,
+            } => {
+                let (qty, notional) = match amount {
+                    OrderAmount::Qty(qty) => {
+                        match self.intraday.order_manager.normalize_qty(symbol, qty).await {
+                            Ok(qty) => (Some(qty), None),
+                            Err(error) => {
+                                error!("Failed to normalize quantity for {symbol}: {error:?}");
+                                return;
+                            }
+                        }
+                    }
+                    OrderAmount::Notional(notional) => (None, Some(notional)),
+                };
+                let request = OrderRequest {
+                    symbol,
+                    qty,
+                    notional,
+                    side,
+                    order_type,
+                    time_in_force,
+                    limit_price,
+                    stop_price: None,
+                    trail_price: None,
+                    trail_percent: None,
+                    extended_hours: Some(extended_hours),
+                    client_order_id: None,
+                    order_class: None,
+                    take_profit: None,
+                    stop_loss: None,
+                };
+
+                if self.intraday.order_manager.is_observing() {
+                    info!(
+                        "Observing only, would submit manual {order_type:?} order to {side:?} \
+                         {symbol}"
+                    );
+                    return;
+                }
+
+                // Only GTC orders are tracked by the order manager, so that they're reconciled across
+                // sessions. Manual day orders won't be retried or cancelled.
+                match self.rest.submit_order(&request).await {
+                    Ok(order) => {
+                        info!(
+                            "Submitted manual {order_type:?} order {} to {side:?} {symbol} \
+                             ({time_in_force:?}, extended hours: {extended_hours})",
+                            order.id.hyphenated()
+                        );
+                        self.intraday.order_manager.track_gtc_order(order);
+                    }
+                    Err(error) => error!("Failed to submit manual order for {symbol}: {error:?}"),
+                }
+            }
             Command::PortfolioStrategy(subcommand) => match subcommand {
                 PortfolioStrategySubcommand::List => {
                     if let Err(error) = self.list_portfolio_strategies() {
                         error!("Failed to list portfolio strategies: {:?}", error);
                     }
                 }
+                PortfolioStrategySubcommand::Candidates => {
+                    if let Err(error) = self.list_portfolio_strategy_candidates() {
+                        error!("Failed to list portfolio strategy candidates: {:?}", error);
+                    }
+                }
                 PortfolioStrategySubcommand::Enable { key } => {
                     self.change_portfolio_strategy_state(&key, StrategyState::Active)
                 }
@@ -526,6 +1242,24 @@ impl Engine {
                 PortfolioStrategySubcommand::Disable { key } => {
                     self.change_portfolio_strategy_state(&key, StrategyState::Disabled)
                 }
+                PortfolioStrategySubcommand::SetWeight { key, weight } => {
+                    match self
+                        .intraday
+                        .portfolio_manager
+                        .set_strategy_weight(&key, weight)
+                    {
+                        Some(old) => info!("Set weight of {key} from {old} to {weight}"),
+                        None => info!("No portfolio strategy found with key {key}"),
+                    }
+                }
+            },
+            Command::WhyBlacklisted { symbol } => match self.intraday.blacklist.get(&symbol) {
+                Some(reason) => info!("{symbol} is blacklisted: {}", reason.describe()),
+                None if self.intraday.recent_loss_sales.contains(&symbol) => info!(
+                    "{symbol} is not blacklisted, but is excluded from candidates after a \
+                         recent loss sale"
+                ),
+                None => info!("{symbol} is not blacklisted"),
             },
             Command::PriceInfo { symbol } => {
                 let price_info = match self.intraday.price_tracker.price_info(symbol) {
@@ -538,11 +1272,49 @@ impl Engine {
 
                 Self::log_price_info(symbol, &price_info, Level::Info);
             }
-            Command::RunPreOpen => {
-                if let Err(error) = self.on_pre_open().await {
+            Command::RecentTrades { days } => {
+                if let Err(error) = self.log_recent_trades(days).await {
+                    error!("Failed to list recent trades: {error:?}");
+                }
+            }
+            Command::RunPreOpen { skip_update } => {
+                if let Err(error) = self.on_pre_open(skip_update).await {
                     error!("Failed to run pre-open: {error:?}");
                 }
             }
+            Command::SetIndicatorDebug { symbol, enable } => {
+                match self.local_history.set_indicator_debug(symbol, enable).await {
+                    Ok(()) if enable => info!("Enabled indicator logging for {symbol}"),
+                    Ok(()) => info!("Disabled indicator logging for {symbol}"),
+                    Err(error) => error!("Failed to set indicator logging: {error}"),
+                }
+            }
+            Command::SetTimezone { offset } => {
+                let previous = Config::get().utc_offset.get();
+                Config::get().utc_offset.set(offset);
+                self.clock_info.next_open = self.clock_info.next_open.map(Config::localize);
+                self.clock_info.next_close = self.clock_info.next_close.map(Config::localize);
+                info!("Updated UTC offset from {previous} to {offset}");
+                self.log_clock();
+            }
+            Command::TestNotify { message } => match self.rest.notify(&message).await {
+                Ok(()) => info!("Sent test notification"),
+                Err(error) => error!("Failed to send test notification: {error:?}"),
+            },
+            Command::ApiStats => self.log_api_stats(),
+            Command::ShowConfig => match serde_json::to_string_pretty(Config::get()) {
+                Ok(json) => info!("Current configuration:\n{json}"),
+                Err(error) => error!("Failed to serialize configuration: {error}"),
+            },
+            Command::ReplayIntraday { path } => {
+                if let Err(error) = self.replay_intraday(&path).await {
+                    error!("Failed to replay intraday data: {error:?}");
+                }
+            }
+            Command::Report { path } => match self.write_report(&path) {
+                Ok(()) => info!("Wrote report to {path}"),
+                Err(error) => error!("Failed to write report: {error:?}"),
+            },
             Command::RepairRecords { symbols } => {
                 if let Err(error) = self
                     .local_history
@@ -552,11 +1324,53 @@ impl Engine {
                     error!("Failed to repair records: {error:?}");
                 }
             }
+            Command::TrackSymbols { symbols } => {
+                let rest = self.rest.clone();
+                let local_history = Arc::clone(&self.local_history);
+
+                task::spawn(async move {
+                    if let Err(error) = track_symbols(rest, local_history, symbols).await {
+                        error!("Failed to track symbols: {error:?}");
+                    }
+                });
+            }
+            Command::QueueRepair { symbols } => {
+                self.repair_queue.extend(symbols);
+                info!(
+                    "{} symbol(s) queued for repair at the next pre-open",
+                    self.repair_queue.len()
+                );
+            }
+            Command::ShowRepairQueue => {
+                if self.repair_queue.is_empty() {
+                    info!("No symbols are queued for repair");
+                } else {
+                    let symbols = self
+                        .repair_queue
+                        .iter()
+                        .map(Symbol::to_string)
+                        .collect::<Vec<_>>();
+                    info!("Queued for repair: {}", symbols.join(", "));
+                }
+            }
+            Command::Orders => {
+                if let Err(error) = self.log_open_orders().await {
+                    error!("Failed to log open orders: {error:?}");
+                }
+            }
             Command::Status => {
                 if let Err(error) = self.log_status().await {
                     error!("Failed to log status: {:?}", error);
                 }
             }
+            Command::Tail { lines } => {
+                let records = logging::recent_records(lines);
+                if records.is_empty() {
+                    info!("No log records are buffered");
+                } else {
+                    info!("Last {} log records\n{}", records.len(), records.join("\n"));
+                }
+            }
             Command::Tax(subcommand) => match subcommand {
                 TaxSubcommand::Update => match self.tax_tracker.ingest(&self.rest).await {
                     Ok(()) => info!("Successfully updated tax records"),
@@ -574,18 +1388,19 @@ impl Engine {
                         }
                     };
 
+                    let money = &Config::get().money_format;
                     info!(
                         "Tax-aware gains and losses for {calendar_year}:\n\
-                        Net short-term gains: {:.2} ({:.2} - {:.2})\n\
-                        Net long-term gains: {:.2} ({:.2} - {:.2})\n\
-                        Dividends: {:.2}",
-                        capital.short_term_gains - capital.short_term_losses,
-                        capital.short_term_gains,
-                        capital.short_term_losses,
-                        capital.long_term_gains - capital.long_term_losses,
-                        capital.long_term_gains,
-                        capital.long_term_losses,
-                        dividends
+                        Net short-term gains: {} ({} - {})\n\
+                        Net long-term gains: {} ({} - {})\n\
+                        Dividends: {}",
+                        money.format(capital.short_term_gains - capital.short_term_losses),
+                        money.format(capital.short_term_gains),
+                        money.format(capital.short_term_losses),
+                        money.format(capital.long_term_gains - capital.long_term_losses),
+                        money.format(capital.long_term_gains),
+                        money.format(capital.long_term_losses),
+                        money.format(dividends)
                     );
                 }
             },
@@ -620,12 +1435,13 @@ impl Engine {
                 };
 
                 let config_blacklist = &Config::get().trading.blacklist;
+                let fractionable = self.fractionable_symbols();
                 let untracked_equities = equities
                     .into_iter()
                     .flat_map(|asset| asset.symbol.to_symbol().map(|symbol| (symbol, asset)))
                     .filter(|(symbol, asset)| {
                         asset.tradable
-                            && asset.fractionable
+                            && fractionable.contains(symbol)
                             && asset.status == AssetStatus::Active
                             && !local_symbols.contains(symbol)
                             && !config_blacklist.contains(symbol)
@@ -633,7 +1449,19 @@ impl Engine {
                     .map(|(symbol, _)| symbol)
                     .collect::<Vec<_>>();
 
-                let mut start = OffsetDateTime::now_utc() - Duration::days(1);
+                // Start from the last market day we have on record so the first request to
+                // Alpaca usually succeeds, rather than stepping back a day at a time
+                let mut start = match self.local_history.latest_bars().await {
+                    Ok(bars) => bars
+                        .values()
+                        .map(|bar| bar.time)
+                        .max()
+                        .unwrap_or_else(|| OffsetDateTime::now_utc() - Duration::days(1)),
+                    Err(error) => {
+                        warn!("Failed to fetch latest local bars: {error:?}");
+                        OffsetDateTime::now_utc() - Duration::days(1)
+                    }
+                };
                 let mut history = None;
 
                 for _ in 0..5 {
@@ -724,6 +1552,114 @@ impl Engine {
         Ok(())
     }
 
+    fn list_portfolio_strategy_candidates(&self) -> anyhow::Result<()> {
+        let mut buf = Cursor::new(Vec::<u8>::with_capacity(1024));
+        writeln!(buf, "Showing portfolio strategy candidates")?;
+
+        for (key, (state, candidates)) in self.intraday.portfolio_manager.strategy_candidates() {
+            writeln!(buf, "{key} ({state:?}):")?;
+
+            if candidates.is_empty() {
+                writeln!(buf, "  No effective candidates")?;
+                continue;
+            }
+
+            for (symbol, fraction) in candidates {
+                writeln!(
+                    buf,
+                    "  {:<8} {:.2}%",
+                    symbol.as_str(),
+                    fraction * Decimal::ONE_HUNDRED
+                )?;
+            }
+        }
+
+        let msg = match String::from_utf8(buf.into_inner()) {
+            Ok(msg) => msg,
+            Err(error) => {
+                error!("Invalid status message encoding: {error:?}");
+                return Ok(());
+            }
+        };
+
+        info!("{msg}");
+        Ok(())
+    }
+
+    fn write_report(&self, path: &str) -> anyhow::Result<()> {
+        const MAX_ALLOCATIONS: usize = 20;
+
+        let money = &Config::get().money_format;
+        let portfolio_manager = &self.intraday.portfolio_manager;
+        let mut buf = Cursor::new(Vec::<u8>::with_capacity(1024));
+
+        writeln!(buf, "# Engine Report")?;
+        writeln!(
+            buf,
+            "\nGenerated at {}\n",
+            Config::localize(OffsetDateTime::now_utc())
+        )?;
+        writeln!(
+            buf,
+            "Account high water mark: {}",
+            money.format(self.account_hwm)
+        )?;
+
+        writeln!(buf, "\n## Strategies\n")?;
+        writeln!(buf, "| Strategy | Weight | State |")?;
+        writeln!(buf, "| --- | --- | --- |")?;
+        for (key, (weight, state)) in portfolio_manager.strategy_weights() {
+            writeln!(buf, "| {key} | {weight:.4} | {state:?} |")?;
+        }
+
+        let allocations = portfolio_manager.target_allocations();
+        writeln!(buf, "\n## Target Allocations\n")?;
+        if allocations.is_empty() {
+            writeln!(buf, "No allocations recorded")?;
+        } else {
+            writeln!(buf, "| Symbol | Equity Fraction |")?;
+            writeln!(buf, "| --- | --- |")?;
+            for (symbol, fraction) in allocations.iter().take(MAX_ALLOCATIONS) {
+                writeln!(
+                    buf,
+                    "| {symbol} | {:.2}% |",
+                    fraction * Decimal::ONE_HUNDRED
+                )?;
+            }
+            if allocations.len() > MAX_ALLOCATIONS {
+                writeln!(
+                    buf,
+                    "\n{} more symbols not shown",
+                    allocations.len() - MAX_ALLOCATIONS
+                )?;
+            }
+        }
+
+        writeln!(buf, "\n## Taxes\n")?;
+        let years = self.tax_tracker.years();
+        if years.is_empty() {
+            writeln!(buf, "No tax records")?;
+        } else {
+            writeln!(buf, "| Year | Net Short-Term | Net Long-Term | Dividends |")?;
+            writeln!(buf, "| --- | --- | --- | --- |")?;
+            for year in years {
+                let TaxReport { trades, dividends } = self
+                    .tax_tracker
+                    .tax_report(year)
+                    .with_context(|| format!("Failed to generate tax report for {year}"))?;
+                writeln!(
+                    buf,
+                    "| {year} | {} | {} | {} |",
+                    money.format(trades.short_term_gains - trades.short_term_losses),
+                    money.format(trades.long_term_gains - trades.long_term_losses),
+                    money.format(dividends)
+                )?;
+            }
+        }
+
+        fs::write(path, buf.into_inner()).with_context(|| format!("Failed to write {path}"))
+    }
+
     fn change_portfolio_strategy_state(&mut self, key: &str, state: StrategyState) {
         match self
             .intraday
@@ -741,13 +1677,33 @@ impl Engine {
         }
     }
 
+    fn log_clock(&self) {
+        let now = OffsetDateTime::now_utc();
+        let describe = |event: Option<OffsetDateTime>| match event {
+            Some(event) => format!(
+                "{} (in {})",
+                format_local_datetime(event),
+                format_duration_compact(event - now)
+            ),
+            None => "N/A".to_owned(),
+        };
+
+        info!(
+            "Now: {}, next open: {}, next close: {}",
+            format_local_datetime(now),
+            describe(self.clock_info.next_open),
+            describe(self.clock_info.next_close)
+        );
+    }
+
     fn log_price_info(symbol: Symbol, price_info: &PriceInfo, level: Level) {
+        let money = &Config::get().money_format;
         log!(
             level,
-            "Price info for {symbol}:\nPrice: {:.2}\nNon-volatile Price: {:.2}\nHWM Loss: {:.3}\
+            "Price info for {symbol}:\nPrice: {}\nNon-volatile Price: {}\nHWM Loss: {:.3}\
             \nTime Since HWM: {}\nLWM Gain: {:.3}\nTime Since LWM: {}",
-            price_info.latest_price,
-            price_info.non_volatile_price,
+            money.format(price_info.latest_price),
+            money.format_f64(price_info.non_volatile_price),
             price_info.hwm_loss,
             price_info.time_since_hwm,
             price_info.lwm_gain,
@@ -791,8 +1747,45 @@ impl Engine {
         write!(buf, ", time until close: ")?;
         write_opt!(buf, self.clock_info.duration_until_close)?;
 
-        writeln!(buf, "\nCurrent Equity: {:.2}", account.equity)?;
-        writeln!(buf, "Cash: {:.2}", account.cash)?;
+        let money = &Config::get().money_format;
+        writeln!(buf, "\nCurrent Equity: {}", money.format(account.equity))?;
+        writeln!(buf, "Cash: {}", money.format(account.cash))?;
+        writeln!(
+            buf,
+            "Deployable capital: {}",
+            money.format(self.portfolio_manager_available_cash())
+        )?;
+        match remaining_daytrades(&account) {
+            Some(remaining) => writeln!(buf, "Day trades remaining: {remaining}")?,
+            None => writeln!(buf, "Day trades remaining: unlimited")?,
+        }
+        if self.intraday.no_round_trips {
+            writeln!(buf, "No new round trips until the next open")?;
+        }
+        match self.holdings_expected_return().await {
+            Ok(Some(expected_return)) => writeln!(
+                buf,
+                "Expected daily return of holdings: {:+.3}%",
+                expected_return * Decimal::ONE_HUNDRED
+            )?,
+            Ok(None) => writeln!(buf, "Expected daily return of holdings: unavailable")?,
+            Err(error) => {
+                warn!("Failed to compute expected return of holdings: {error:?}");
+                writeln!(buf, "Expected daily return of holdings: unavailable")?
+            }
+        }
+        match self.portfolio_beta().await {
+            Ok(Some(beta)) => writeln!(
+                buf,
+                "Portfolio beta vs {}: {beta:.3}",
+                Config::get().trading.beta_benchmark
+            )?,
+            Ok(None) => writeln!(buf, "Portfolio beta: unavailable")?,
+            Err(error) => {
+                warn!("Failed to compute portfolio beta: {error:?}");
+                writeln!(buf, "Portfolio beta: unavailable")?
+            }
+        }
 
         // Append position info
         if positions.is_empty() {
@@ -803,12 +1796,16 @@ impl Engine {
             for position in positions.iter() {
                 write!(
                     buf,
-                    "\n{:<9}{:<9.2}{:<12.2}{:<+18.3}",
+                    "\n{:<9}{:<9.2}{:<12}{:<+18.3}",
                     position.symbol,
                     position.qty,
-                    position.market_value,
+                    money.format(position.market_value),
                     position.unrealized_plpc * Decimal::new(100, 0)
                 )?;
+
+                if self.delisted.contains(&position.symbol) {
+                    write!(buf, "DELISTED - review manually")?;
+                }
             }
         }
 
@@ -825,12 +1822,172 @@ impl Engine {
         Ok(())
     }
 
+    async fn log_open_orders(&self) -> anyhow::Result<()> {
+        let orders = self
+            .rest
+            .get_orders(RequestOrderStatus::Open, 500, OffsetDateTime::UNIX_EPOCH)
+            .await
+            .context("Failed to fetch open orders")?;
+
+        if orders.is_empty() {
+            info!("There are no open orders");
+            return Ok(());
+        }
+
+        let money = &Config::get().money_format;
+        let describe_price = |price: Option<Decimal>| match price {
+            Some(price) => money.format(price),
+            None => "-".to_owned(),
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let mut lines = Vec::with_capacity(orders.len() + 1);
+        lines.push(
+            "Symbol   Side  Type           Qty           Limit       Stop        Filled     Age"
+                .to_owned(),
+        );
+        for order in &orders {
+            let side = match order.side {
+                OrderSide::Buy => "buy",
+                OrderSide::Sell => "sell",
+            };
+            let order_type = order
+                .order_type
+                .map(|order_type| format!("{order_type:?}"))
+                .unwrap_or_else(|| "-".to_owned());
+            let qty = match (order.qty, order.notional) {
+                (Some(qty), _) => format!("{qty}"),
+                (None, Some(notional)) => money.format(notional),
+                (None, None) => "-".to_owned(),
+            };
+
+            lines.push(format!(
+                "{:<9}{:<6}{:<15}{:<14}{:<12}{:<12}{:<11}{}",
+                order.symbol,
+                side,
+                order_type,
+                qty,
+                describe_price(order.limit_price),
+                describe_price(order.stop_price),
+                order.filled_qty.unwrap_or(Decimal::ZERO),
+                format_duration_compact(now - order.submitted_at)
+            ));
+        }
+
+        info!("Open orders\n{}", lines.join("\n"));
+        Ok(())
+    }
+
+    fn log_api_stats(&self) {
+        let request_stats = self.rest.request_stats();
+        let stats = request_stats.snapshot();
+        if stats.is_empty() {
+            info!("No API requests have been sent");
+            return;
+        }
+
+        let mut lines = Vec::with_capacity(stats.len() + 1);
+        lines.push(format!(
+            "{:<36}{:<10}{:<9}{:<7}{}",
+            "Endpoint", "Requests", "Errors", "429s", "Avg latency"
+        ));
+        for endpoint in &stats {
+            lines.push(format!(
+                "{:<36}{:<10}{:<9}{:<7}{}ms",
+                endpoint.endpoint,
+                endpoint.requests,
+                endpoint.errors,
+                endpoint.rate_limited,
+                endpoint.average_latency.as_millis()
+            ));
+        }
+
+        let requests = stats.iter().map(|endpoint| endpoint.requests).sum::<u64>();
+        let errors = stats.iter().map(|endpoint| endpoint.errors).sum::<u64>();
+        info!(
+            "API requests\n{}\nTotal: {requests} requests, {errors} errors ({:.1}%), {} retries",
+            lines.join("\n"),
+            errors as f64 * 100.0 / requests as f64,
+            request_stats.retries()
+        );
+    }
+
+    // Only orders submitted within the window are considered, so GTC orders submitted earlier but
+    // filled within it are left out
+    async fn log_recent_trades(&self, days: u32) -> anyhow::Result<()> {
+        let limit = 500;
+        let cutoff = OffsetDateTime::now_utc() - Duration::days(i64::from(days));
+        let mut after = cutoff;
+        let mut trades = Vec::new();
+
+        loop {
+            let orders = self
+                .rest
+                .get_orders(RequestOrderStatus::Closed, limit, after)
+                .await
+                .context("Failed to fetch closed orders")?
+                .into_iter()
+                .filter(|order| order.submitted_at > after)
+                .collect::<Vec<_>>();
+
+            if orders.is_empty() {
+                break;
+            }
+
+            after = orders.last().unwrap().submitted_at;
+            trades.extend(orders.into_iter().filter(|order| {
+                order
+                    .filled_at
+                    .map_or(false, |filled_at| filled_at >= cutoff)
+                    && order.filled_qty.map_or(false, |qty| qty > Decimal::ZERO)
+            }));
+        }
+
+        if trades.is_empty() {
+            info!("No trades in the last {days} day(s)");
+            return Ok(());
+        }
+
+        trades.sort_unstable_by_key(|order| order.filled_at);
+
+        let money = &Config::get().money_format;
+        let mut lines = Vec::with_capacity(trades.len() + 1);
+        lines.push("Symbol   Side  Qty           Avg Price   Filled At".to_owned());
+        for order in &trades {
+            let side = match order.side {
+                OrderSide::Buy => "buy",
+                OrderSide::Sell => "sell",
+            };
+            let avg_price = order
+                .filled_avg_price
+                .map(|price| money.format(price))
+                .unwrap_or_else(|| "-".to_owned());
+            let filled_at = order
+                .filled_at
+                .map(format_local_datetime)
+                .unwrap_or_else(|| "-".to_owned());
+
+            lines.push(format!(
+                "{:<9}{:<6}{:<14}{:<12}{}",
+                order.symbol,
+                side,
+                order.filled_qty.unwrap_or(Decimal::ZERO),
+                avg_price,
+                filled_at
+            ));
+        }
+
+        info!("Trades in the last {days} day(s)\n{}", lines.join("\n"));
+        Ok(())
+    }
+
     async fn handle_stream_event(&mut self, event: StreamEvent) {
         match event {
             StreamEvent::MinuteBar { symbol, bar } => {
+                self.intraday.last_stream_bar = Some(OffsetDateTime::now_utc());
                 self.handle_stream_minute_bar(symbol, bar).await;
             }
-            StreamEvent::Dump { json } => self.dump_state(&json),
+            StreamEvent::Dump { json, label } => self.dump_state(&json, label.as_deref()),
         }
     }
 
@@ -839,35 +1996,41 @@ impl Engine {
     }
 
     async fn handle_stream_minute_bar(&mut self, symbol: Symbol, bar: Bar) {
-        const FIVE_MINUTES: Duration = Duration::minutes(5);
-
-        let avg_span = self.get_avg_span(symbol).await;
+        if is_stale_bar(symbol, &bar, OffsetDateTime::now_utc()) {
+            return;
+        }
 
-        if let Some(price_info) = self
-            .intraday
-            .price_tracker
-            .record_price(symbol, avg_span, bar)
-        {
-            let threshold = avg_span * 0.225;
+        let avg_span = match self.get_avg_span(symbol).await {
+            Some(avg_span) => avg_span,
+            None => {
+                trace!("Ignoring bar for {symbol}; no metadata available");
+                return;
+            }
+        };
+        let atr = self.get_atr(symbol).await;
+
+        if let Some(triggers) = evaluate_bar(
+            &mut self.intraday.price_tracker,
+            &mut self.intraday.last_triggers,
+            symbol,
+            avg_span,
+            atr,
+            bar,
+        ) {
+            let BarTriggers {
+                price_info,
+                threshold,
+                band,
+                sell_trigger,
+                buy_trigger,
+            } = triggers;
             let mut log_trace_info = false;
 
-            let sell_trigger = price_info.time_since_hwm >= FIVE_MINUTES
-                && price_info.hwm_loss <= -threshold
-                && price_info.hwm_loss > -2.0 * threshold;
-            let buy_trigger = price_info.time_since_lwm >= FIVE_MINUTES
-                && price_info.lwm_gain > threshold
-                && price_info.lwm_gain < 2.0 * threshold;
-
-            let (sell_trigger, buy_trigger) = match (sell_trigger, buy_trigger) {
-                (true, true) => {
-                    if price_info.time_since_hwm < price_info.time_since_lwm {
-                        (true, false)
-                    } else {
-                        (false, true)
-                    }
+            if let Some(band) = band {
+                if let Err(error) = self.position_band_sell(symbol, band).await {
+                    error!("Failed to handle trailing stop band for {symbol}: {error:?}");
                 }
-                (st, bt) => (st, bt),
-            };
+            }
 
             if sell_trigger {
                 trace!("Sending sell trigger for {symbol}");
@@ -894,7 +2057,125 @@ impl Engine {
         }
     }
 
-    fn dump_state(&self, stream_json: &Value) {
+    async fn replay_intraday(&mut self, path: &str) -> anyhow::Result<()> {
+        let json = fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+        let history =
+            PriceTracker::parse_patched_json(&json).context("Failed to parse intraday data")?;
+
+        let mut avg_spans = HashMap::with_capacity(history.len());
+        let mut atrs = HashMap::new();
+        for &symbol in history.keys() {
+            if let Some(atr) = self.get_atr(symbol).await {
+                atrs.insert(symbol, atr);
+            }
+
+            match self.get_avg_span(symbol).await {
+                Some(avg_span) => {
+                    avg_spans.insert(symbol, avg_span);
+                }
+                None => warn!("No metadata for {symbol}; it will not be replayed"),
+            }
+        }
+
+        // The replay runs against its own tracker, cooldowns and a clock frozen to the recorded
+        // minutes, so triggers go through the same evaluation as live bars but are only reported
+        // and never reach the order manager
+        let open =
+            Config::localize(OffsetDateTime::now_utc()).replace_time(PriceTracker::open_time());
+        let num_minutes = history.values().map(Vec::len).max().unwrap_or(0);
+        let mut price_tracker = PriceTracker::new();
+        let mut last_triggers = HashMap::new();
+        let mut num_triggers = 0usize;
+        let mut bands_hit = HashMap::<Symbol, usize>::new();
+
+        info!(
+            "Replaying {num_minutes} minutes of intraday data for {} symbols",
+            history.len()
+        );
+
+        for minute in 0..num_minutes {
+            let time = open + Duration::minutes(minute as i64);
+
+            for (&symbol, prices) in &history {
+                let price = match prices.get(minute).copied().map(f64_to_decimal) {
+                    Some(Ok(price)) => price,
+                    Some(Err(error)) => {
+                        warn!("Skipping invalid price for {symbol} at minute {minute}: {error}");
+                        continue;
+                    }
+                    None => continue,
+                };
+
+                let bar = Bar {
+                    time,
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    volume: 0,
+                };
+
+                if is_stale_bar(symbol, &bar, time) {
+                    continue;
+                }
+
+                let avg_span = match avg_spans.get(&symbol) {
+                    Some(&avg_span) => avg_span,
+                    None => continue,
+                };
+                let BarTriggers {
+                    price_info,
+                    band,
+                    sell_trigger,
+                    buy_trigger,
+                    ..
+                } = match evaluate_bar(
+                    &mut price_tracker,
+                    &mut last_triggers,
+                    symbol,
+                    avg_span,
+                    atrs.get(&symbol).copied(),
+                    bar,
+                ) {
+                    Some(triggers) => triggers,
+                    None => continue,
+                };
+
+                if let Some(band) = band {
+                    if bands_hit.get(&symbol).map_or(true, |&hit| band > hit) {
+                        bands_hit.insert(symbol, band);
+                        num_triggers += 1;
+                        info!(
+                            "[{}] Trailing stop band {} reached for {symbol} at {} (HWM loss: \
+                             {:.3})",
+                            time.time(),
+                            band + 1,
+                            Config::get().money_format.format(price_info.latest_price),
+                            price_info.hwm_loss,
+                        );
+                    }
+                }
+
+                if sell_trigger || buy_trigger {
+                    num_triggers += 1;
+                    info!(
+                        "[{}] {} trigger for {symbol} at {} (HWM loss: {:.3}, LWM gain: {:.3})",
+                        time.time(),
+                        if sell_trigger { "Sell" } else { "Buy" },
+                        Config::get().money_format.format(price_info.latest_price),
+                        price_info.hwm_loss,
+                        price_info.lwm_gain,
+                    );
+                }
+            }
+        }
+
+        info!("Finished replaying intraday data from {path}; {num_triggers} trigger(s) fired");
+        Ok(())
+    }
+
+    // Labelled dumps are written to their own file so that earlier dumps aren't overwritten
+    fn dump_state(&self, stream_json: &Value, label: Option<&str>) {
         let engine_json = match serde_json::to_value(self) {
             Ok(json) => json,
             Err(error) => {
@@ -903,14 +2184,35 @@ impl Engine {
             }
         };
 
+        let config = Config::get();
         let aggregate = json!({
-            "config": Config::get(),
+            "config": config,
             "engine": engine_json,
             "stream": stream_json
         });
 
-        match fs::write("statedump.json", &aggregate.to_string()) {
-            Ok(()) => info!("Wrote state to statedump.json"),
+        let contents = if config.dump_pretty {
+            serde_json::to_string_pretty(&aggregate).unwrap_or_else(|_| aggregate.to_string())
+        } else {
+            aggregate.to_string()
+        };
+
+        // Compressed dumps are always written to their own file, timestamped if unlabelled
+        let (file, result) = if config.dump_compressed {
+            let label = label.map_or_else(command::timestamp_label, str::to_owned);
+            let file = format!("statedump-{label}.json.gz");
+            let result = write_compressed(&file, contents.as_bytes());
+            (file, result)
+        } else {
+            let file = match label {
+                Some(label) => format!("statedump-{label}.json"),
+                None => "statedump.json".to_owned(),
+            };
+            let result = fs::write(&file, &contents);
+            (file, result)
+        };
+        match result {
+            Ok(()) => info!("Wrote state to {file}"),
             Err(error) => {
                 error!("Failed to write JSON to file, writing to console instead. {error:?}");
                 info!("{aggregate}");
@@ -918,3 +2220,433 @@ impl Engine {
         }
     }
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BlacklistReason {
+    Inactive,
+    NotTradable,
+    NotFractionable,
+    Delisted,
+    Config,
+}
+
+impl BlacklistReason {
+    // Returns why the equity can't be traded, if it can't
+    pub fn for_equity(equity: &Equity) -> Option<Self> {
+        if equity.status != AssetStatus::Active {
+            Some(Self::Inactive)
+        } else if !equity.tradable {
+            Some(Self::NotTradable)
+        } else if !equity.fractionable {
+            Some(Self::NotFractionable)
+        } else {
+            None
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Inactive => "the asset is not active",
+            Self::NotTradable => "the asset is not tradable on Alpaca",
+            Self::NotFractionable => "the asset does not support fractional shares",
+            Self::Delisted => "the asset was delisted while held",
+            Self::Config => "the symbol is in the configured blacklist",
+        }
+    }
+}
+
+// Seeds the local history of symbols which aren't tracked yet so that they're part of the
+// universe from the next pre-open, rather than waiting for them to be found as untracked. Fetching
+// the history can take a while, so this runs as its own task like a manual history update.
+async fn track_symbols(
+    rest: AlpacaRestApi,
+    local_history: Arc<LocalHistoryImpl>,
+    symbols: Vec<Symbol>,
+) -> anyhow::Result<()> {
+    let mut results = Vec::with_capacity(symbols.len());
+    let mut valid = Vec::new();
+    for &symbol in &symbols {
+        match rest.asset(symbol).await {
+            Ok(Some(asset)) if asset.status == AssetStatus::Active && asset.tradable => {
+                valid.push(symbol)
+            }
+            Ok(Some(asset)) => results.push((
+                symbol,
+                format!(
+                    "asset is not tradable (status {}, tradable {})",
+                    asset.status, asset.tradable
+                ),
+            )),
+            Ok(None) => results.push((symbol, "no such asset".to_owned())),
+            Err(error) => results.push((symbol, format!("failed to fetch asset: {error}"))),
+        }
+    }
+
+    if !valid.is_empty() {
+        local_history
+            .repair_records(&rest, &valid)
+            .await
+            .context("Failed to fetch and store history")?;
+
+        let local_symbols = local_history
+            .symbols()
+            .await
+            .context("Failed to fetch list of local symbols")?;
+        for symbol in valid {
+            let result = if local_symbols.contains(&symbol) {
+                "tracked"
+            } else {
+                "no history was stored"
+            };
+            results.push((symbol, result.to_owned()));
+        }
+    }
+
+    let lines = results
+        .iter()
+        .map(|(symbol, result)| format!("{symbol:<9}{result}"))
+        .collect::<Vec<_>>();
+    info!("Track symbols\n{}", lines.join("\n"));
+    Ok(())
+}
+
+// Fetches the current positions and account, retrying up to `max_retries` times with exponential
+// backoff so that a transient failure doesn't stop the engine or put it into safety mode
+async fn fetch_account_info(
+    rest: &AlpacaRestApi,
+    max_retries: u32,
+) -> anyhow::Result<(HashMap<Symbol, Position>, Account)> {
+    let mut retries = 0;
+
+    loop {
+        let result = match rest.position_map().await {
+            Ok(position_map) => rest.account().await.map(|account| (position_map, account)),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(info) => return Ok(info),
+            Err(error) if retries < max_retries => {
+                retries += 1;
+                rest.request_stats().record_retry();
+                let delay = 2u64.pow(retries);
+                warn!(
+                    "Failed to fetch account info: {error:?}. Retry {retries}/{max_retries} in \
+                     {delay}s"
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+fn write_compressed(path: &str, contents: &[u8]) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(fs::File::create(path)?, Compression::default());
+    encoder.write_all(contents)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+const PDT_MINIMUM_EQUITY: Decimal = Decimal::from_parts(25_000, 0, 0, false, 0);
+const PDT_MAX_DAYTRADES: u32 = 3;
+
+// Returns the number of day trades the account can make in the rolling 5 day window before being
+// restricted, or None if the account has enough equity to not be subject to PDT rules
+pub fn remaining_daytrades(account: &Account) -> Option<u32> {
+    if account.equity >= PDT_MINIMUM_EQUITY {
+        return None;
+    }
+
+    if account.pattern_day_trader {
+        return Some(0);
+    }
+
+    Some(PDT_MAX_DAYTRADES.saturating_sub(account.daytrade_count))
+}
+
+// The threshold is relative to the price, so the ATR is scaled down by the latest price
+fn trailing_threshold(avg_span: f64, atr: Option<f64>, price: Decimal) -> f64 {
+    let trading = &Config::get().trading;
+    match (atr, price.to_f64()) {
+        (Some(atr), Some(price)) if price > 0.0 => trading.atr_multiplier * atr / price,
+        _ => avg_span * 0.225,
+    }
+}
+
+// Drops bars which arrive too long after their minute, so that triggers aren't evaluated against
+// prices which are no longer current. The replay passes its own clock as `now`.
+fn is_stale_bar(symbol: Symbol, bar: &Bar, now: OffsetDateTime) -> bool {
+    let max_bar_age = Config::get().trading.max_bar_age_minutes;
+    if max_bar_age == 0 {
+        return false;
+    }
+
+    let age = now - bar.time;
+    if age > Duration::minutes(i64::from(max_bar_age)) {
+        warn!(
+            "Dropping stale bar for {symbol} from {}, which is {} minutes old",
+            bar.time,
+            age.whole_minutes()
+        );
+        return true;
+    }
+
+    false
+}
+
+struct BarTriggers {
+    price_info: PriceInfo,
+    threshold: f64,
+    band: Option<usize>,
+    sell_trigger: bool,
+    buy_trigger: bool,
+}
+
+// Records the bar and works out which triggers it fires, with the trigger cooldown applied. This is
+// shared by live bars and the intraday replay, which only differ in what they do with the result.
+fn evaluate_bar(
+    price_tracker: &mut PriceTracker,
+    last_triggers: &mut HashMap<Symbol, OffsetDateTime>,
+    symbol: Symbol,
+    avg_span: f64,
+    atr: Option<f64>,
+    bar: Bar,
+) -> Option<BarTriggers> {
+    let time = bar.time;
+    let price_info = price_tracker.record_price(symbol, avg_span, bar)?;
+    let threshold = trailing_threshold(avg_span, atr, price_info.latest_price);
+    let band = trailing_stop_band(&price_info, threshold);
+    let (mut sell_trigger, mut buy_trigger) = evaluate_triggers(&price_info, threshold);
+
+    if sell_trigger || buy_trigger {
+        let cooldown = Duration::minutes(i64::from(Config::get().trading.trigger_cooldown_minutes));
+
+        match last_triggers.get(&symbol) {
+            Some(&last_trigger) if time - last_trigger < cooldown => {
+                trace!(
+                    "Suppressing {} trigger for {symbol}, last trigger was at {}",
+                    if sell_trigger { "sell" } else { "buy" },
+                    last_trigger.time()
+                );
+                sell_trigger = false;
+                buy_trigger = false;
+            }
+            _ => {
+                last_triggers.insert(symbol, time);
+            }
+        }
+    }
+
+    Some(BarTriggers {
+        price_info,
+        threshold,
+        band,
+        sell_trigger,
+        buy_trigger,
+    })
+}
+
+// Returns whether the sell and buy triggers fire, in that order. At most one will be true.
+// When trailing stop bands are configured they replace the sell trigger
+fn evaluate_triggers(price_info: &PriceInfo, threshold: f64) -> (bool, bool) {
+    const FIVE_MINUTES: Duration = Duration::minutes(5);
+
+    let sell_trigger = Config::get().trading.trailing_stop_bands.is_empty()
+        && price_info.time_since_hwm >= FIVE_MINUTES
+        && price_info.hwm_loss <= -threshold
+        && price_info.hwm_loss > -2.0 * threshold;
+    let buy_trigger = price_info.time_since_lwm >= FIVE_MINUTES
+        && price_info.lwm_gain > threshold
+        && price_info.lwm_gain < 2.0 * threshold;
+
+    match (sell_trigger, buy_trigger) {
+        (true, true) => {
+            if price_info.time_since_hwm < price_info.time_since_lwm {
+                (true, false)
+            } else {
+                (false, true)
+            }
+        }
+        (st, bt) => (st, bt),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum WatchdogAction {
+    Continue,
+    EnterSafetyMode,
+    Liquidate { loss: Decimal },
+}
+
+// Decides what the watchdog does about the account's equity. Zero or negative equity is handled
+// as configured, since it's usually a glitch rather than a real loss; otherwise the positions are
+// liquidated once the equity falls to the kill threshold of the high water mark, outside of the
+// grace period.
+fn watchdog_action(
+    current_equity: Decimal,
+    account_hwm: Decimal,
+    non_positive_equity_action: NonPositiveEquityAction,
+    tsl_kill_threshold: Decimal,
+    in_grace_period: bool,
+) -> WatchdogAction {
+    if account_hwm <= Decimal::ZERO {
+        return WatchdogAction::Continue;
+    }
+
+    if current_equity <= Decimal::ZERO {
+        match non_positive_equity_action {
+            NonPositiveEquityAction::Skip => return WatchdogAction::Continue,
+            NonPositiveEquityAction::SafetyMode => return WatchdogAction::EnterSafetyMode,
+            NonPositiveEquityAction::Liquidate => {}
+        }
+    }
+
+    if in_grace_period {
+        return WatchdogAction::Continue;
+    }
+
+    let loss = current_equity / account_hwm;
+    if loss <= tsl_kill_threshold {
+        WatchdogAction::Liquidate { loss }
+    } else {
+        WatchdogAction::Continue
+    }
+}
+
+// Returns the index of the deepest configured trailing stop band the loss from the high water mark
+// has reached, if any
+fn trailing_stop_band(price_info: &PriceInfo, threshold: f64) -> Option<usize> {
+    Config::get()
+        .trading
+        .trailing_stop_bands
+        .iter()
+        .rposition(|band| price_info.hwm_loss <= -band.threshold_multiple * threshold)
+}
+
+fn format_local_datetime(datetime: OffsetDateTime) -> String {
+    let local = Config::localize(datetime);
+    match (local.format(&*DATE_FORMAT), local.format(&*TIME_FORMAT)) {
+        (Ok(date), Ok(time)) => format!("{date} {time}"),
+        _ => local.to_string(),
+    }
+}
+
+fn format_duration_compact(duration: Duration) -> String {
+    let sign = if duration.is_negative() { "-" } else { "" };
+    let duration = duration.abs();
+    format!(
+        "{sign}{}h{:02}m{:02}s",
+        duration.whole_hours(),
+        duration.whole_minutes() % 60,
+        duration.whole_seconds() % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Decimal = Decimal::from_parts(5, 0, 0, false, 1);
+
+    #[test]
+    fn zero_equity_does_not_liquidate_by_default() {
+        let hwm = Decimal::from(10_000);
+        assert_eq!(
+            watchdog_action(
+                Decimal::ZERO,
+                hwm,
+                NonPositiveEquityAction::Skip,
+                THRESHOLD,
+                false
+            ),
+            WatchdogAction::Continue
+        );
+        assert_eq!(
+            watchdog_action(
+                Decimal::from(-5),
+                hwm,
+                NonPositiveEquityAction::Skip,
+                THRESHOLD,
+                false
+            ),
+            WatchdogAction::Continue
+        );
+        assert_eq!(
+            watchdog_action(
+                Decimal::ZERO,
+                hwm,
+                NonPositiveEquityAction::SafetyMode,
+                THRESHOLD,
+                false
+            ),
+            WatchdogAction::EnterSafetyMode
+        );
+    }
+
+    #[test]
+    fn zero_high_water_mark_never_divides() {
+        for action in [
+            NonPositiveEquityAction::Skip,
+            NonPositiveEquityAction::SafetyMode,
+            NonPositiveEquityAction::Liquidate,
+        ] {
+            assert_eq!(
+                watchdog_action(Decimal::ZERO, Decimal::ZERO, action, THRESHOLD, false),
+                WatchdogAction::Continue
+            );
+        }
+    }
+
+    #[test]
+    fn zero_equity_liquidates_only_when_configured() {
+        let hwm = Decimal::from(10_000);
+        assert_eq!(
+            watchdog_action(
+                Decimal::ZERO,
+                hwm,
+                NonPositiveEquityAction::Liquidate,
+                THRESHOLD,
+                false
+            ),
+            WatchdogAction::Liquidate {
+                loss: Decimal::ZERO
+            }
+        );
+        // Not even then during the grace period
+        assert_eq!(
+            watchdog_action(
+                Decimal::ZERO,
+                hwm,
+                NonPositiveEquityAction::Liquidate,
+                THRESHOLD,
+                true
+            ),
+            WatchdogAction::Continue
+        );
+    }
+
+    #[test]
+    fn kill_threshold_is_measured_against_the_high_water_mark() {
+        let hwm = Decimal::from(10_000);
+        let check = |equity: i64, in_grace_period: bool| {
+            watchdog_action(
+                Decimal::from(equity),
+                hwm,
+                NonPositiveEquityAction::Skip,
+                THRESHOLD,
+                in_grace_period,
+            )
+        };
+
+        assert_eq!(check(9_000, false), WatchdogAction::Continue);
+        assert_eq!(
+            check(5_000, false),
+            WatchdogAction::Liquidate {
+                loss: Decimal::new(5, 1)
+            }
+        );
+        assert_eq!(check(4_000, true), WatchdogAction::Continue);
+    }
+}
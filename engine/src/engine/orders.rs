@@ -1,52 +1,263 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
-use anyhow::Context;
-use entity::trading::{Order, OrderRequest, OrderSide, OrderTimeInForce, OrderType};
-use log::info;
+use anyhow::{anyhow, Context};
+use common::{
+    config::{Config, MoneyRounding},
+    util::DateSerdeWrapper,
+};
+use entity::trading::{
+    Order, OrderRequest, OrderSide, OrderStatus, OrderTimeInForce, OrderType, Position, Side,
+};
+use log::{info, warn};
 use rust_decimal::{Decimal, RoundingStrategy};
 use serde::Serialize;
 use stock_symbol::Symbol;
 
-use rest::AlpacaRestApi;
-use time::OffsetDateTime;
+use rest::{AlpacaRestApi, RequestOrderStatus};
+use time::{Date, OffsetDateTime};
 use uuid::Uuid;
 
+// Number of times a liquidation is resubmitted when the position changes underneath it
+const LIQUIDATION_RETRIES: u32 = 3;
+
 #[derive(Serialize)]
 pub struct OrderManager {
     #[serde(skip)]
     rest: AlpacaRestApi,
     trade_statuses: HashMap<Symbol, TradeStatus>,
     open_orders: Vec<OrderMeta>,
+    // Symbols with a rejected order today. We don't buy these again until the next day so that we
+    // don't keep submitting the same doomed order. Sells are still allowed since they reduce risk.
+    rejected_symbols: HashSet<Symbol>,
+    // Open GTC orders submitted through the engine. Unlike day orders, these are kept across
+    // sessions and restarts.
+    gtc_orders: HashSet<Uuid>,
+    // Symbols which accept fractional orders, refreshed from the asset list each pre-open
+    fractionable: HashSet<Symbol>,
+    // Whether orders are only logged rather than submitted, until the configured observe_until date
+    observing: bool,
     pub allow_buying: bool,
 }
 
 impl OrderManager {
-    pub fn new(rest: AlpacaRestApi) -> Self {
+    pub fn new(rest: AlpacaRestApi, gtc_orders: HashSet<Uuid>) -> Self {
         Self {
             rest,
             trade_statuses: HashMap::new(),
             open_orders: Vec::new(),
+            rejected_symbols: HashSet::new(),
+            gtc_orders,
+            fractionable: HashSet::new(),
+            observing: false,
             allow_buying: true,
         }
     }
 
+    pub fn is_observing(&self) -> bool {
+        self.observing
+    }
+
+    /// Enters or leaves observe-only mode depending on the date, logging the transition.
+    pub fn update_observe_mode(&mut self) {
+        let observe_until = observe_until();
+        match (self.observing, observe_until) {
+            (false, Some(date)) => {
+                warn!("OBSERVE ONLY UNTIL {date}: orders will be logged but not submitted")
+            }
+            (true, None) => {
+                warn!("OBSERVATION PERIOD OVER: orders will now be submitted to the account")
+            }
+            _ => {}
+        }
+        self.observing = observe_until.is_some();
+    }
+
+    pub fn gtc_orders(&self) -> &HashSet<Uuid> {
+        &self.gtc_orders
+    }
+
+    /// Starts tracking a GTC order submitted outside of the order manager, such as a manual order,
+    /// so that it's persisted and reconciled across sessions. Day orders are ignored.
+    pub fn track_gtc_order(&mut self, order: Order) {
+        if order.time_in_force == Some(OrderTimeInForce::GoodUntilCanceled) {
+            self.track(OrderMeta::new(order, None));
+        }
+    }
+
+    fn track(&mut self, meta: OrderMeta) {
+        if meta.good_until_canceled {
+            self.gtc_orders.insert(meta.id);
+        }
+        self.open_orders.push(meta);
+    }
+
+    /// Resumes tracking of the persisted GTC orders which are still open, and forgets any which
+    /// were filled or cancelled since they were last seen. Open GTC orders which we didn't submit
+    /// are left alone. Duplicate open orders for the same symbol and side are cleaned up first.
+    pub async fn reconcile_gtc_orders(&mut self) -> anyhow::Result<()> {
+        let orders = self
+            .rest
+            .get_orders(RequestOrderStatus::Open, 500, OffsetDateTime::UNIX_EPOCH)
+            .await
+            .context("Failed to fetch open orders")?;
+        let orders = self.cancel_duplicate_orders(orders).await;
+
+        let mut open_gtc_orders = HashSet::new();
+        for order in orders {
+            if order.time_in_force != Some(OrderTimeInForce::GoodUntilCanceled)
+                || !self.gtc_orders.contains(&order.id)
+            {
+                continue;
+            }
+
+            open_gtc_orders.insert(order.id);
+            if self.open_orders.iter().any(|meta| meta.id == order.id) {
+                continue;
+            }
+
+            info!(
+                "Tracking GTC order {} for {}",
+                order.id.hyphenated(),
+                order.symbol
+            );
+            self.open_orders.push(OrderMeta::new(order, None));
+        }
+
+        for id in self.gtc_orders.difference(&open_gtc_orders) {
+            info!("GTC order {} is no longer open", id.hyphenated());
+        }
+        self.open_orders
+            .retain(|meta| !meta.good_until_canceled || open_gtc_orders.contains(&meta.id));
+        self.gtc_orders = open_gtc_orders;
+
+        Ok(())
+    }
+
+    // Keeps only the most recently submitted open order for each symbol and side, cancelling the
+    // rest if configured to. Only day orders the engine is tracking are ever cancelled; manual and
+    // GTC orders are only warned about. Returns the orders which are still open.
+    async fn cancel_duplicate_orders(&mut self, orders: Vec<Order>) -> Vec<Order> {
+        let mut groups = HashMap::<(Symbol, OrderSide), Vec<Order>>::new();
+        for order in orders {
+            groups
+                .entry((order.symbol, order.side))
+                .or_default()
+                .push(order);
+        }
+
+        let mut remaining = Vec::new();
+        for ((symbol, side), mut group) in groups {
+            group.sort_by_key(|order| order.submitted_at);
+            let latest = group.pop().expect("groups are non-empty");
+
+            if !group.is_empty() {
+                warn!(
+                    "Found {} open {side:?} orders for {symbol}",
+                    group.len() + 1
+                );
+            }
+
+            for order in group {
+                let submitted_by_engine = self
+                    .open_orders
+                    .iter()
+                    .any(|meta| meta.id == order.id && !meta.good_until_canceled);
+                if !Config::get().trading.cancel_duplicate_orders || !submitted_by_engine {
+                    remaining.push(order);
+                    continue;
+                }
+
+                if self.observing {
+                    info!(
+                        "Observing only, would cancel duplicate order {} for {symbol}",
+                        order.id.hyphenated()
+                    );
+                    remaining.push(order);
+                    continue;
+                }
+
+                match self.rest.cancel_order(order.id).await {
+                    Ok(()) => {
+                        info!(
+                            "Cancelled duplicate order {} for {symbol}, keeping {}",
+                            order.id.hyphenated(),
+                            latest.id.hyphenated()
+                        );
+                        self.open_orders.retain(|meta| meta.id != order.id);
+                    }
+                    Err(error) => {
+                        warn!(
+                            "Failed to cancel duplicate order {}: {error:?}",
+                            order.id.hyphenated()
+                        );
+                        remaining.push(order);
+                    }
+                }
+            }
+
+            remaining.push(latest);
+        }
+
+        remaining
+    }
+
     pub async fn on_tick(&mut self) -> anyhow::Result<()> {
+        let mut rejected = Vec::new();
+        let max_age =
+            time::Duration::minutes(i64::from(Config::get().trading.order_max_age_minutes));
+
         for order_meta in &mut self.open_orders {
             let now = OffsetDateTime::now_utc();
+            let age = now - order_meta.submitted_at;
+
+            // Once cancelled, the order will be cleaned up when we query it below. A cancel can
+            // fail because the order already filled, so it's only attempted once and the query
+            // decides what happened. GTC orders are expected to stay open.
+            let mut cancelled = false;
+            if !order_meta.cancel_requested && !order_meta.good_until_canceled && age > max_age {
+                info!(
+                    "Cancelling order {} after {} minutes",
+                    order_meta.id.hyphenated(),
+                    age.whole_minutes()
+                );
+                if let Err(error) = self.rest.cancel_order(order_meta.id).await {
+                    warn!(
+                        "Failed to cancel order {}: {error:?}",
+                        order_meta.id.hyphenated()
+                    );
+                }
+                order_meta.cancel_requested = true;
+                cancelled = true;
+            }
 
-            if (now - order_meta.last_queried) < Duration::from_secs(60) {
+            if !cancelled && (now - order_meta.last_queried) < Duration::from_secs(60) {
                 continue;
             }
 
             order_meta.last_queried = now;
 
-            let order = self
-                .rest
-                .get_order(order_meta.id)
-                .await
-                .context("Failed to fetch order")?;
+            let order = match self.rest.get_order(order_meta.id).await {
+                Ok(order) => order,
+                Err(error) => {
+                    warn!(
+                        "Failed to fetch order {}: {error:?}",
+                        order_meta.id.hyphenated()
+                    );
+                    continue;
+                }
+            };
 
-            if order.status.is_closed() {
+            if order.status == OrderStatus::Rejected || order.status.is_closed() {
+                self.gtc_orders.remove(&order.id);
+            }
+
+            if order.status == OrderStatus::Rejected {
+                order_meta.id = Uuid::nil();
+                rejected.push((order, order_meta.notional, order_meta.retried));
+            } else if order.status.is_closed() {
                 order_meta.id = Uuid::nil();
 
                 if let Some(status) = self.trade_statuses.get_mut(&order.symbol) {
@@ -60,9 +271,76 @@ impl OrderManager {
 
         self.open_orders.retain(|meta| !meta.id.is_nil());
 
+        for (order, notional, retried) in rejected {
+            self.handle_rejection(order, notional, retried).await;
+        }
+
         Ok(())
     }
 
+    async fn handle_rejection(&mut self, order: Order, notional: Option<Decimal>, retried: bool) {
+        let symbol = order.symbol;
+        let side = match order.side {
+            OrderSide::Buy => "buy",
+            OrderSide::Sell => "sell",
+        };
+        // Alpaca does not report why an order was rejected on the order itself, so the best we
+        // can do is log what we asked for
+        warn!(
+            "Order {} to {side} {} of {symbol} was rejected",
+            order.id.hyphenated(),
+            notional
+                .map(|notional| format!("${notional:.2}"))
+                .unwrap_or_else(|| "all".to_owned())
+        );
+
+        self.trade_statuses.remove(&symbol);
+
+        // The only rejection we know how to correct is a buy exceeding our buying power
+        if let (OrderSide::Buy, Some(notional), false) = (order.side, notional, retried) {
+            if Config::get().trading.retry_rejected_orders {
+                match self.retry_rejected_buy(symbol, notional).await {
+                    Ok(true) => return,
+                    Ok(false) => {}
+                    Err(error) => {
+                        warn!("Failed to retry rejected buy order for {symbol}: {error:?}")
+                    }
+                }
+            }
+        }
+
+        self.rejected_symbols.insert(symbol);
+    }
+
+    // Resubmits a rejected buy capped at the current buying power. Returns whether an order was
+    // submitted.
+    async fn retry_rejected_buy(
+        &mut self,
+        symbol: Symbol,
+        notional: Decimal,
+    ) -> anyhow::Result<bool> {
+        let buying_power = self
+            .rest
+            .account()
+            .await
+            .context("Failed to fetch account")?
+            .buying_power;
+        let adjusted = Decimal::min(notional, buying_power);
+
+        if adjusted < Decimal::new(101, 2) {
+            return Ok(false);
+        }
+
+        info!("Retrying rejected buy order for {symbol} with ${adjusted:.2}");
+        let order = self.submit(symbol, OrderSide::Buy, adjusted).await?;
+        let mut meta = OrderMeta::new(order, Some(adjusted));
+        meta.retried = true;
+        self.trade_statuses
+            .insert(symbol, TradeStatus::OrderPending);
+        self.track(meta);
+        Ok(true)
+    }
+
     pub fn trade_status(&self, symbol: Symbol) -> TradeStatus {
         self.trade_statuses
             .get(&symbol)
@@ -70,62 +348,313 @@ impl OrderManager {
             .unwrap_or(TradeStatus::Untraded)
     }
 
-    pub async fn liquidate(&mut self, symbol: Symbol) -> anyhow::Result<()> {
-        let order = self.rest.liquidate_position(symbol).await?;
+    /// The notional of the open sell orders for the symbol, or `None` if one of them is a
+    /// liquidation, which sells the whole position.
+    pub fn pending_sell_notional(&self, symbol: Symbol) -> Option<Decimal> {
+        self.open_orders
+            .iter()
+            .filter(|meta| meta.symbol == symbol && meta.side == OrderSide::Sell)
+            .map(|meta| meta.notional)
+            .sum()
+    }
+
+    /// The total notional of the open buy orders, which the account's cash doesn't reflect until
+    /// they fill.
+    pub fn pending_buy_notional(&self) -> Decimal {
+        self.open_orders
+            .iter()
+            .filter(|meta| meta.side == OrderSide::Buy)
+            .flat_map(|meta| meta.notional)
+            .sum()
+    }
+
+    pub fn is_rejected(&self, symbol: Symbol) -> bool {
+        self.rejected_symbols.contains(&symbol)
+    }
+
+    // Returns a description of why the order would contradict the current position or an order
+    // which is still in flight, if it does
+    fn side_conflict(
+        &self,
+        symbol: Symbol,
+        side: OrderSide,
+        position: Option<&Position>,
+    ) -> Option<String> {
+        if !Config::get().trading.order_side_safety {
+            return None;
+        }
+
+        let pending = self
+            .open_orders
+            .iter()
+            .find(|meta| meta.symbol == symbol && meta.side != side);
+        if let Some(meta) = pending {
+            return Some(format!(
+                "order {} on the opposite side is still pending",
+                meta.id.hyphenated()
+            ));
+        }
+
+        match (side, position.map(|position| position.side)) {
+            (OrderSide::Sell, None) => Some("no position is held".to_owned()),
+            (OrderSide::Sell, Some(Side::Short)) => {
+                Some("selling would add to a short position".to_owned())
+            }
+            (OrderSide::Buy, Some(Side::Short)) => {
+                Some("buying would cover a short position".to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    pub async fn liquidate(
+        &mut self,
+        symbol: Symbol,
+        position: Option<&Position>,
+    ) -> anyhow::Result<()> {
+        if let Some(conflict) = self.side_conflict(symbol, OrderSide::Sell, position) {
+            warn!("Not liquidating position in {symbol}; {conflict}");
+            return Ok(());
+        }
+
+        if self.observing {
+            info!("Observing only, would liquidate position in {symbol}");
+            return Ok(());
+        }
+
+        let mut order = self.rest.liquidate_position(symbol).await?;
+        let mut retries = 0;
+        let order = loop {
+            if let Some(order) = order {
+                break order;
+            }
+
+            if retries >= LIQUIDATION_RETRIES {
+                return Err(anyhow!(
+                    "Position in {symbol} kept changing, gave up liquidating after {retries} retries"
+                ));
+            }
+            retries += 1;
+
+            // Sell whatever is actually held now rather than what we last saw
+            let qty = self
+                .rest
+                .position(symbol)
+                .await
+                .with_context(|| format!("Failed to re-fetch position in {symbol}"))?
+                .qty_available;
+            let qty = self.normalize_qty(symbol, qty).await?;
+            if qty <= Decimal::ZERO {
+                return Err(anyhow!("No shares of {symbol} are available to liquidate"));
+            }
+
+            warn!("Quantity of {symbol} changed while liquidating, retrying with {qty} shares");
+            order = self.rest.sell_position(symbol, qty).await?;
+        };
+
         info!(
             "Submitted order {} to liquidate position in {symbol}",
             order.id.hyphenated()
         );
         self.trade_statuses
             .insert(symbol, TradeStatus::OrderPending);
-        self.open_orders.push(OrderMeta::from(order));
+        self.track(OrderMeta::new(order, None));
         Ok(())
     }
 
-    pub async fn sell(&mut self, symbol: Symbol, notional: Decimal) -> anyhow::Result<()> {
-        let order = self
-            .rest
-            .submit_order(&OrderRequest {
-                symbol,
-                qty: None,
-                notional: Some(notional.round_dp_with_strategy(2, RoundingStrategy::ToZero)),
-                side: OrderSide::Sell,
-                order_type: OrderType::Market,
-                time_in_force: OrderTimeInForce::Day,
-                limit_price: None,
-                stop_price: None,
-                trail_price: None,
-                trail_percent: None,
-                extended_hours: None,
-                client_order_id: None,
-                order_class: None,
-                take_profit: None,
-                stop_loss: None,
-            })
-            .await?;
+    pub async fn sell(
+        &mut self,
+        symbol: Symbol,
+        notional: Decimal,
+        position: Option<&Position>,
+    ) -> anyhow::Result<()> {
+        if let Some(conflict) = self.side_conflict(symbol, OrderSide::Sell, position) {
+            warn!("Not selling {symbol}; {conflict}");
+            return Ok(());
+        }
+
+        if self.observing {
+            info!("Observing only, would sell ${notional:.2} of {symbol}");
+            return Ok(());
+        }
+
+        // Notional orders are only accepted for fractionable assets, so other assets are sold in
+        // whole shares
+        let whole_shares = match position {
+            Some(position) if position.current_price > Decimal::ZERO => {
+                !self.is_fractionable(symbol).await?
+            }
+            _ => false,
+        };
+        let order = match position {
+            Some(position) if whole_shares => {
+                let qty = self
+                    .normalize_qty(symbol, notional / position.current_price)
+                    .await?;
+                if qty.is_zero() {
+                    info!("Not selling {symbol}; ${notional:.2} is less than one share");
+                    return Ok(());
+                }
+                self.submit_qty(symbol, OrderSide::Sell, qty).await?
+            }
+            _ => self.submit(symbol, OrderSide::Sell, notional).await?,
+        };
         info!(
             "Submitted order {} to sell ${notional:.2} of {symbol}",
             order.id.hyphenated()
         );
         self.trade_statuses
             .insert(symbol, TradeStatus::OrderPending);
-        self.open_orders.push(OrderMeta::from(order));
+        self.track(OrderMeta::new(order, Some(notional)));
         Ok(())
     }
 
-    pub async fn buy(&mut self, symbol: Symbol, notional: Decimal) -> anyhow::Result<()> {
+    pub async fn buy(
+        &mut self,
+        symbol: Symbol,
+        notional: Decimal,
+        position: Option<&Position>,
+    ) -> anyhow::Result<()> {
         if !self.allow_buying {
             info!("Buying disabled, ignoring order for {symbol}");
             return Ok(());
         }
 
-        let order = self
+        if self.is_rejected(symbol) {
+            info!("Order for {symbol} was rejected earlier today, ignoring buy order");
+            return Ok(());
+        }
+
+        if let Some(conflict) = self.side_conflict(symbol, OrderSide::Buy, position) {
+            warn!("Not buying {symbol}; {conflict}");
+            return Ok(());
+        }
+
+        if let Some(spread) = self.excessive_spread(symbol).await {
+            info!(
+                "Not buying {symbol}; bid-ask spread is {:.2}% of the mid price",
+                spread * Decimal::ONE_HUNDRED
+            );
+            return Ok(());
+        }
+
+        if self.observing {
+            info!("Observing only, would buy ${notional:.2} of {symbol}");
+            return Ok(());
+        }
+
+        let order = self.submit(symbol, OrderSide::Buy, notional).await?;
+        info!(
+            "Submitted order {} to buy ${notional:.2} of {symbol}",
+            order.id.hyphenated()
+        );
+        self.trade_statuses
+            .insert(symbol, TradeStatus::OrderPending);
+        self.track(OrderMeta::new(order, Some(notional)));
+        Ok(())
+    }
+
+    // Returns the spread of the latest quote as a fraction of the mid price if it's too wide to
+    // enter the symbol. Entries go ahead if no usable quote is available.
+    async fn excessive_spread(&self, symbol: Symbol) -> Option<Decimal> {
+        let max_spread = Config::get().trading.max_entry_spread_fraction;
+        if max_spread <= Decimal::ZERO {
+            return None;
+        }
+
+        let quote = match self.rest.latest_quote(symbol).await {
+            Ok(Some(quote)) => quote,
+            Ok(None) => return None,
+            Err(error) => {
+                warn!(
+                    "Failed to fetch latest quote for {symbol}, skipping spread check: {error:?}"
+                );
+                return None;
+            }
+        };
+
+        // One-sided or crossed quotes don't tell us anything useful about the spread
+        if quote.bid_price <= Decimal::ZERO || quote.ask_price < quote.bid_price {
+            return None;
+        }
+
+        let mid = (quote.bid_price + quote.ask_price) / Decimal::TWO;
+        let spread = (quote.ask_price - quote.bid_price) / mid;
+        (spread > max_spread).then_some(spread)
+    }
+
+    pub fn fractionable_symbols(&self) -> &HashSet<Symbol> {
+        &self.fractionable
+    }
+
+    pub fn set_fractionable_symbols(&mut self, fractionable: HashSet<Symbol>) {
+        self.fractionable = fractionable;
+    }
+
+    // Falls back to looking up the asset if the fractionable symbols haven't been loaded yet
+    async fn is_fractionable(&self, symbol: Symbol) -> anyhow::Result<bool> {
+        if !self.fractionable.is_empty() {
+            return Ok(self.fractionable.contains(&symbol));
+        }
+
+        Ok(self
             .rest
+            .asset(symbol)
+            .await
+            .context("Failed to fetch asset")?
+            .map(|asset| asset.fractionable)
+            .unwrap_or(false))
+    }
+
+    /// Rounds the given share quantity down to the precision accepted for the symbol: whole shares
+    /// for non-fractionable assets, and the configured maximum number of decimal places otherwise.
+    pub async fn normalize_qty(&self, symbol: Symbol, qty: Decimal) -> anyhow::Result<Decimal> {
+        Ok(round_qty(
+            qty,
+            self.is_fractionable(symbol).await?,
+            Config::get().trading.max_qty_decimal_places,
+        ))
+    }
+
+    async fn submit(
+        &self,
+        symbol: Symbol,
+        side: OrderSide,
+        notional: Decimal,
+    ) -> anyhow::Result<Order> {
+        // Sells are always rounded toward zero so that they can never exceed the position
+        let rounding = match side {
+            OrderSide::Buy => Config::get().trading.money_rounding,
+            OrderSide::Sell => MoneyRounding::Floor,
+        };
+        let notional = rounding.round(notional);
+        self.submit_request(symbol, side, None, Some(notional))
+            .await
+    }
+
+    // The quantity is expected to have been normalized already
+    async fn submit_qty(
+        &self,
+        symbol: Symbol,
+        side: OrderSide,
+        qty: Decimal,
+    ) -> anyhow::Result<Order> {
+        self.submit_request(symbol, side, Some(qty), None).await
+    }
+
+    async fn submit_request(
+        &self,
+        symbol: Symbol,
+        side: OrderSide,
+        qty: Option<Decimal>,
+        notional: Option<Decimal>,
+    ) -> anyhow::Result<Order> {
+        self.rest
             .submit_order(&OrderRequest {
                 symbol,
-                qty: None,
-                notional: Some(notional.round_dp_with_strategy(2, RoundingStrategy::ToZero)),
-                side: OrderSide::Buy,
+                qty,
+                notional,
+                side,
                 order_type: OrderType::Market,
                 time_in_force: OrderTimeInForce::Day,
                 limit_price: None,
@@ -138,19 +667,51 @@ impl OrderManager {
                 take_profit: None,
                 stop_loss: None,
             })
-            .await?;
-        info!(
-            "Submitted order {} to buy ${notional:.2} of {symbol}",
-            order.id.hyphenated()
-        );
-        self.trade_statuses
-            .insert(symbol, TradeStatus::OrderPending);
-        self.open_orders.push(OrderMeta::from(order));
-        Ok(())
+            .await
+    }
+
+    // GTC orders are left open
+    pub async fn cancel_open_orders(&mut self) {
+        if self.observing {
+            for order_meta in self
+                .open_orders
+                .iter()
+                .filter(|meta| !meta.good_until_canceled)
+            {
+                info!(
+                    "Observing only, would cancel order {}",
+                    order_meta.id.hyphenated()
+                );
+            }
+            return;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let (gtc_orders, day_orders) = self
+            .open_orders
+            .drain(..)
+            .partition::<Vec<_>, _>(|meta| meta.good_until_canceled);
+        self.open_orders = gtc_orders;
+
+        for order_meta in day_orders {
+            match self.rest.cancel_order(order_meta.id).await {
+                Ok(()) => info!(
+                    "Cancelled order {} after {} minutes",
+                    order_meta.id.hyphenated(),
+                    (now - order_meta.submitted_at).whole_minutes()
+                ),
+                Err(error) => warn!(
+                    "Failed to cancel order {}: {error:?}",
+                    order_meta.id.hyphenated()
+                ),
+            }
+        }
     }
 
     pub fn clear(&mut self) {
         self.trade_statuses.clear();
+        self.rejected_symbols.clear();
+        self.open_orders.retain(|meta| meta.good_until_canceled);
     }
 }
 
@@ -175,14 +736,66 @@ impl TradeStatus {
 #[derive(Serialize)]
 struct OrderMeta {
     id: Uuid,
+    symbol: Symbol,
+    side: OrderSide,
     last_queried: OffsetDateTime,
+    submitted_at: OffsetDateTime,
+    notional: Option<Decimal>,
+    retried: bool,
+    cancel_requested: bool,
+    good_until_canceled: bool,
 }
 
-impl From<Order> for OrderMeta {
-    fn from(order: Order) -> Self {
+impl OrderMeta {
+    fn new(order: Order, notional: Option<Decimal>) -> Self {
         Self {
             id: order.id,
+            symbol: order.symbol,
+            side: order.side,
             last_queried: OffsetDateTime::now_utc(),
+            submitted_at: order.submitted_at,
+            notional,
+            retried: false,
+            cancel_requested: false,
+            good_until_canceled: order.time_in_force == Some(OrderTimeInForce::GoodUntilCanceled),
         }
     }
 }
+
+// The configured date until which orders are only logged, if it hasn't been reached yet
+fn observe_until() -> Option<Date> {
+    let DateSerdeWrapper(date) = Config::get().observe_until?;
+    let today = Config::localize(OffsetDateTime::now_utc()).date();
+    (today < date).then_some(date)
+}
+
+// Rounds the quantity down to the precision the asset accepts: whole shares unless it's
+// fractionable, in which case at most max_decimal_places
+fn round_qty(qty: Decimal, fractionable: bool, max_decimal_places: u32) -> Decimal {
+    let dp = if fractionable { max_decimal_places } else { 0 };
+    qty.round_dp_with_strategy(dp, RoundingStrategy::ToZero)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractionable_qty_is_truncated_to_max_decimal_places() {
+        let qty = Decimal::new(1_234_567_891_234, 12); // 1.234567891234
+        assert_eq!(round_qty(qty, true, 9), Decimal::new(1_234_567_891, 9));
+        assert_eq!(round_qty(qty, true, 2), Decimal::new(123, 2));
+        // Never rounded up, so we can't sell more than we hold
+        assert_eq!(
+            round_qty(Decimal::new(1999, 3), true, 2),
+            Decimal::new(199, 2)
+        );
+    }
+
+    #[test]
+    fn non_fractionable_qty_is_rounded_down_to_whole_shares() {
+        assert_eq!(round_qty(Decimal::new(1999, 3), false, 9), Decimal::ONE);
+        assert_eq!(round_qty(Decimal::new(5, 1), false, 9), Decimal::ZERO);
+        assert_eq!(round_qty(Decimal::from(12), false, 9), Decimal::from(12));
+    }
+}
@@ -1,16 +1,30 @@
 use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
 use std::{cell::RefCell, mem};
 
-use common::{config::Config, mwu::Delta};
+use anyhow::{anyhow, Context};
+use common::{
+    config::{Config, MoneyRounding},
+    mwu::Delta,
+    util::{
+        serialize_rounded_decimal, serialize_rounded_decimal_map,
+        serialize_rounded_decimal_nested_map,
+    },
+};
+use entity::data::Bar;
 use history::{LocalHistory, Timeframe};
 use log::{debug, error, info, warn};
-use rust_decimal::Decimal;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
 use stock_symbol::Symbol;
+use time::{Date, OffsetDateTime};
 
 use crate::portfolio::{
-    make_long_portfolio, Expert, LongPortfolioStrategy, Mwu, Weighted, WeightedMut,
+    evaluate_symbols, make_long_portfolio, Expert, LongPortfolioStrategy, Mwu, Weighted,
+    WeightedMut,
 };
 
 use super::engine_impl::Engine;
@@ -78,6 +92,55 @@ impl PortfolioManager {
             .collect()
     }
 
+    pub fn strategy_weights(&self) -> BTreeMap<&'static str, (Decimal, StrategyState)> {
+        self.long
+            .experts
+            .iter()
+            .map(|(&key, strategy)| (key, (strategy.meta.weight, strategy.get_state())))
+            .collect()
+    }
+
+    // Returns each strategy's state along with its effective candidates and their optimal equity
+    // fractions, largest first
+    pub fn strategy_candidates(
+        &self,
+    ) -> BTreeMap<&'static str, (StrategyState, Vec<(Symbol, Decimal)>)> {
+        self.long
+            .experts
+            .iter()
+            .map(|(&key, strategy)| {
+                let mut candidates = strategy
+                    .effective_candidates()
+                    .into_iter()
+                    .map(|symbol| (symbol, strategy.optimal_equity_fraction(symbol)))
+                    .collect::<Vec<_>>();
+                candidates.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+                (key, (strategy.get_state(), candidates))
+            })
+            .collect()
+    }
+
+    // Returns the fraction of usable equity allocated to each symbol at the last pre-open across
+    // all strategies, weighting each strategy's fractions by its capped share, largest first
+    pub fn target_allocations(&self) -> Vec<(Symbol, Decimal)> {
+        let (shares, _) = self.allocation_shares();
+        let mut allocations = self
+            .initial_long_fractions
+            .iter()
+            .map(|(&symbol, split)| {
+                let fraction = split
+                    .iter()
+                    .map(|(key, fraction)| {
+                        shares.get(key).copied().unwrap_or(Decimal::ZERO) * fraction
+                    })
+                    .sum::<Decimal>();
+                (symbol, fraction)
+            })
+            .collect::<Vec<_>>();
+        allocations.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+        allocations
+    }
+
     pub fn set_strategy_state(&mut self, key: &str, state: StrategyState) -> Option<StrategyState> {
         self.long
             .experts
@@ -85,6 +148,13 @@ impl PortfolioManager {
             .map(|strategy| strategy.set_state(state))
     }
 
+    pub fn set_strategy_weight(&mut self, key: &str, weight: Decimal) -> Option<Decimal> {
+        self.long
+            .experts
+            .get_mut(key)
+            .map(|strategy| mem::replace(strategy.weight_mut(), weight))
+    }
+
     pub fn into_metadata(self) -> PortfolioManagerMetadata {
         PortfolioManagerMetadata {
             long: self
@@ -183,23 +253,54 @@ impl PortfolioManager {
         }
     }
 
+    // The share of usable equity commanded by each strategy right now, with the configured
+    // per-strategy ceilings applied
+    fn latest_allocation_shares(
+        &self,
+        data_source: &PriceTracker,
+    ) -> HashMap<&'static str, Decimal> {
+        let weights = self
+            .long
+            .latest_weights(data_source)
+            .into_iter()
+            .map(|(&key, weight)| (key, weight));
+        let (shares, capped) =
+            capped_shares(weights, &Config::get().trading.strategy_max_allocation);
+        for (key, share) in capped {
+            debug!(
+                "Capping allocation of {key} from {share:.4} to {:.4}",
+                shares[key]
+            );
+        }
+        shares
+    }
+
+    // The share of usable equity commanded by each strategy as of the last weight update, with the
+    // configured per-strategy ceilings applied, along with the strategies which were capped
+    fn allocation_shares(&self) -> (HashMap<&'static str, Decimal>, Vec<(&'static str, Decimal)>) {
+        let weights = self
+            .long
+            .experts
+            .iter()
+            .map(|(&key, strategy)| (key, strategy.weight()));
+        capped_shares(weights, &Config::get().trading.strategy_max_allocation)
+    }
+
     fn update_strategy_weights(&mut self, strategy_returns: &HashMap<&'static str, Decimal>) {
         self.long
             .weight_update(|key, _| Delta::Return(strategy_returns[key]));
     }
 
     fn update_initial_long_fractions(&mut self) {
-        self.initial_long_fractions.clear();
-
-        for (&key, strategy) in &self.long.experts {
-            for symbol in strategy.candidates() {
-                let fraction = strategy.optimal_equity_fraction(symbol);
-                self.initial_long_fractions
-                    .entry(symbol)
-                    .or_insert_with(HashMap::new)
-                    .insert(key, fraction);
-            }
-        }
+        self.initial_long_fractions =
+            long_fractions(self.long.experts.iter().map(|(&key, strategy)| {
+                let fractions = strategy
+                    .candidates()
+                    .into_iter()
+                    .map(|symbol| (symbol, strategy.optimal_equity_fraction(symbol)))
+                    .collect::<Vec<_>>();
+                (key, fractions)
+            }));
 
         debug!(
             "Long fractions sum: {}",
@@ -208,6 +309,16 @@ impl PortfolioManager {
                 .flat_map(|split| split.values())
                 .sum::<Decimal>()
         );
+
+        // The fractions themselves are kept per strategy since they score each strategy at the
+        // next pre-open, so the ceilings are applied to the shares they're weighted by
+        let (shares, capped) = self.allocation_shares();
+        for (key, share) in capped {
+            info!(
+                "Capping allocation of {key} from {share:.4} to {:.4} of usable equity",
+                shares[key]
+            );
+        }
     }
 }
 
@@ -233,34 +344,64 @@ impl Engine {
         let config = Config::get();
         let total_equity = self.intraday.last_account.equity;
         let usable_equity = (Decimal::ONE - config.trading.target_cash_fraction) * total_equity;
+        let shares = pm.latest_allocation_shares(pt);
         let mut equities = Vec::with_capacity(symbols.len());
 
         for &symbol in symbols {
-            let fraction = pm.long.latest_optimal_equity_fraction(pt, symbol);
+            let fraction = shares
+                .iter()
+                .map(|(key, share)| {
+                    share * pm.long.experts[key].latest_optimal_equity_fraction(pt, symbol)
+                })
+                .sum::<Decimal>();
 
-            if fraction < config.trading.minimum_position_equity_fraction {
-                equities.push(Decimal::ZERO);
-            } else {
-                equities.push(fraction * usable_equity);
+            // Any equity above the cap is left as cash rather than redistributed
+            if fraction > config.trading.max_position_equity_fraction {
+                debug!(
+                    "Clamping optimal equity fraction of {symbol} from {fraction:.4} to {:.4}",
+                    config.trading.max_position_equity_fraction
+                );
             }
+
+            equities.push(target_equity(
+                fraction,
+                usable_equity,
+                config.trading.max_position_equity_fraction,
+                config.trading.minimum_position_equity_fraction,
+                config.trading.money_rounding,
+            ));
         }
 
         Ok(equities)
     }
 
     pub fn portfolio_manager_available_cash(&self) -> Decimal {
-        Decimal::max(
-            self.intraday.last_account.cash
-                - Config::get().trading.minimum_cash_fraction * self.intraday.last_account.equity,
-            Decimal::ZERO,
-        )
+        let trading = &Config::get().trading;
+        let account = &self.intraday.last_account;
+        let cash_floor = Decimal::max(
+            trading.minimum_cash_fraction * account.equity,
+            trading.minimum_cash_absolute,
+        );
+
+        // Cash accounts have a multiplier of 1, and their buying power is just their cash
+        let deployable = if trading.use_margin && account.multiplier > Decimal::ONE {
+            account.buying_power * trading.margin_utilization
+        } else {
+            account.cash
+        };
+
+        Decimal::max(deployable - cash_floor, Decimal::ZERO)
     }
 
-    pub fn portfolio_manager_minimum_trade(&self) -> Decimal {
-        Decimal::max(
-            self.intraday.last_account.equity * Config::get().trading.minimum_trade_equity_fraction,
+    // The smallest trade worth making against a position currently worth current_equity
+    pub fn portfolio_manager_minimum_trade(&self, current_equity: Decimal) -> Decimal {
+        let config = &Config::get().trading;
+        let absolute = Decimal::max(
+            self.intraday.last_account.equity * config.minimum_trade_equity_fraction,
             Decimal::new(101, 2),
-        )
+        );
+        let relative = current_equity * config.minimum_trade_position_fraction;
+        Decimal::max(absolute, relative)
     }
 
     async fn get_lastday_returns(&self) -> anyhow::Result<HashMap<Symbol, Decimal>> {
@@ -270,14 +411,235 @@ impl Engine {
             .await?
             .into_iter()
             .flat_map(|(symbol, bars)| {
-                (bars.len() >= 2).then(|| {
-                    let n = bars.len();
-                    (symbol, bars[n - 1].close / bars[n - 2].close)
-                })
+                daily_returns(&bars)
+                    .last()
+                    .map(|&(_, daily_return)| (symbol, daily_return))
             })
             .collect())
     }
 
+    /// Logs the correlation between the daily returns of each pair of held positions, and warns
+    /// about pairs correlated above the configured threshold.
+    pub async fn log_correlations(&self) -> anyhow::Result<()> {
+        let trading = &Config::get().trading;
+        let mut symbols = self
+            .intraday
+            .last_position_map
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        symbols.sort_unstable();
+
+        if symbols.len() < 2 {
+            info!("At least two positions are needed to compute correlations");
+            return Ok(());
+        }
+
+        let mut returns = Vec::with_capacity(symbols.len());
+        for &symbol in &symbols {
+            let bars = self
+                .local_history
+                .get_symbol_history(
+                    symbol,
+                    Timeframe::DaysBeforeNow(trading.correlation_lookback_days),
+                )
+                .await?;
+            returns.push(
+                daily_returns(&bars)
+                    .into_iter()
+                    .flat_map(|(date, daily_return)| {
+                        daily_return
+                            .to_f64()
+                            .map(|daily_return| (date, daily_return))
+                    })
+                    .collect::<BTreeMap<_, _>>(),
+            );
+        }
+
+        let mut table = format!("{:<9}", "");
+        for symbol in &symbols {
+            table.push_str(&format!("{:>8}", symbol.as_str()));
+        }
+
+        let mut concentrated = Vec::new();
+        for (i, symbol) in symbols.iter().enumerate() {
+            table.push_str(&format!("\n{:<9}", symbol.as_str()));
+            for j in 0..symbols.len() {
+                match correlation(&returns[i], &returns[j]) {
+                    Some(correlation) => {
+                        table.push_str(&format!("{correlation:>8.2}"));
+                        if j > i && correlation > trading.correlation_warning_threshold {
+                            concentrated.push((*symbol, symbols[j], correlation));
+                        }
+                    }
+                    None => table.push_str(&format!("{:>8}", "N/A")),
+                }
+            }
+        }
+
+        info!(
+            "Correlation of daily returns over the last {} days\n{table}",
+            trading.correlation_lookback_days
+        );
+        for (a, b, correlation) in concentrated {
+            warn!("{a} and {b} are highly correlated ({correlation:.2}); concentration risk");
+        }
+
+        Ok(())
+    }
+
+    /// Computes the expected daily return of the current holdings as a fraction, weighting the
+    /// mean daily return the WMWU market top 5 strategy sees for each position by its market value.
+    /// Cash is assumed to return nothing. Returns `None` if there is no equity.
+    pub async fn holdings_expected_return(&self) -> anyhow::Result<Option<Decimal>> {
+        let equity = self.intraday.last_account.equity;
+        if equity <= Decimal::ZERO {
+            return Ok(None);
+        }
+
+        let symbols = self
+            .intraday
+            .last_position_map
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut expected_return = Decimal::ZERO;
+        for evaluation in evaluate_symbols(self, &symbols).await? {
+            let symbol = evaluation.symbol;
+            let mean_return = match evaluation.mean_return {
+                Some(mean_return) => mean_return,
+                None => {
+                    warn!("Insufficient history for symbol {symbol}, assuming return of 0");
+                    continue;
+                }
+            };
+
+            let market_value = self.intraday.last_position_map[&symbol].market_value;
+            expected_return += (market_value / equity) * mean_return;
+        }
+
+        Ok(Some(expected_return))
+    }
+
+    /// Computes the beta of the held positions against the configured benchmark, weighting each
+    /// position's daily returns by its current market value. Positions with too little local
+    /// history are left out. Returns `None` if there is nothing to measure.
+    pub async fn portfolio_beta(&self) -> anyhow::Result<Option<f64>> {
+        let trading = &Config::get().trading;
+        let timeframe = || Timeframe::DaysBeforeNow(trading.correlation_lookback_days);
+        let to_f64_returns = |bars: &[Bar]| {
+            daily_returns(bars)
+                .into_iter()
+                .flat_map(|(date, daily_return)| {
+                    daily_return
+                        .to_f64()
+                        .map(|daily_return| (date, daily_return))
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let benchmark = Symbol::from_str(&trading.beta_benchmark)
+            .map_err(|error| anyhow!("Invalid beta benchmark: {error}"))?;
+        let benchmark_returns = to_f64_returns(
+            &self
+                .local_history
+                .get_symbol_history(benchmark, timeframe())
+                .await?,
+        );
+        if benchmark_returns.is_empty() {
+            warn!("No local history for benchmark {benchmark}; cannot compute beta");
+            return Ok(None);
+        }
+
+        // Weighted sum of the position returns on each date, along with the total weight of the
+        // positions which had a return that day
+        let mut weighted_returns = BTreeMap::<Date, (f64, f64)>::new();
+        for (&symbol, position) in &self.intraday.last_position_map {
+            let weight = match position.market_value.to_f64() {
+                Some(weight) if weight > 0.0 => weight,
+                _ => continue,
+            };
+
+            let bars = self
+                .local_history
+                .get_symbol_history(symbol, timeframe())
+                .await?;
+            if bars.len() < 2 {
+                warn!("Excluding {symbol} from beta; not enough local history");
+                continue;
+            }
+
+            for (date, daily_return) in to_f64_returns(&bars) {
+                let entry = weighted_returns.entry(date).or_insert((0.0, 0.0));
+                entry.0 += weight * daily_return;
+                entry.1 += weight;
+            }
+        }
+
+        let portfolio_returns = weighted_returns
+            .into_iter()
+            .map(|(date, (sum, total_weight))| (date, sum / total_weight))
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(beta(&portfolio_returns, &benchmark_returns))
+    }
+
+    pub async fn log_beta(&self) -> anyhow::Result<()> {
+        let trading = &Config::get().trading;
+        match self.portfolio_beta().await? {
+            Some(beta) => info!(
+                "Portfolio beta vs {} over the last {} days: {beta:.3}",
+                trading.beta_benchmark, trading.correlation_lookback_days
+            ),
+            None => info!("Not enough data to compute the portfolio beta"),
+        }
+
+        Ok(())
+    }
+
+    pub async fn log_symbol_evaluations(&self, symbols: &[Symbol]) -> anyhow::Result<()> {
+        let metadata = self
+            .local_history
+            .get_metadata()
+            .await
+            .context("Failed to fetch metadata")?;
+        let evaluations = evaluate_symbols(self, symbols).await?;
+
+        let mut table = format!(
+            "{:<9}{:>6}{:>14}{:>14}{:>16}",
+            "Symbol", "Bars", "WMWU weight", "Mean return", "Median volume"
+        );
+        for evaluation in evaluations {
+            let symbol = evaluation.symbol;
+            let weight = evaluation
+                .weight
+                .map(|weight| format!("{weight:.4}"))
+                .unwrap_or_else(|| "N/A".to_owned());
+            let mean_return = evaluation
+                .mean_return
+                .map(|mean_return| format!("{:+.3}%", mean_return * Decimal::ONE_HUNDRED))
+                .unwrap_or_else(|| "N/A".to_owned());
+            let median_volume = metadata
+                .get(&symbol)
+                .map(|meta| meta.median_volume.to_string())
+                .unwrap_or_else(|| "N/A".to_owned());
+
+            table.push_str(&format!(
+                "\n{:<9}{:>6}{weight:>14}{mean_return:>14}{median_volume:>16}",
+                symbol.as_str(),
+                evaluation.bars
+            ));
+
+            if let Some(reason) = self.intraday.blacklist.get(&symbol) {
+                table.push_str(&format!("  blacklisted: {}", reason.describe()));
+            }
+        }
+
+        info!("Symbol evaluations\n{table}");
+        Ok(())
+    }
+
     pub async fn portfolio_manager_on_pre_open(&mut self) -> anyhow::Result<()> {
         info!("Running portfolio manager pre-open tasks");
 
@@ -292,6 +654,17 @@ impl Engine {
         info!("Updating strategy weights");
         pm.update_strategy_weights(&strategy_returns);
 
+        match self.holdings_expected_return().await {
+            Ok(Some(expected_return)) => debug!(
+                "Expected daily return of current holdings: {:+.3}%",
+                expected_return * Decimal::ONE_HUNDRED
+            ),
+            Ok(None) => (),
+            Err(error) => warn!("Failed to compute expected return of holdings: {error:?}"),
+        }
+
+        self.update_recent_loss_sales().await;
+
         for strategy in self.intraday.portfolio_manager.long.experts.values() {
             strategy.on_pre_open(self).await?;
         }
@@ -302,9 +675,50 @@ impl Engine {
             .portfolio_manager
             .update_initial_long_fractions();
 
+        // With no fractions every strategy targets zero equity for every symbol, so no buys will
+        // be triggered today
+        if self
+            .intraday
+            .portfolio_manager
+            .initial_long_fractions
+            .is_empty()
+        {
+            info!("Holding 100% cash today: no candidates");
+        }
+
         Ok(())
     }
 
+    async fn update_recent_loss_sales(&mut self) {
+        let window = Config::get().trading.wash_sale_window_days;
+        if window == 0 {
+            self.intraday.recent_loss_sales.clear();
+            return;
+        }
+
+        if let Err(error) = self.tax_tracker.ingest(&self.rest).await {
+            warn!("Failed to update tax tracker, recent loss sales may be out of date: {error:?}");
+        }
+
+        let since = Config::localize(OffsetDateTime::now_utc()).date()
+            - time::Duration::days(i64::from(window));
+        self.intraday.recent_loss_sales = self.tax_tracker.loss_sales_since(since);
+
+        if !self.intraday.recent_loss_sales.is_empty() {
+            let mut symbols = self
+                .intraday
+                .recent_loss_sales
+                .iter()
+                .map(Symbol::to_string)
+                .collect::<Vec<_>>();
+            symbols.sort_unstable();
+            info!(
+                "Excluding symbols sold at a loss in the last {window} days from candidacy: {}",
+                symbols.join(", ")
+            );
+        }
+    }
+
     pub fn portfolio_manager_on_close(&mut self) {
         let current_equity = self.equity();
         let pm = &mut self.intraday.portfolio_manager;
@@ -319,11 +733,38 @@ impl Engine {
 
         pm.dbl_equity_at_close = mem::replace(&mut pm.last_equity_at_close, current_equity);
     }
+
+    pub fn log_equity_curve(&self) -> anyhow::Result<()> {
+        let path = match &Config::get().equity_curve_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let write_header = !Path::new(path).exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        if write_header {
+            writeln!(file, "date,equity,cash,positions")?;
+        }
+
+        let account = &self.intraday.last_account;
+        writeln!(
+            file,
+            "{},{},{},{}",
+            Config::localize(OffsetDateTime::now_utc()).date(),
+            account.equity,
+            account.cash,
+            self.intraday.last_position_map.len()
+        )?;
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct PortfolioManagerMetadata {
     long: HashMap<String, StrategyMeta>,
+    #[serde(serialize_with = "serialize_rounded_decimal_nested_map")]
     initial_long_fractions: HashMap<Symbol, HashMap<String, Decimal>>,
     #[serde(default)]
     last_equity_at_close: Equity,
@@ -333,7 +774,9 @@ pub struct PortfolioManagerMetadata {
 
 #[derive(Serialize, Deserialize, Default)]
 struct Equity {
+    #[serde(serialize_with = "serialize_rounded_decimal")]
     cash: Decimal,
+    #[serde(serialize_with = "serialize_rounded_decimal_map")]
     long: HashMap<Symbol, Decimal>,
 }
 
@@ -349,6 +792,7 @@ impl Equity {
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
 struct StrategyMeta {
+    #[serde(serialize_with = "serialize_rounded_decimal")]
     weight: Decimal,
     state: StrategyState,
 }
@@ -482,3 +926,291 @@ pub enum StrategyState {
     Liquidated,
     Disabled,
 }
+
+// Collects each strategy's optimal equity fraction for each of its candidates by symbol. Without
+// any candidates this is empty, and the whole portfolio is held as cash.
+fn long_fractions<I>(strategy_fractions: I) -> HashMap<Symbol, HashMap<&'static str, Decimal>>
+where
+    I: IntoIterator<Item = (&'static str, Vec<(Symbol, Decimal)>)>,
+{
+    let mut long_fractions = HashMap::<Symbol, HashMap<_, _>>::new();
+    for (key, fractions) in strategy_fractions {
+        for (symbol, fraction) in fractions {
+            long_fractions
+                .entry(symbol)
+                .or_default()
+                .insert(key, fraction);
+        }
+    }
+    long_fractions
+}
+
+// Normalizes the strategy weights into shares of usable equity and applies the configured ceilings,
+// redistributing the excess to the uncapped strategies in proportion to their shares. If every
+// strategy ends up capped, the rest is held as cash. Returns the shares along with each capped
+// strategy's share before it was capped.
+fn capped_shares<I>(
+    weights: I,
+    caps: &HashMap<String, Decimal>,
+) -> (HashMap<&'static str, Decimal>, Vec<(&'static str, Decimal)>)
+where
+    I: IntoIterator<Item = (&'static str, Decimal)>,
+{
+    let weights = weights.into_iter().collect::<Vec<_>>();
+    let phi = weights.iter().map(|&(_, weight)| weight).sum::<Decimal>();
+    if phi <= Decimal::ZERO {
+        return (HashMap::new(), Vec::new());
+    }
+
+    let mut shares = weights
+        .into_iter()
+        .map(|(key, weight)| (key, weight / phi))
+        .collect::<HashMap<_, _>>();
+
+    // Each pass caps at least one more strategy, so this terminates
+    let mut capped = Vec::new();
+    loop {
+        let mut overflow = Decimal::ZERO;
+        for (&key, share) in shares.iter_mut() {
+            if let Some(&cap) = caps.get(key) {
+                if *share > cap {
+                    capped.push((key, *share));
+                    overflow += *share - cap;
+                    *share = cap;
+                }
+            }
+        }
+
+        if overflow <= Decimal::ZERO {
+            break;
+        }
+
+        let uncapped_total = shares
+            .iter()
+            .filter(|(key, _)| !capped.iter().any(|(capped_key, _)| capped_key == *key))
+            .map(|(_, &share)| share)
+            .sum::<Decimal>();
+        if uncapped_total <= Decimal::ZERO {
+            debug!("Every strategy is capped, holding {overflow:.4} of usable equity as cash");
+            break;
+        }
+
+        for (key, share) in shares.iter_mut() {
+            if !capped.iter().any(|(capped_key, _)| capped_key == key) {
+                *share += overflow * *share / uncapped_total;
+            }
+        }
+    }
+
+    (shares, capped)
+}
+
+// The equity to hold in a position given its fraction of usable equity. The fraction is capped at
+// max_fraction, and positions below min_fraction aren't held at all.
+fn target_equity(
+    fraction: Decimal,
+    usable_equity: Decimal,
+    max_fraction: Decimal,
+    min_fraction: Decimal,
+    rounding: MoneyRounding,
+) -> Decimal {
+    let fraction = Decimal::min(fraction, max_fraction);
+    if fraction < min_fraction {
+        Decimal::ZERO
+    } else {
+        rounding.round(fraction * usable_equity)
+    }
+}
+
+// The close-to-close return of each bar after the first, keyed by the date of the later bar
+fn daily_returns(bars: &[Bar]) -> Vec<(Date, Decimal)> {
+    bars.windows(2)
+        .map(|window| (window[1].time.date(), window[1].close / window[0].close))
+        .collect()
+}
+
+// Pearson correlation over the dates both series have a return for. None if there are too few
+// common dates or either series is constant over them.
+fn correlation(a: &BTreeMap<Date, f64>, b: &BTreeMap<Date, f64>) -> Option<f64> {
+    let pairs = a
+        .iter()
+        .flat_map(|(date, &x)| b.get(date).map(|&y| (x, y)))
+        .collect::<Vec<_>>();
+    if pairs.len() < 3 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+
+    Some(cov / (var_x * var_y).sqrt())
+}
+
+// Beta of the returns against the benchmark over the dates both have a return for. None under the
+// same conditions as the correlation.
+fn beta(returns: &BTreeMap<Date, f64>, benchmark: &BTreeMap<Date, f64>) -> Option<f64> {
+    let pairs = returns
+        .iter()
+        .flat_map(|(date, &x)| benchmark.get(date).map(|&y| (x, y)))
+        .collect::<Vec<_>>();
+    if pairs.len() < 3 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let (mut cov, mut var_y) = (0.0, 0.0);
+    for (x, y) in pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_y += (y - mean_y).powi(2);
+    }
+
+    if var_y == 0.0 {
+        return None;
+    }
+
+    Some(cov / var_y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str) -> Symbol {
+        Symbol::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn no_candidates_holds_all_cash() {
+        let fractions = long_fractions(vec![
+            ("wmwu_market_top_5", Vec::new()),
+            ("wmwu_dow30", Vec::new()),
+        ]);
+        assert!(fractions.is_empty());
+
+        // Every symbol then has no fraction from any strategy, so none of them targets any equity
+        // and no buy orders can be triggered
+        let usable_equity = Decimal::from(10_000);
+        for name in ["AAPL", "MSFT", "SPY"] {
+            let fraction = fractions
+                .get(&symbol(name))
+                .map(|split| split.values().sum::<Decimal>())
+                .unwrap_or(Decimal::ZERO);
+            assert_eq!(
+                target_equity(
+                    fraction,
+                    usable_equity,
+                    Decimal::ONE,
+                    Decimal::ZERO,
+                    MoneyRounding::Floor
+                ),
+                Decimal::ZERO
+            );
+        }
+    }
+
+    #[test]
+    fn long_fractions_are_split_by_strategy() {
+        let aapl = symbol("AAPL");
+        let msft = symbol("MSFT");
+        let fractions = long_fractions(vec![
+            (
+                "a",
+                vec![(aapl, Decimal::new(3, 1)), (msft, Decimal::new(2, 1))],
+            ),
+            ("b", vec![(aapl, Decimal::new(5, 1))]),
+        ]);
+
+        assert_eq!(fractions.len(), 2);
+        assert_eq!(fractions[&aapl]["a"], Decimal::new(3, 1));
+        assert_eq!(fractions[&aapl]["b"], Decimal::new(5, 1));
+        assert_eq!(fractions[&msft].len(), 1);
+    }
+
+    #[test]
+    fn capped_strategy_overflow_goes_to_the_others_by_weight() {
+        let caps = HashMap::from([("a".to_owned(), Decimal::new(2, 1))]);
+        let (shares, capped) = capped_shares(
+            vec![
+                ("a", Decimal::from(6)),
+                ("b", Decimal::from(3)),
+                ("c", Decimal::from(1)),
+            ],
+            &caps,
+        );
+
+        assert_eq!(capped, vec![("a", Decimal::new(6, 1))]);
+        assert_eq!(shares["a"], Decimal::new(2, 1));
+        // The 0.4 overflow is split 3:1
+        assert_eq!(shares["b"], Decimal::new(6, 1));
+        assert_eq!(shares["c"], Decimal::new(2, 1));
+        assert_eq!(shares.values().sum::<Decimal>(), Decimal::ONE);
+    }
+
+    #[test]
+    fn redistribution_can_cap_another_strategy() {
+        let caps = HashMap::from([
+            ("a".to_owned(), Decimal::new(5, 1)),
+            ("b".to_owned(), Decimal::new(3, 1)),
+        ]);
+        let (shares, capped) = capped_shares(
+            vec![
+                ("a", Decimal::from(7)),
+                ("b", Decimal::from(2)),
+                ("c", Decimal::from(1)),
+            ],
+            &caps,
+        );
+
+        // b only exceeds its cap after a's overflow is handed to it
+        assert_eq!(capped.len(), 2);
+        assert_eq!(shares["a"], Decimal::new(5, 1));
+        assert_eq!(shares["b"], Decimal::new(3, 1));
+        assert!((shares["c"] - Decimal::new(2, 1)).abs() < Decimal::new(1, 20));
+    }
+
+    #[test]
+    fn every_strategy_capped_holds_cash() {
+        let caps = HashMap::from([
+            ("a".to_owned(), Decimal::new(3, 1)),
+            ("b".to_owned(), Decimal::new(3, 1)),
+        ]);
+        let (shares, _) = capped_shares(vec![("a", Decimal::ONE), ("b", Decimal::ONE)], &caps);
+
+        assert_eq!(shares.values().sum::<Decimal>(), Decimal::new(6, 1));
+    }
+
+    #[test]
+    fn zero_weights_give_no_shares() {
+        let (shares, capped) = capped_shares(vec![("a", Decimal::ZERO)], &HashMap::new());
+        assert!(shares.is_empty());
+        assert!(capped.is_empty());
+    }
+
+    #[test]
+    fn target_equity_is_clamped_and_rounded() {
+        let usable_equity = Decimal::new(1_000_001, 2); // $10,000.01
+        let max = Decimal::new(2, 1);
+        let min = Decimal::new(1, 2);
+        let target =
+            |fraction| target_equity(fraction, usable_equity, max, min, MoneyRounding::Floor);
+
+        assert_eq!(target(Decimal::new(1, 1)), Decimal::new(100_000, 2));
+        // Capped at the maximum fraction
+        assert_eq!(target(Decimal::new(5, 1)), Decimal::new(200_000, 2));
+        // Too small to hold
+        assert_eq!(target(Decimal::new(5, 3)), Decimal::ZERO);
+    }
+}
@@ -1,11 +1,16 @@
-use log::{debug, trace};
-use rust_decimal::Decimal;
+use common::{
+    config::{Config, MoneyRounding},
+    util::f64_to_decimal,
+};
+use log::{debug, info, trace, warn};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
 use stock_symbol::Symbol;
 use time::Duration;
 
 use crate::event::stream::StreamRequest;
 
-use super::engine_impl::Engine;
+use super::engine_impl::{remaining_daytrades, BlacklistReason, Engine};
+use super::orders::TradeStatus;
 use anyhow::Context;
 
 impl Engine {
@@ -15,6 +20,15 @@ impl Engine {
             .keys()
             .cloned()
             .chain(self.intraday.portfolio_manager.candidates())
+            .chain(
+                Config::get()
+                    .trading
+                    .always_include
+                    .iter()
+                    .copied()
+                    .filter(|symbol| !self.intraday.blacklist.contains_key(symbol)),
+            )
+            .filter(|symbol| !self.delisted.contains(symbol))
     }
 
     pub async fn position_manager_on_open(&mut self) {
@@ -34,6 +48,12 @@ impl Engine {
                     self.position_buy_trigger(symbol).await?;
                 }
             }
+
+            // Orders can't fill once the market closes, so trimming has to happen now
+            if Config::get().trading.trim_overnight_gap_risk && !self.intraday.gap_risk_trimmed {
+                self.intraday.gap_risk_trimmed = true;
+                self.trim_overnight_gap_risk().await?;
+            }
         }
 
         Ok(())
@@ -71,23 +91,238 @@ impl Engine {
 
         if optimal_equity == Decimal::ZERO {
             debug!("Liquidating position in {symbol}");
-            self.intraday.order_manager.liquidate(symbol).await?;
+            self.intraday
+                .order_manager
+                .liquidate(symbol, Some(position))
+                .await?;
         } else {
             let notional = current_equity - optimal_equity;
 
-            let min_trade = self.portfolio_manager_minimum_trade();
+            let min_trade = self.portfolio_manager_minimum_trade(current_equity);
             if notional <= min_trade {
                 trace!("Trigger for {symbol} ignored; notional amount {notional:.2} is less than threshold of {min_trade:.2}");
                 return Ok(());
             }
 
+            // Liquidations above are always allowed, but rebalancing waits until outside the window
+            if self.in_no_trade_window() {
+                trace!("Trigger for {symbol} ignored; within no-trade window");
+                return Ok(());
+            }
+
             debug!("Selling ${notional:.2} of {symbol}. Optimal equity: {optimal_equity:.2}, current equity: {current_equity:.2}");
-            self.intraday.order_manager.sell(symbol, notional).await?;
+            self.intraday
+                .order_manager
+                .sell(symbol, notional, Some(position))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Sells the configured fraction of the position once the loss from the high water mark reaches
+    // a trailing stop band deeper than any already acted on today
+    pub async fn position_band_sell(&mut self, symbol: Symbol, band: usize) -> anyhow::Result<()> {
+        if self
+            .intraday
+            .trailing_bands_hit
+            .get(&symbol)
+            .map_or(false, |&hit| hit >= band)
+        {
+            return Ok(());
+        }
+
+        let position = match self.intraday.last_position_map.get(&symbol) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+
+        if !self
+            .intraday
+            .order_manager
+            .trade_status(symbol)
+            .is_sell_daytrade_safe()
+        {
+            trace!("Trailing stop band for {symbol} ignored due to trade status");
+            return Ok(());
+        }
+
+        let sell_fraction = Config::get().trading.trailing_stop_bands[band].sell_fraction;
+        // Only liquidations go ahead within the no-trade window. The band isn't recorded, so the
+        // partial sell can still happen once the window is over.
+        if sell_fraction < Decimal::ONE && self.in_no_trade_window() {
+            trace!("Trailing stop band for {symbol} ignored; within no-trade window");
+            return Ok(());
+        }
+
+        if sell_fraction >= Decimal::ONE {
+            info!(
+                "Trailing stop band {} reached for {symbol}; liquidating",
+                band + 1
+            );
+            self.intraday
+                .order_manager
+                .liquidate(symbol, Some(position))
+                .await?;
+        } else {
+            let notional = position.market_value * sell_fraction;
+            info!(
+                "Trailing stop band {} reached for {symbol}; selling ${notional:.2}",
+                band + 1
+            );
+            self.intraday
+                .order_manager
+                .sell(symbol, notional, Some(position))
+                .await?;
+        }
+
+        // Recorded only once the order went through, so a failed order is retried on the next bar
+        self.intraday.trailing_bands_hit.insert(symbol, band);
+        Ok(())
+    }
+
+    pub async fn log_overnight_gap_risk(&mut self) {
+        for (symbol, risk) in self.overnight_gap_risks().await {
+            warn!(
+                "Overnight gap risk of {symbol} is an estimated {:.2}% of equity",
+                risk * Decimal::ONE_HUNDRED
+            );
+        }
+    }
+
+    async fn trim_overnight_gap_risk(&mut self) -> anyhow::Result<()> {
+        let threshold = Config::get().trading.overnight_gap_risk_threshold;
+        for (symbol, risk) in self.overnight_gap_risks().await {
+            if !self
+                .intraday
+                .order_manager
+                .trade_status(symbol)
+                .is_sell_daytrade_safe()
+            {
+                warn!("Not trimming {symbol} for overnight gap risk due to trade status");
+                continue;
+            }
+
+            let position = match self.intraday.last_position_map.get(&symbol) {
+                Some(position) => position,
+                None => continue,
+            };
+
+            // Sells submitted earlier this tick already reduce the position, so only the rest of
+            // the excess is sold. A pending liquidation takes care of the whole position.
+            let pending = match self.intraday.order_manager.pending_sell_notional(symbol) {
+                Some(pending) => pending,
+                None => continue,
+            };
+
+            // Risk scales with the position's value, so this brings it down to the threshold
+            let notional = position.market_value * (Decimal::ONE - threshold / risk) - pending;
+            self.intraday.gap_trimmed_symbols.insert(symbol);
+            if notional <= Decimal::ZERO {
+                continue;
+            }
+
+            info!(
+                "Trimming ${notional:.2} of {symbol}; overnight gap risk is an estimated {:.2}% of \
+                 equity",
+                risk * Decimal::ONE_HUNDRED
+            );
+            self.intraday
+                .order_manager
+                .sell(symbol, notional, Some(position))
+                .await?;
         }
 
         Ok(())
     }
 
+    // Returns the held positions whose estimated overnight gap risk, as a fraction of equity,
+    // exceeds the configured threshold
+    async fn overnight_gap_risks(&mut self) -> Vec<(Symbol, Decimal)> {
+        let threshold = Config::get().trading.overnight_gap_risk_threshold;
+        let equity = self.intraday.last_account.equity;
+        if threshold <= Decimal::ZERO || equity <= Decimal::ZERO {
+            return Vec::new();
+        }
+
+        let positions = self
+            .intraday
+            .last_position_map
+            .iter()
+            .map(|(&symbol, position)| (symbol, position.market_value, position.current_price))
+            .collect::<Vec<_>>();
+
+        let mut risks = Vec::new();
+        for (symbol, market_value, price) in positions {
+            // The typical daily range stands in for the size of an adverse gap. get_atr only
+            // returns a value in ATR mode, so the average span is used otherwise.
+            let atr_fraction = match (self.get_atr(symbol).await, price.to_f64()) {
+                (Some(atr), Some(price)) if price > 0.0 => Some(atr / price),
+                _ => None,
+            };
+            let gap_fraction = match atr_fraction {
+                Some(fraction) => fraction,
+                None => match self.get_avg_span(symbol).await {
+                    Some(span) => span,
+                    None => continue,
+                },
+            };
+
+            let gap_fraction = match f64_to_decimal(gap_fraction) {
+                Ok(fraction) => fraction,
+                Err(error) => {
+                    warn!("Invalid gap estimate for {symbol}: {error}");
+                    continue;
+                }
+            };
+
+            let risk = market_value * gap_fraction / equity;
+            if risk > threshold {
+                risks.push((symbol, risk));
+            }
+        }
+
+        risks
+    }
+
+    // Manually exits a single position without affecting the rest of the account
+    pub async fn liquidate_symbol(&mut self, symbol: Symbol) -> anyhow::Result<()> {
+        let position = match self.intraday.last_position_map.get(&symbol) {
+            Some(position) => position,
+            None => {
+                info!("Not liquidating {symbol}; no currently held position");
+                return Ok(());
+            }
+        };
+
+        if !self
+            .intraday
+            .order_manager
+            .trade_status(symbol)
+            .is_sell_daytrade_safe()
+        {
+            warn!("Not liquidating {symbol}; selling it now would count as a day trade");
+            return Ok(());
+        }
+
+        info!("Manually liquidating position in {symbol}");
+        self.intraday
+            .order_manager
+            .liquidate(symbol, Some(position))
+            .await
+    }
+
+    // Returns the number of remaining day trades if buys should be suppressed due to PDT rules
+    fn pdt_blocks_buys(&self) -> Option<u32> {
+        let config = &Config::get().trading;
+        if !config.pdt_protection {
+            return None;
+        }
+
+        remaining_daytrades(&self.intraday.last_account)
+            .filter(|&remaining| remaining <= config.pdt_reserved_daytrades)
+    }
+
     pub async fn position_buy_trigger(&mut self, symbol: Symbol) -> anyhow::Result<()> {
         if !self.within_duration_of_close(Duration::seconds(15)) {
             return Ok(());
@@ -103,6 +338,27 @@ impl Engine {
             return Ok(());
         }
 
+        if self.in_no_trade_window() {
+            trace!("Trigger for {symbol} ignored; within no-trade window");
+            return Ok(());
+        }
+
+        // Buying now would undo the trim right before the gap it protects against
+        if self.intraday.gap_trimmed_symbols.contains(&symbol) {
+            trace!("Trigger for {symbol} ignored; trimmed for overnight gap risk");
+            return Ok(());
+        }
+
+        let is_new_position = !self.intraday.last_position_map.contains_key(&symbol)
+            && !self.intraday.new_positions.contains(&symbol);
+        let max_new_positions = Config::get().trading.max_new_positions_per_day;
+        if let Some(max_new_positions) = max_new_positions {
+            if is_new_position && self.intraday.new_positions.len() >= max_new_positions {
+                trace!("Trigger for {symbol} ignored; new position limit reached");
+                return Ok(());
+            }
+        }
+
         let current_equity = self
             .intraday
             .last_position_map
@@ -115,18 +371,170 @@ impl Engine {
             .context("Failed to obtain optimal equity")?[0];
 
         let deficit = optimal_equity - current_equity;
-        let cash = self.portfolio_manager_available_cash();
-        let notional = Decimal::min(deficit, cash);
+        // Buys submitted earlier in the tick haven't come out of the account's cash yet
+        let cash = Decimal::max(
+            self.portfolio_manager_available_cash()
+                - self.intraday.order_manager.pending_buy_notional(),
+            Decimal::ZERO,
+        );
+        let notional = buy_notional(deficit, cash, Config::get().trading.money_rounding);
 
-        let min_trade = self.portfolio_manager_minimum_trade();
+        let min_trade = self.portfolio_manager_minimum_trade(current_equity);
         if notional <= min_trade {
             trace!("Trigger for {symbol} ignored; notional amount {notional:.2} is less than threshold of {min_trade:.2}");
             return Ok(());
         }
 
+        // A position opened now could become a round trip if it needs to be sold later today, so
+        // don't open any when near the PDT limit. Sells are risk-reducing and still allowed.
+        if let Some(remaining) = self.pdt_blocks_buys() {
+            info!("Not buying {symbol}; only {remaining} day trades remaining under PDT rules");
+            return Ok(());
+        }
+
+        if self.intraday.no_round_trips {
+            trace!("Trigger for {symbol} ignored; no new round trips until the next open");
+            return Ok(());
+        }
+
+        if !self.is_symbol_tradable(symbol).await? {
+            warn!("Not buying {symbol}; the asset is halted or otherwise not tradable");
+            return Ok(());
+        }
+
         debug!("Buying ${notional:.2} of {symbol}. Optimal equity: {optimal_equity:.2}, current equity: {current_equity:.2}");
-        self.intraday.order_manager.buy(symbol, notional).await?;
+        self.intraday
+            .order_manager
+            .buy(
+                symbol,
+                notional,
+                self.intraday.last_position_map.get(&symbol),
+            )
+            .await?;
+
+        // The order manager may have declined to submit the order
+        let submitted =
+            self.intraday.order_manager.trade_status(symbol) == TradeStatus::OrderPending;
+        if is_new_position && submitted {
+            self.intraday.new_positions.insert(symbol);
+
+            if max_new_positions == Some(self.intraday.new_positions.len()) {
+                info!(
+                    "Opened {} new positions today; no more will be opened until the next session",
+                    self.intraday.new_positions.len()
+                );
+            }
+        }
 
         Ok(())
     }
+
+    // Symbols outside the pre-open universe may have been halted or delisted since, so we re-check
+    // their status with Alpaca before placing an order
+    async fn is_symbol_tradable(&mut self, symbol: Symbol) -> anyhow::Result<bool> {
+        if self.intraday.blacklist.contains_key(&symbol) {
+            return Ok(false);
+        }
+
+        if self.intraday.universe.contains(&symbol) {
+            return Ok(true);
+        }
+
+        match self.check_delisted(symbol).await? {
+            Some(asset) => match BlacklistReason::for_equity(&asset) {
+                Some(reason) => {
+                    self.intraday.blacklist.insert(symbol, reason);
+                    Ok(false)
+                }
+                None => {
+                    self.intraday.universe.insert(symbol);
+                    Ok(true)
+                }
+            },
+            None => {
+                self.intraday
+                    .blacklist
+                    .insert(symbol, BlacklistReason::Delisted);
+                Ok(false)
+            }
+        }
+    }
+}
+
+// The notional of a buy covering the deficit, capped at the available cash. The deficit is rounded
+// before capping so that rounding can never push the order above the cash.
+fn buy_notional(deficit: Decimal, cash: Decimal, rounding: MoneyRounding) -> Decimal {
+    Decimal::min(rounding.round(deficit), MoneyRounding::Floor.round(cash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Buys a deficit in each symbol in turn, taking each order out of the remaining cash like the
+    // pending buys are. Returns the total notional bought.
+    fn deploy(deficits: &[Decimal], cash: Decimal, rounding: MoneyRounding) -> Decimal {
+        let mut pending = Decimal::ZERO;
+        for &deficit in deficits {
+            let remaining = Decimal::max(cash - pending, Decimal::ZERO);
+            let notional = buy_notional(deficit, remaining, rounding);
+            assert!(notional <= remaining);
+            if notional > Decimal::ZERO {
+                pending += notional;
+            }
+        }
+        pending
+    }
+
+    #[test]
+    fn deploying_all_cash_never_over_allocates() {
+        // Each deficit rounds up to $333.34 under banker's rounding, which would add up to $0.02
+        // more than the cash
+        let cash = Decimal::new(100_000, 2);
+        let deficits = [Decimal::new(333_336, 3); 3];
+        for rounding in [MoneyRounding::Floor, MoneyRounding::Bankers] {
+            assert!(deploy(&deficits, cash, rounding) <= cash);
+        }
+        assert_eq!(
+            deploy(&deficits, cash, MoneyRounding::Bankers),
+            Decimal::new(100_000, 2)
+        );
+        assert_eq!(
+            deploy(&deficits, cash, MoneyRounding::Floor),
+            Decimal::new(99_999, 2)
+        );
+    }
+
+    #[test]
+    fn sub_cent_cash_is_rounded_down() {
+        // The available cash after the cash floor can have fractions of a cent
+        let cash = Decimal::new(1_234_567, 4); // $123.4567
+        let deficits = [
+            Decimal::new(50, 0),
+            Decimal::new(50, 0),
+            Decimal::new(50, 0),
+        ];
+        for rounding in [MoneyRounding::Floor, MoneyRounding::Bankers] {
+            let total = deploy(&deficits, cash, rounding);
+            assert_eq!(total, Decimal::new(12_345, 2));
+            assert!(total <= cash);
+        }
+    }
+
+    #[test]
+    fn small_deficits_are_not_capped() {
+        let cash = Decimal::from(1_000);
+        assert_eq!(
+            buy_notional(Decimal::new(12_345, 3), cash, MoneyRounding::Bankers),
+            Decimal::new(1_234, 2)
+        );
+        assert_eq!(
+            buy_notional(Decimal::new(12_355, 3), cash, MoneyRounding::Bankers),
+            Decimal::new(1_236, 2)
+        );
+        assert_eq!(
+            buy_notional(Decimal::new(12_359, 3), cash, MoneyRounding::Floor),
+            Decimal::new(1_235, 2)
+        );
+    }
 }
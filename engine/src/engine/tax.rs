@@ -1,5 +1,5 @@
 use std::{
-    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     ops::AddAssign,
 };
 
@@ -81,6 +81,30 @@ impl TaxTracker {
         Ok(ret)
     }
 
+    /// Returns every calendar year in which a trade or dividend was recorded.
+    pub fn years(&self) -> BTreeSet<i32> {
+        self.tax_history
+            .values()
+            .flat_map(|history| history.history.keys())
+            .map(|&DateSerdeWrapper(date)| date.year())
+            .chain(self.dividends.iter().map(|div| div.date.year()))
+            .collect()
+    }
+
+    /// Returns the symbols which were sold at a loss on or after the given date.
+    pub fn loss_sales_since(&self, since: Date) -> HashSet<Symbol> {
+        self.tax_history
+            .iter()
+            .filter_map(|(&symbol, history)| match history.last_loss_sale() {
+                Ok(last_loss_sale) => last_loss_sale.filter(|&date| date >= since).map(|_| symbol),
+                Err(error) => {
+                    warn!("Failed to determine loss sales for {symbol}: {error:?}");
+                    None
+                }
+            })
+            .collect()
+    }
+
     fn ingest_order_if_eligible(&mut self, order: &Order) {
         // Already ingested
         if self.ingested_orders.contains(&order.id) {
@@ -176,6 +200,16 @@ impl SymbolTaxHistory {
     }
 
     fn tax_report(&self, calendar_year: i32) -> anyhow::Result<Capital> {
+        self.build_report(calendar_year)
+            .map(SymbolTaxReportBuilder::into_capital)
+    }
+
+    fn last_loss_sale(&self) -> anyhow::Result<Option<Date>> {
+        // The calendar year doesn't matter here since we only want the loss sale dates
+        self.build_report(0).map(|builder| builder.last_loss_sale)
+    }
+
+    fn build_report(&self, calendar_year: i32) -> anyhow::Result<SymbolTaxReportBuilder> {
         let mut builder = SymbolTaxReportBuilder::new(calendar_year);
 
         for (&DateSerdeWrapper(date), event) in &self.history {
@@ -196,7 +230,7 @@ impl SymbolTaxHistory {
             }
         }
 
-        Ok(builder.into_capital())
+        Ok(builder)
     }
 }
 
@@ -204,6 +238,7 @@ struct SymbolTaxReportBuilder {
     capital: Capital,
     purchases: VecDeque<(Date, SecurityTransaction)>,
     calendar_year: i32,
+    last_loss_sale: Option<Date>,
 }
 
 impl SymbolTaxReportBuilder {
@@ -212,6 +247,7 @@ impl SymbolTaxReportBuilder {
             capital: Capital::new(),
             purchases: VecDeque::new(),
             calendar_year,
+            last_loss_sale: None,
         }
     }
 
@@ -236,19 +272,26 @@ impl SymbolTaxReportBuilder {
 
             let matched_shares = Decimal::min(unmatched_shares, purchase.shares);
 
-            if !paper && sale_date.year() == self.calendar_year {
+            if !paper {
                 let purchase_cost_basis = matched_shares * purchase.avg_price;
                 let sale_cost_basis = matched_shares * sale.avg_price;
                 let delta = sale_cost_basis - purchase_cost_basis;
 
-                match (
-                    delta < Decimal::ZERO,
-                    is_at_least_one_year_apart(purchase_date, sale_date),
-                ) {
-                    (true, true) => self.capital.long_term_losses -= delta,
-                    (true, false) => self.capital.short_term_losses -= delta,
-                    (false, true) => self.capital.long_term_gains += delta,
-                    (false, false) => self.capital.short_term_gains += delta,
+                // Sales are ingested in chronological order
+                if delta < Decimal::ZERO {
+                    self.last_loss_sale = Some(sale_date);
+                }
+
+                if sale_date.year() == self.calendar_year {
+                    match (
+                        delta < Decimal::ZERO,
+                        is_at_least_one_year_apart(purchase_date, sale_date),
+                    ) {
+                        (true, true) => self.capital.long_term_losses -= delta,
+                        (true, false) => self.capital.short_term_losses -= delta,
+                        (false, true) => self.capital.long_term_gains += delta,
+                        (false, false) => self.capital.short_term_gains += delta,
+                    }
                 }
             }
 
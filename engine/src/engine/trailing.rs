@@ -55,7 +55,7 @@ impl PriceTracker {
     }
 
     pub fn patched_json(&self) -> String {
-        let open = Time::from_hms(9, 30, 0).unwrap();
+        let open = Self::open_time();
         let mut history = HashMap::with_capacity(self.stocks.len());
         for (&symbol, stock) in &self.stocks {
             let mut prices_iter = stock.prices.iter().peekable();
@@ -80,6 +80,15 @@ impl PriceTracker {
         }
         serde_json::to_string(&history).unwrap()
     }
+
+    pub fn open_time() -> Time {
+        Time::from_hms(9, 30, 0).unwrap()
+    }
+
+    // Inverse of `patched_json`, yielding one price per minute since the open for each symbol
+    pub fn parse_patched_json(json: &str) -> serde_json::Result<HashMap<Symbol, Vec<f64>>> {
+        serde_json::from_str(json)
+    }
 }
 
 #[derive(Serialize)]
@@ -7,7 +7,7 @@ use std::time::Duration as StdDuration;
 use time::{Duration as TimeDuration, OffsetDateTime};
 use tokio::time::sleep;
 
-const EPSILON: StdDuration = StdDuration::from_millis(5);
+const EPSILON: TimeDuration = TimeDuration::milliseconds(5);
 
 pub async fn run_task(emitter: EventEmitter<ClockEvent>, rest: AlpacaRestApi) {
     if run_inner(&emitter, rest).await.is_err() {
@@ -16,90 +16,153 @@ pub async fn run_task(emitter: EventEmitter<ClockEvent>, rest: AlpacaRestApi) {
 }
 
 async fn run_inner(emitter: &EventEmitter<ClockEvent>, rest: AlpacaRestApi) -> Result<(), Panic> {
+    let config = Config::get();
+    let pre_open_hours_offset = config.trading.pre_open_hours_offset;
+    let tick_duration = tick_duration(config.trading.seconds_per_tick);
+
     let mut market_clock = fetch_clock(&rest).await?;
 
     log::debug!("Initial clock: {market_clock:#?}");
 
     // Get the clock aligned with real time
-
-    let last_open = if Config::get().force_open && market_clock.is_open {
-        emitter.emit(ClockEvent::PreOpen);
-        emitter.emit(ClockEvent::Open {
-            next_close: market_clock.next_close,
-        });
-        OffsetDateTime::now_utc()
-    } else {
-        let last_open = market_clock.next_open;
-        market_clock = open_sequence(market_clock, emitter, &rest).await?;
-        last_open
-    };
+    let schedule = next_clock_events(
+        OffsetDateTime::now_utc(),
+        &market_clock,
+        pre_open_hours_offset,
+        config.force_open,
+    );
+    let mut last_open = session_open(&schedule).unwrap_or(market_clock.next_open);
+    execute(schedule, emitter).await;
+    // Once the session has opened, the clock's next open is the one after this session
+    market_clock = fetch_clock(&rest).await?;
 
     log::debug!("Last open: {last_open}. Starting clock: {market_clock:#?}");
 
-    run_clock(last_open, market_clock, emitter, rest).await
-}
-
-async fn run_clock(
-    mut last_open: OffsetDateTime,
-    mut market_clock: Clock,
-    emitter: &EventEmitter<ClockEvent>,
-    rest: AlpacaRestApi,
-) -> Result<(), Panic> {
-    let tick_duration = StdDuration::from_secs(Config::get().trading.seconds_per_tick);
-
     // One cycle of this loop occurrs over the course of a day or longer. The top of the loop
     // coincides with the time immediately after the market opens.
     loop {
-        let mut tick_time = last_open;
+        execute(
+            session_events(
+                last_open,
+                last_open,
+                market_clock.next_close,
+                market_clock.next_open,
+                tick_duration,
+            ),
+            emitter,
+        )
+        .await;
+
+        market_clock = fetch_clock(&rest).await?;
+        last_open = market_clock.next_open;
+        execute(open_events(&market_clock, pre_open_hours_offset), emitter).await;
+        market_clock = fetch_clock(&rest).await?;
+    }
+}
+
+// Emits each event once its scheduled time arrives
+async fn execute(schedule: Vec<(OffsetDateTime, ClockEvent)>, emitter: &EventEmitter<ClockEvent>) {
+    for (time, event) in schedule {
+        sleep(duration_until(time)).await;
+        emitter.emit(event);
+    }
+}
+
+fn tick_duration(seconds_per_tick: u64) -> TimeDuration {
+    TimeDuration::seconds(i64::try_from(seconds_per_tick).unwrap_or(i64::MAX))
+}
+
+/// The events leading up to the next session given a freshly fetched market clock: the pre-open
+/// and open ahead of the clock's next open, or both immediately if `force_open` is set and the
+/// market is already open.
+fn next_clock_events(
+    now: OffsetDateTime,
+    market_clock: &Clock,
+    pre_open_hours_offset: u8,
+    force_open: bool,
+) -> Vec<(OffsetDateTime, ClockEvent)> {
+    if force_open && market_clock.is_open {
+        forced_open_events(now, market_clock)
+    } else {
+        open_events(market_clock, pre_open_hours_offset)
+    }
+}
 
-        loop {
-            tick_time += tick_duration;
-            sleep(duration_until(tick_time)).await;
+// The time at which the schedule opens the session, if it does
+fn session_open(schedule: &[(OffsetDateTime, ClockEvent)]) -> Option<OffsetDateTime> {
+    schedule
+        .iter()
+        .find(|(_, event)| matches!(event, ClockEvent::Open { .. }))
+        .map(|&(time, _)| time)
+}
 
-            let current_time = OffsetDateTime::now_utc();
-            let duration_since_open = current_time - last_open;
-            let duration_until_close = market_clock.next_close - current_time;
+/// The events of one session: a tick every `tick_duration` after `start` until the close, then the
+/// close itself.
+fn session_events(
+    start: OffsetDateTime,
+    open: OffsetDateTime,
+    next_close: OffsetDateTime,
+    next_open: OffsetDateTime,
+    tick_duration: TimeDuration,
+) -> Vec<(OffsetDateTime, ClockEvent)> {
+    let mut events = Vec::new();
+    let mut tick_time = start;
 
-            emitter.emit(ClockEvent::Tick {
-                duration_since_open,
+    loop {
+        tick_time += tick_duration;
+        let duration_until_close = next_close - tick_time;
+        events.push((
+            tick_time,
+            ClockEvent::Tick {
+                duration_since_open: tick_time - open,
                 duration_until_close,
-            });
+            },
+        ));
 
-            if duration_until_close < tick_duration + EPSILON {
-                sleep(duration_until(market_clock.next_close)).await;
-                break;
-            }
+        if duration_until_close < tick_duration + EPSILON {
+            break;
         }
-
-        emitter.emit(ClockEvent::Close {
-            next_open: market_clock.next_open,
-        });
-        market_clock = fetch_clock(&rest).await?;
-        last_open = market_clock.next_open;
-        market_clock = open_sequence(market_clock, emitter, &rest).await?;
     }
-}
 
-async fn open_sequence(
-    market_clock: Clock,
-    emitter: &EventEmitter<ClockEvent>,
-    rest: &AlpacaRestApi,
-) -> Result<Clock, Panic> {
-    sleep(duration_until_pre_open(market_clock)).await;
-    emitter.emit(ClockEvent::PreOpen);
-    sleep(duration_until(market_clock.next_open)).await;
-    emitter.emit(ClockEvent::Open {
-        next_close: market_clock.next_close,
-    });
-    fetch_clock(rest).await
+    events.push((next_close, ClockEvent::Close { next_open }));
+    events
 }
 
-fn duration_until_pre_open(market_clock: Clock) -> StdDuration {
-    let seconds = i64::from(Config::get().trading.pre_open_hours_offset) * 60 * 60;
-    let pre_open_offset_duration = TimeDuration::new(seconds, 0);
-    let pre_open = market_clock.next_open - pre_open_offset_duration;
+/// The pre-open and open events ahead of the market clock's next open.
+fn open_events(
+    market_clock: &Clock,
+    pre_open_hours_offset: u8,
+) -> Vec<(OffsetDateTime, ClockEvent)> {
+    let seconds = i64::from(pre_open_hours_offset) * 60 * 60;
+    let pre_open = market_clock.next_open - TimeDuration::new(seconds, 0);
     log::debug!("Pre-open time: {pre_open}");
-    duration_until(pre_open)
+
+    vec![
+        (pre_open, ClockEvent::PreOpen),
+        (
+            market_clock.next_open,
+            ClockEvent::Open {
+                next_close: market_clock.next_close,
+            },
+        ),
+    ]
+}
+
+/// With `force_open` set and the market already open, the session is started immediately rather
+/// than waiting for the next open.
+fn forced_open_events(
+    now: OffsetDateTime,
+    market_clock: &Clock,
+) -> Vec<(OffsetDateTime, ClockEvent)> {
+    vec![
+        (now, ClockEvent::PreOpen),
+        (
+            now,
+            ClockEvent::Open {
+                next_close: market_clock.next_close,
+            },
+        ),
+    ]
 }
 
 fn duration_until(odt: OffsetDateTime) -> StdDuration {
@@ -128,6 +191,7 @@ async fn fetch_clock(rest: &AlpacaRestApi) -> Result<Clock, Panic> {
                 }
 
                 retries += 1;
+                rest.request_stats().record_retry();
                 sleep(StdDuration::from_secs(1)).await;
             }
         }
@@ -135,3 +199,188 @@ async fn fetch_clock(rest: &AlpacaRestApi) -> Result<Clock, Panic> {
 }
 
 struct Panic;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    fn utc(year: i32, month: Month, day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day)
+            .unwrap()
+            .with_hms(hour, minute, 0)
+            .unwrap()
+            .assume_utc()
+    }
+
+    fn clock(
+        timestamp: OffsetDateTime,
+        is_open: bool,
+        next_open: OffsetDateTime,
+        next_close: OffsetDateTime,
+    ) -> Clock {
+        Clock {
+            timestamp,
+            is_open,
+            next_open,
+            next_close,
+        }
+    }
+
+    fn ticks(schedule: &[(OffsetDateTime, ClockEvent)]) -> usize {
+        schedule
+            .iter()
+            .filter(|(_, event)| matches!(event, ClockEvent::Tick { .. }))
+            .count()
+    }
+
+    #[test]
+    fn normal_day_opens_after_pre_open_and_ticks_until_close() {
+        let open = utc(2024, Month::January, 9, 14, 30);
+        let close = utc(2024, Month::January, 9, 21, 0);
+        let now = utc(2024, Month::January, 9, 10, 0);
+        let market_clock = clock(now, false, open, close);
+
+        let schedule = next_clock_events(now, &market_clock, 2, false);
+        assert_eq!(
+            schedule,
+            vec![
+                (utc(2024, Month::January, 9, 12, 30), ClockEvent::PreOpen),
+                (open, ClockEvent::Open { next_close: close }),
+            ]
+        );
+        assert_eq!(session_open(&schedule), Some(open));
+
+        let next_open = utc(2024, Month::January, 10, 14, 30);
+        let session = session_events(open, open, close, next_open, tick_duration(60));
+        // A tick every minute from 14:31 to 20:59, then the close
+        assert_eq!(ticks(&session), 389);
+        assert_eq!(
+            session[0],
+            (
+                utc(2024, Month::January, 9, 14, 31),
+                ClockEvent::Tick {
+                    duration_since_open: TimeDuration::minutes(1),
+                    duration_until_close: TimeDuration::minutes(389),
+                }
+            )
+        );
+        assert_eq!(session[388].0, utc(2024, Month::January, 9, 20, 59));
+        assert_eq!(
+            session.last(),
+            Some(&(close, ClockEvent::Close { next_open }))
+        );
+    }
+
+    #[test]
+    fn half_day_closes_early() {
+        // The day after Thanksgiving closes at 13:00 Eastern
+        let open = utc(2024, Month::November, 29, 14, 30);
+        let close = utc(2024, Month::November, 29, 18, 0);
+        let next_open = utc(2024, Month::December, 2, 14, 30);
+
+        let session = session_events(open, open, close, next_open, tick_duration(60));
+        assert_eq!(ticks(&session), 209);
+        assert_eq!(
+            session.last(),
+            Some(&(close, ClockEvent::Close { next_open }))
+        );
+        // The last tick still fires before the early close
+        assert_eq!(
+            session[session.len() - 2].0,
+            utc(2024, Month::November, 29, 17, 59)
+        );
+    }
+
+    #[test]
+    fn weekend_waits_for_monday_open() {
+        let now = utc(2024, Month::January, 13, 12, 0);
+        let open = utc(2024, Month::January, 15, 14, 30);
+        let close = utc(2024, Month::January, 15, 21, 0);
+        let market_clock = clock(now, false, open, close);
+
+        // force_open only applies while the market is open
+        for force_open in [false, true] {
+            assert_eq!(
+                next_clock_events(now, &market_clock, 2, force_open),
+                vec![
+                    (utc(2024, Month::January, 15, 12, 30), ClockEvent::PreOpen),
+                    (open, ClockEvent::Open { next_close: close }),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn force_open_starts_the_session_immediately() {
+        let now = utc(2024, Month::January, 9, 16, 0);
+        let close = utc(2024, Month::January, 9, 21, 0);
+        let next_open = utc(2024, Month::January, 10, 14, 30);
+        let next_close = utc(2024, Month::January, 10, 21, 0);
+        // While the market is open, the next open is the next trading day
+        let market_clock = clock(now, true, next_open, close);
+
+        let schedule = next_clock_events(now, &market_clock, 2, true);
+        assert_eq!(
+            schedule,
+            vec![
+                (now, ClockEvent::PreOpen),
+                (now, ClockEvent::Open { next_close: close }),
+            ]
+        );
+        assert_eq!(session_open(&schedule), Some(now));
+
+        // Without force_open, we wait for the next day's session
+        let market_clock = clock(now, true, next_open, next_close);
+        assert_eq!(
+            next_clock_events(now, &market_clock, 2, false),
+            vec![
+                (utc(2024, Month::January, 10, 12, 30), ClockEvent::PreOpen),
+                (next_open, ClockEvent::Open { next_close }),
+            ]
+        );
+    }
+
+    #[test]
+    fn pre_open_follows_the_open_across_dst() {
+        // 2024 DST started on March 10, moving the open from 14:30Z to 13:30Z
+        let now = utc(2024, Month::March, 8, 21, 0);
+        let open = utc(2024, Month::March, 11, 13, 30);
+        let close = utc(2024, Month::March, 11, 20, 0);
+        let market_clock = clock(now, false, open, close);
+
+        assert_eq!(
+            next_clock_events(now, &market_clock, 2, false),
+            vec![
+                (utc(2024, Month::March, 11, 11, 30), ClockEvent::PreOpen),
+                (open, ClockEvent::Open { next_close: close }),
+            ]
+        );
+
+        // The Friday session before the change closes on its own schedule and reports Monday's
+        // shifted open
+        let friday_open = utc(2024, Month::March, 8, 14, 30);
+        let session = session_events(friday_open, friday_open, now, open, tick_duration(60));
+        assert_eq!(ticks(&session), 389);
+        assert_eq!(
+            session.last(),
+            Some(&(now, ClockEvent::Close { next_open: open }))
+        );
+
+        // And the session after it is still six and a half hours long
+        let session = session_events(open, open, close, open, tick_duration(60));
+        assert_eq!(ticks(&session), 389);
+    }
+
+    #[test]
+    fn ticks_never_pass_the_close() {
+        // A tick length which doesn't divide the session evenly
+        let open = utc(2024, Month::January, 9, 14, 30);
+        let close = utc(2024, Month::January, 9, 21, 0);
+        let session = session_events(open, open, close, close, tick_duration(7 * 60));
+
+        let (last_tick, _) = session[session.len() - 2];
+        assert!(last_tick < close);
+        assert!(close - last_tick < TimeDuration::minutes(7));
+    }
+}
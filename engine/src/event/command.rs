@@ -1,15 +1,16 @@
-use std::array;
 use std::{num::NonZeroUsize, time::Duration};
 
-use crate::event::{Command, EventEmitter};
+use crate::event::{ClockEvent, Command, EventEmitter, OrderAmount};
 use crate::{PortfolioStrategySubcommand, TaxSubcommand};
-use common::config::Config;
+use common::{config::Config, util::DATE_FORMAT};
+use entity::trading::{OrderSide, OrderTimeInForce, OrderType};
 use log::error;
+use rust_decimal::Decimal;
 use rustyline::error::ReadlineError;
 use rustyline::history::FileHistory;
 use rustyline::Editor;
 use stock_symbol::Symbol;
-use time::UtcOffset;
+use time::{Date, OffsetDateTime, UtcOffset};
 use tokio::task;
 
 pub async fn run_task(emitter: EventEmitter<Command>, editor: Editor<(), FileHistory>) {
@@ -86,20 +87,45 @@ fn parse_command(input: &str) -> Option<Command> {
     let args = components.collect::<Vec<_>>();
 
     match command {
+        "api-stats" => Some(Command::ApiStats),
+        "beta" => Some(Command::Beta),
         "buytoggle" => buytoggle(&args),
+        "cg" | "check-gaps" => check_gaps(&args),
+        "check-database" | "checkdb" => Some(Command::CheckDatabase),
+        "clear-delisted" => clear_delisted(&args),
+        "clock" => Some(Command::Clock),
+        "correlations" | "corr" => Some(Command::Correlations),
         "cts" => Some(Command::CurrentTrackedSymbols),
-        "dumpstate" => Some(Command::DumpState),
+        "dumpstate" => dump_state(&args),
+        "eval" | "eval-symbols" => eval_symbols(&args),
+        "flush-history-cache" | "fhc" => Some(Command::FlushHistoryCache),
+        "indicator-debug" => indicator_debug(&args),
+        "indicators" => indicators(&args),
+        "inject-clock" => inject_clock(&args),
         "liquidate" => Some(Command::Liquidate),
+        "liquidate-symbol" | "lsym" => liquidate_symbol(&args),
+        "order" => order(&args),
+        "orders" => Some(Command::Orders),
         "pi" | "price-info" => price_info(&args),
         "ps" => portfolio_strategy(&args),
-        "rpo" | "run-pre-open" => Some(Command::RunPreOpen),
+        "queue-repair" | "qr" => queue_repair(&args),
+        "recent-trades" | "trades" => recent_trades(&args),
+        "repair-queue" => Some(Command::ShowRepairQueue),
+        "rpo" | "run-pre-open" => run_pre_open(&args),
         "rr" | "repair-records" => repair_records(&args),
+        "replay-intraday" => replay_intraday(&args),
+        "report" => report(&args),
+        "show-config" | "config" => Some(Command::ShowConfig),
         "status" => Some(Command::Status),
         "stop" | "quit" | "exit" | "q" => Some(Command::Stop),
-        "suo" | "set-utc-offset" => set_utc_offset(&args),
+        "suo" | "set-utc-offset" | "set-timezone" => set_utc_offset(&args),
+        "tail" => tail(&args),
         "tax" => tax(&args),
+        "test-notify" => test_notify(&args),
+        "track-symbols" | "track" => track_symbols(&args),
         "uhist" => update_history(&args),
         "untracked-symbols" | "usym" => Some(Command::UntrackedSymbols),
+        "why-blacklisted" | "wb" => why_blacklisted(&args),
         _ => {
             println!("Unknown command \"{command}\"");
             None
@@ -125,6 +151,227 @@ fn buytoggle(args: &[&str]) -> Option<Command> {
     Some(Command::BuyToggle { allow })
 }
 
+fn check_gaps(args: &[&str]) -> Option<Command> {
+    let symbols = match args.first() {
+        Some(&arg) => arg,
+        None => {
+            println!("Missing argument <symbols>. Usage: check-gaps <symbols>");
+            return None;
+        }
+    };
+
+    parse_symbols(symbols).map(|symbols| Command::CheckGaps { symbols })
+}
+
+fn clear_delisted(args: &[&str]) -> Option<Command> {
+    match args.first() {
+        Some(&symbols) => parse_symbols(symbols).map(|symbols| Command::ClearDelisted { symbols }),
+        None => Some(Command::ClearDelisted {
+            symbols: Vec::new(),
+        }),
+    }
+}
+
+fn eval_symbols(args: &[&str]) -> Option<Command> {
+    let symbols = match args.first() {
+        Some(&arg) => arg,
+        None => {
+            println!("Missing argument <symbols>. Usage: eval-symbols <symbols>");
+            return None;
+        }
+    };
+
+    parse_symbols(symbols).map(|symbols| Command::EvalSymbols { symbols })
+}
+
+fn indicator_debug(args: &[&str]) -> Option<Command> {
+    let (symbol, enable) = match args {
+        [symbol, "on"] => (symbol, true),
+        [symbol, "off"] => (symbol, false),
+        _ => {
+            println!("Usage: indicator-debug <symbol> on/off");
+            return None;
+        }
+    };
+
+    match Symbol::from_str(symbol) {
+        Ok(symbol) => Some(Command::SetIndicatorDebug { symbol, enable }),
+        Err(error) => {
+            println!("Invalid symbol: {error}");
+            None
+        }
+    }
+}
+
+// Labels a state dump with the current local time
+pub(crate) fn timestamp_label() -> String {
+    let now = Config::localize(OffsetDateTime::now_utc());
+    format!(
+        "{}-{:02}{:02}{:02}",
+        now.date(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+fn dump_state(args: &[&str]) -> Option<Command> {
+    let label = match args.first().copied() {
+        None => None,
+        Some("-t" | "--timestamp") => Some(timestamp_label()),
+        Some(label) => {
+            // The label becomes part of a file name
+            if !label
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+            {
+                println!("Labels may only contain letters, digits, '-' and '_'");
+                return None;
+            }
+
+            Some(label.to_owned())
+        }
+    };
+
+    Some(Command::DumpState { label })
+}
+
+fn indicators(args: &[&str]) -> Option<Command> {
+    let (symbol, date) = match args {
+        [symbol, date] => (symbol, date),
+        _ => {
+            println!("Usage: indicators <symbol> <yyyy-mm-dd>");
+            return None;
+        }
+    };
+
+    let symbol = match Symbol::from_str(symbol) {
+        Ok(symbol) => symbol,
+        Err(error) => {
+            println!("Invalid symbol: {error}");
+            return None;
+        }
+    };
+
+    let date = match Date::parse(date, &*DATE_FORMAT) {
+        Ok(date) => date,
+        Err(error) => {
+            println!("Invalid date: {error}");
+            return None;
+        }
+    };
+
+    Some(Command::Indicators { symbol, date })
+}
+
+fn order(args: &[&str]) -> Option<Command> {
+    if !Config::get().debug_commands {
+        println!("Debug commands are disabled. Set debug_commands in the config to enable them");
+        return None;
+    }
+
+    let (side, symbol, amount, options) = match args {
+        [side, symbol, amount, options @ ..] => (*side, *symbol, *amount, options),
+        _ => {
+            println!(
+                "Usage: order <buy|sell> <symbol> <qty|$notional> [market|limit <price>] \
+                 [day|gtc|opg|cls|ioc|fok] [ext]"
+            );
+            return None;
+        }
+    };
+
+    let side = match side {
+        "buy" => OrderSide::Buy,
+        "sell" => OrderSide::Sell,
+        _ => {
+            println!("Expected side to be buy or sell");
+            return None;
+        }
+    };
+
+    let symbol = match Symbol::from_str(symbol) {
+        Ok(symbol) => symbol,
+        Err(error) => {
+            println!("Invalid symbol: {error}");
+            return None;
+        }
+    };
+
+    let amount = match amount.strip_prefix('$') {
+        Some(notional) => notional.parse::<Decimal>().map(OrderAmount::Notional),
+        None => amount.parse::<Decimal>().map(OrderAmount::Qty),
+    };
+    let amount = match amount {
+        Ok(OrderAmount::Qty(amount) | OrderAmount::Notional(amount)) if amount <= Decimal::ZERO => {
+            println!("Order amount must be positive");
+            return None;
+        }
+        Ok(amount) => amount,
+        Err(error) => {
+            println!("Invalid order amount: {error}");
+            return None;
+        }
+    };
+
+    let mut order_type = OrderType::Market;
+    let mut limit_price = None;
+    let mut time_in_force = OrderTimeInForce::Day;
+    let mut extended_hours = false;
+
+    let mut options = options.iter().copied();
+    while let Some(option) = options.next() {
+        match option {
+            "market" => order_type = OrderType::Market,
+            "limit" => {
+                order_type = OrderType::Limit;
+                limit_price = match options.next().map(str::parse::<Decimal>) {
+                    Some(Ok(price)) if price > Decimal::ZERO => Some(price),
+                    Some(Ok(_)) => {
+                        println!("Limit price must be positive");
+                        return None;
+                    }
+                    Some(Err(error)) => {
+                        println!("Invalid limit price: {error}");
+                        return None;
+                    }
+                    None => {
+                        println!("Missing limit price");
+                        return None;
+                    }
+                };
+            }
+            "day" => time_in_force = OrderTimeInForce::Day,
+            "gtc" => time_in_force = OrderTimeInForce::GoodUntilCanceled,
+            "opg" => time_in_force = OrderTimeInForce::MarketOnOpen,
+            "cls" => time_in_force = OrderTimeInForce::MarketOnClose,
+            "ioc" => time_in_force = OrderTimeInForce::ImmediateOrCancel,
+            "fok" => time_in_force = OrderTimeInForce::FillOrKill,
+            "ext" | "extended-hours" => extended_hours = true,
+            _ => {
+                println!("Unknown order option \"{option}\"");
+                return None;
+            }
+        }
+    }
+
+    // Alpaca only accepts extended hours orders as day limit orders
+    if extended_hours && (limit_price.is_none() || time_in_force != OrderTimeInForce::Day) {
+        println!("Extended hours orders must be day limit orders");
+        return None;
+    }
+
+    Some(Command::Order {
+        symbol,
+        side,
+        amount,
+        order_type,
+        limit_price,
+        time_in_force,
+        extended_hours,
+    })
+}
+
 fn price_info(args: &[&str]) -> Option<Command> {
     let symbol = match args.first() {
         Some(&arg) => arg,
@@ -145,6 +392,26 @@ fn price_info(args: &[&str]) -> Option<Command> {
     Some(Command::PriceInfo { symbol })
 }
 
+fn liquidate_symbol(args: &[&str]) -> Option<Command> {
+    let symbol = match args.first() {
+        Some(&arg) => arg,
+        None => {
+            println!("Missing argument <symbol>. Usage: liquidate-symbol <symbol>");
+            return None;
+        }
+    };
+
+    let symbol = match Symbol::from_str(symbol) {
+        Ok(symbol) => symbol,
+        Err(error) => {
+            println!("Invalid symbol: {error}");
+            return None;
+        }
+    };
+
+    Some(Command::LiquidateSymbol { symbol })
+}
+
 fn portfolio_strategy(args: &[&str]) -> Option<Command> {
     let first = match args.first().copied() {
         Some("list") => {
@@ -152,10 +419,15 @@ fn portfolio_strategy(args: &[&str]) -> Option<Command> {
                 PortfolioStrategySubcommand::List,
             ))
         }
+        Some("candidates") => {
+            return Some(Command::PortfolioStrategy(
+                PortfolioStrategySubcommand::Candidates,
+            ))
+        }
         Some(first) => first,
         None => {
             println!(
-                "Expected one of the following sub-commands: list, enable, disable, liquidate"
+                "Expected one of the following sub-commands: list, candidates, enable, disable, liquidate, set-weight"
             );
             return None;
         }
@@ -173,6 +445,24 @@ fn portfolio_strategy(args: &[&str]) -> Option<Command> {
         "enable" => PortfolioStrategySubcommand::Enable { key },
         "disable" => PortfolioStrategySubcommand::Disable { key },
         "liquidate" => PortfolioStrategySubcommand::Liquidate { key },
+        "set-weight" => {
+            let weight = match args.get(2).map(|weight| weight.parse::<Decimal>()) {
+                Some(Ok(weight)) if weight >= Decimal::ZERO => weight,
+                Some(Ok(_)) => {
+                    println!("Weight must be non-negative");
+                    return None;
+                }
+                Some(Err(error)) => {
+                    println!("Invalid weight: {error}");
+                    return None;
+                }
+                None => {
+                    println!("Missing argument <weight>. Usage: ps set-weight <key> <weight>");
+                    return None;
+                }
+            };
+            PortfolioStrategySubcommand::SetWeight { key, weight }
+        }
         _ => {
             println!("Unknown subcommand \"{first}\"");
             return None;
@@ -182,6 +472,34 @@ fn portfolio_strategy(args: &[&str]) -> Option<Command> {
     Some(Command::PortfolioStrategy(subcommand))
 }
 
+fn recent_trades(args: &[&str]) -> Option<Command> {
+    let days = match args.first().map(|days| days.parse::<u32>()) {
+        None => 1,
+        Some(Ok(days)) if days > 0 => days,
+        Some(Ok(_)) => {
+            println!("Number of days must be positive");
+            return None;
+        }
+        Some(Err(error)) => {
+            println!("Invalid number of days: {error}");
+            return None;
+        }
+    };
+
+    Some(Command::RecentTrades { days })
+}
+
+fn run_pre_open(args: &[&str]) -> Option<Command> {
+    match args {
+        [] => Some(Command::RunPreOpen { skip_update: false }),
+        ["--skip-update"] => Some(Command::RunPreOpen { skip_update: true }),
+        _ => {
+            println!("Usage: run-pre-open [--skip-update]");
+            None
+        }
+    }
+}
+
 fn repair_records(args: &[&str]) -> Option<Command> {
     let symbols = match args.first() {
         Some(&arg) => arg,
@@ -191,6 +509,45 @@ fn repair_records(args: &[&str]) -> Option<Command> {
         }
     };
 
+    parse_symbols(symbols).map(|symbols| Command::RepairRecords { symbols })
+}
+
+fn track_symbols(args: &[&str]) -> Option<Command> {
+    let symbols = match args.first() {
+        Some(&arg) => arg,
+        None => {
+            println!("Missing argument <symbols>. Usage: track-symbols <symbols>");
+            return None;
+        }
+    };
+
+    parse_symbols(symbols).map(|symbols| Command::TrackSymbols { symbols })
+}
+
+fn test_notify(args: &[&str]) -> Option<Command> {
+    let message = if args.is_empty() {
+        "Test notification from stonkbot".to_owned()
+    } else {
+        args.join(" ")
+    };
+
+    Some(Command::TestNotify { message })
+}
+
+fn queue_repair(args: &[&str]) -> Option<Command> {
+    let symbols = match args.first() {
+        Some(&arg) => arg,
+        None => {
+            println!("Missing argument <symbols>. Usage: queue-repair <symbols>");
+            return None;
+        }
+    };
+
+    parse_symbols(symbols).map(|symbols| Command::QueueRepair { symbols })
+}
+
+// Parses a comma-separated list of symbols
+fn parse_symbols(symbols: &str) -> Option<Vec<Symbol>> {
     let mut symbols_vec = Vec::new();
     for symbol in symbols.split(',') {
         match Symbol::from_str(symbol) {
@@ -202,36 +559,64 @@ fn repair_records(args: &[&str]) -> Option<Command> {
         }
     }
 
-    Some(Command::RepairRecords {
-        symbols: symbols_vec,
-    })
+    Some(symbols_vec)
+}
+
+fn replay_intraday(args: &[&str]) -> Option<Command> {
+    match args.first() {
+        Some(&path) => Some(Command::ReplayIntraday {
+            path: path.to_owned(),
+        }),
+        None => {
+            println!("Missing argument <path>. Usage: replay-intraday <path>");
+            None
+        }
+    }
+}
+
+fn report(args: &[&str]) -> Option<Command> {
+    match args.first() {
+        Some(&path) => Some(Command::Report {
+            path: path.to_owned(),
+        }),
+        None => {
+            println!("Missing argument <path>. Usage: report <path>");
+            None
+        }
+    }
 }
 
 fn set_utc_offset(args: &[&str]) -> Option<Command> {
     let offset_str = match args.first() {
         Some(&arg) => arg,
         None => {
-            println!("Missing offset argument, required H:M:S offset.");
+            println!("Current UTC offset: {}", Config::get().utc_offset.get());
             return None;
         }
     };
 
-    let mut time_components = offset_str.split(':');
-    let [h, m, s] = array::from_fn(|_| {
-        time_components
-            .next()
-            .and_then(|component| component.parse::<i8>().ok())
-    });
+    // The sign applies to every component, so -05:30 is five and a half hours behind UTC
+    let (negative, unsigned) = match offset_str.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, offset_str.strip_prefix('+').unwrap_or(offset_str)),
+    };
 
-    let (h, m, s) = match (h, m, s) {
-        (Some(h), Some(m), Some(s)) => (h, m, s),
+    let components = unsigned
+        .split(':')
+        .map(|component| component.parse::<i8>().ok().filter(|&value| value >= 0))
+        .collect::<Option<Vec<_>>>();
+    let (h, m, s) = match components.as_deref() {
+        Some(&[h]) => (h, 0, 0),
+        Some(&[h, m]) => (h, m, 0),
+        Some(&[h, m, s]) => (h, m, s),
         _ => {
-            println!("Required offset in the form H:M:S where H, M, and S are signed integers");
+            println!("Required offset in the form [+-]H[:M[:S]], for example -05:00");
             return None;
         }
     };
 
-    let offset = match UtcOffset::from_hms(h, m, s) {
+    let sign = if negative { -1 } else { 1 };
+    let offset = match UtcOffset::from_hms(sign * h, sign * m, sign * s) {
         Ok(offset) => offset,
         Err(error) => {
             println!("Component out of range: {error}");
@@ -239,9 +624,24 @@ fn set_utc_offset(args: &[&str]) -> Option<Command> {
         }
     };
 
-    Config::get().utc_offset.set(offset);
-    println!("Updated UTC offset");
-    None
+    Some(Command::SetTimezone { offset })
+}
+
+fn tail(args: &[&str]) -> Option<Command> {
+    let lines = match args.first().map(|lines| lines.parse::<usize>()) {
+        None => 20,
+        Some(Ok(lines)) if lines > 0 => lines,
+        Some(Ok(_)) => {
+            println!("Number of lines must be positive");
+            return None;
+        }
+        Some(Err(error)) => {
+            println!("Invalid number of lines: {error}");
+            return None;
+        }
+    };
+
+    Some(Command::Tail { lines })
 }
 
 fn tax(args: &[&str]) -> Option<Command> {
@@ -273,6 +673,52 @@ fn tax(args: &[&str]) -> Option<Command> {
     }
 }
 
+fn inject_clock(args: &[&str]) -> Option<Command> {
+    if !Config::get().debug_commands {
+        println!("Debug commands are disabled. Set debug_commands in the config to enable them");
+        return None;
+    }
+
+    // Length of a regular trading session
+    let session = time::Duration::minutes(390);
+    let now = OffsetDateTime::now_utc();
+
+    let event = match args.first().copied() {
+        Some("pre-open") => ClockEvent::PreOpen,
+        Some("open") => ClockEvent::Open {
+            next_close: now + session,
+        },
+        Some("close") => ClockEvent::Close {
+            next_open: now + time::Duration::hours(24) - session,
+        },
+        Some("tick") => {
+            let seconds_until_close = match args.get(1).map(|arg| arg.parse::<u32>()) {
+                Some(Ok(seconds)) => seconds,
+                Some(Err(error)) => {
+                    println!("Invalid number of seconds until close: {error}");
+                    return None;
+                }
+                None => 30 * 60,
+            };
+            let duration_until_close =
+                time::Duration::seconds(i64::from(seconds_until_close)).min(session);
+
+            ClockEvent::Tick {
+                duration_since_open: session - duration_until_close,
+                duration_until_close,
+            }
+        }
+        _ => {
+            println!(
+                "Expected one of pre-open, open, close, tick. Usage: inject-clock <event> [seconds until close]"
+            );
+            return None;
+        }
+    };
+
+    Some(Command::InjectClock { event })
+}
+
 fn update_history(args: &[&str]) -> Option<Command> {
     let max_updates = match args.get(0) {
         Some(&arg) => match arg.parse::<usize>().map(NonZeroUsize::new) {
@@ -291,3 +737,23 @@ fn update_history(args: &[&str]) -> Option<Command> {
 
     Some(Command::UpdateHistory { max_updates })
 }
+
+fn why_blacklisted(args: &[&str]) -> Option<Command> {
+    let symbol = match args.first() {
+        Some(&arg) => arg,
+        None => {
+            println!("Missing argument <symbol>. Usage: why-blacklisted <symbol>");
+            return None;
+        }
+    };
+
+    let symbol = match Symbol::from_str(symbol) {
+        Ok(symbol) => symbol,
+        Err(error) => {
+            println!("Invalid symbol: {error}");
+            return None;
+        }
+    };
+
+    Some(Command::WhyBlacklisted { symbol })
+}
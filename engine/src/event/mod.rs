@@ -5,12 +5,16 @@ pub mod stream;
 use std::{fmt::Debug, marker::PhantomData, num::NonZeroUsize};
 
 use log::warn;
+use rust_decimal::Decimal;
 use serde_json::Value;
 use stock_symbol::Symbol;
-use time::{Duration, OffsetDateTime};
+use time::{Date, Duration, OffsetDateTime, UtcOffset};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-use entity::data::Bar;
+use entity::{
+    data::Bar,
+    trading::{OrderSide, OrderTimeInForce, OrderType},
+};
 
 pub struct EventReceiver {
     rx: UnboundedReceiver<EngineEvent>,
@@ -88,19 +92,162 @@ impl From<StreamEvent> for EngineEvent {
 
 #[derive(Debug)]
 pub enum Command {
-    BuyToggle { allow: bool },
+    ApiStats,
+    Beta,
+    BuyToggle {
+        allow: bool,
+    },
+    CheckDatabase,
+    CheckGaps {
+        symbols: Vec<Symbol>,
+    },
+    /// Clears the given symbols from the delisted set, or the whole set if none are given
+    ClearDelisted {
+        symbols: Vec<Symbol>,
+    },
+    Clock,
+    Correlations,
     CurrentTrackedSymbols,
-    DumpState,
+    DumpState {
+        label: Option<String>,
+    },
+    FlushHistoryCache,
+    EvalSymbols {
+        symbols: Vec<Symbol>,
+    },
+    Indicators {
+        symbol: Symbol,
+        date: Date,
+    },
+    InjectClock {
+        event: ClockEvent,
+    },
     Liquidate,
+    LiquidateSymbol {
+        symbol: Symbol,
+    },
+    /// Submits an order directly, bypassing the strategies and the order manager
+    Order {
+        symbol: Symbol,
+        side: OrderSide,
+        amount: OrderAmount,
+        order_type: OrderType,
+        limit_price: Option<Decimal>,
+        time_in_force: OrderTimeInForce,
+        extended_hours: bool,
+    },
+    Orders,
     PortfolioStrategy(PortfolioStrategySubcommand),
-    PriceInfo { symbol: Symbol },
-    RunPreOpen,
-    RepairRecords { symbols: Vec<Symbol> },
+    PriceInfo {
+        symbol: Symbol,
+    },
+    /// Queues records to be repaired at the next pre-open instead of right away
+    QueueRepair {
+        symbols: Vec<Symbol>,
+    },
+    RecentTrades {
+        days: u32,
+    },
+    RunPreOpen {
+        skip_update: bool,
+    },
+    SetIndicatorDebug {
+        symbol: Symbol,
+        enable: bool,
+    },
+    SetTimezone {
+        offset: UtcOffset,
+    },
+    ShowConfig,
+    ShowRepairQueue,
+    RepairRecords {
+        symbols: Vec<Symbol>,
+    },
+    ReplayIntraday {
+        path: String,
+    },
+    Report {
+        path: String,
+    },
     Status,
     Stop,
+    Tail {
+        lines: usize,
+    },
     Tax(TaxSubcommand),
-    UpdateHistory { max_updates: Option<NonZeroUsize> },
+    TestNotify {
+        message: String,
+    },
+    TrackSymbols {
+        symbols: Vec<Symbol>,
+    },
+    UpdateHistory {
+        max_updates: Option<NonZeroUsize>,
+    },
     UntrackedSymbols,
+    WhyBlacklisted {
+        symbol: Symbol,
+    },
+}
+
+impl Command {
+    /// Whether this command may run while the engine is in safety mode. Only commands which
+    /// inspect state are permitted, along with `DumpState` and `Report`, which write to their own
+    /// files, `TestNotify`, which only sends a test message, and `Liquidate`, `LiquidateSymbol` and
+    /// `Stop`. Anything which changes trading behavior, engine settings, strategy state, local
+    /// history or its cache, or tax records is refused.
+    pub fn is_permitted_in_safety_mode(&self) -> bool {
+        match self {
+            Self::ApiStats
+            | Self::Beta
+            | Self::CheckDatabase
+            | Self::CheckGaps { .. }
+            | Self::Clock
+            | Self::Correlations
+            | Self::CurrentTrackedSymbols
+            | Self::DumpState { .. }
+            | Self::EvalSymbols { .. }
+            | Self::Indicators { .. }
+            | Self::Liquidate
+            | Self::LiquidateSymbol { .. }
+            | Self::Orders
+            | Self::PortfolioStrategy(PortfolioStrategySubcommand::Candidates)
+            | Self::PortfolioStrategy(PortfolioStrategySubcommand::List)
+            | Self::PriceInfo { .. }
+            | Self::RecentTrades { .. }
+            | Self::ReplayIntraday { .. }
+            | Self::Report { .. }
+            | Self::ShowConfig
+            | Self::ShowRepairQueue
+            | Self::Status
+            | Self::Stop
+            | Self::Tail { .. }
+            | Self::Tax(TaxSubcommand::Evaluate { .. })
+            | Self::TestNotify { .. }
+            | Self::UntrackedSymbols
+            | Self::WhyBlacklisted { .. } => true,
+            Self::BuyToggle { .. }
+            | Self::ClearDelisted { .. }
+            | Self::FlushHistoryCache
+            | Self::InjectClock { .. }
+            | Self::Order { .. }
+            | Self::PortfolioStrategy(_)
+            | Self::QueueRepair { .. }
+            | Self::RunPreOpen { .. }
+            | Self::RepairRecords { .. }
+            | Self::SetIndicatorDebug { .. }
+            | Self::SetTimezone { .. }
+            | Self::Tax(TaxSubcommand::Update)
+            | Self::TrackSymbols { .. }
+            | Self::UpdateHistory { .. } => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OrderAmount {
+    Qty(Decimal),
+    Notional(Decimal),
 }
 
 #[derive(Debug)]
@@ -112,12 +259,26 @@ pub enum TaxSubcommand {
 #[derive(Debug)]
 pub enum PortfolioStrategySubcommand {
     List,
-    Enable { key: String },
-    Liquidate { key: String },
-    Disable { key: String },
+    /// Lists each strategy's effective candidates along with their optimal equity fractions.
+    Candidates,
+    Enable {
+        key: String,
+    },
+    Liquidate {
+        key: String,
+    },
+    Disable {
+        key: String,
+    },
+    /// Overrides the strategy's MWU weight. Unless the strategy is disabled, the weight will
+    /// continue to be updated by the MWU learning from this value onward.
+    SetWeight {
+        key: String,
+        weight: Decimal,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum ClockEvent {
     PreOpen,
     Open {
@@ -136,5 +297,5 @@ pub enum ClockEvent {
 #[derive(Debug)]
 pub enum StreamEvent {
     MinuteBar { symbol: Symbol, bar: Bar },
-    Dump { json: Value },
+    Dump { json: Value, label: Option<String> },
 }
@@ -7,7 +7,7 @@ use futures::{
     stream::{SplitSink, SplitStream},
     Future, SinkExt, StreamExt,
 };
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use serde::Serialize;
 use serde_json::Value;
 use std::{
@@ -84,6 +84,9 @@ async fn run_task(
         expected_sub_state: SubscriptionState::new(),
         actual_sub_state: SubscriptionState::new(),
         last_message_recv_time: Instant::now(),
+        url_index: 0,
+        url_failures: 0,
+        connected_at: None,
     };
 
     loop {
@@ -151,10 +154,13 @@ async fn handle_state_discrepancies(
 
     match &mut stream.state {
         StreamState::Opening => {
-            let socket = match connect(&Config::get().urls.alpaca_stream_endpoint).await {
+            let url = stream_url(stream.url_index);
+            info!("Connecting stream to {url}");
+            let socket = match connect(url, &Config::get().urls.alpaca_stream_endpoint).await {
                 Ok(socket) => socket,
                 Err(error) => {
                     warn!("Failed to connect: {error:?}");
+                    stream.record_connection_failure();
                     return;
                 }
             };
@@ -167,6 +173,7 @@ async fn handle_state_discrepancies(
             ));
 
             stream.last_message_recv_time = Instant::now();
+            stream.connected_at = Some(Instant::now());
             stream.state = StreamState::Open {
                 send,
                 pong_pending: false,
@@ -194,14 +201,17 @@ async fn handle_state_discrepancies(
             error!("{message}");
             stream.actual_sub_state.clear();
             stream.state = StreamState::Opening;
+            stream.record_connection_closed();
         }
         StreamState::UnexpectedlyClosed => {
             stream.actual_sub_state.clear();
             stream.state = StreamState::Opening;
+            stream.record_connection_closed();
         }
         StreamState::Closed => {
             stream.expected_sub_state.clear();
             stream.actual_sub_state.clear();
+            stream.connected_at = None;
         }
     }
 }
@@ -218,7 +228,10 @@ async fn handle_request(
                 return;
             }
 
+            // Each session starts over from the primary URL
             stream.state = StreamState::Opening;
+            stream.url_index = 0;
+            stream.url_failures = 0;
         }
         StreamRequest::SubscribeBars(bars) => {
             stream.expected_sub_state.add_bars(bars);
@@ -235,7 +248,7 @@ async fn handle_request(
                 }
             }
         }
-        StreamRequest::DumpState => {
+        StreamRequest::DumpState { label } => {
             let json = match serde_json::to_value(stream) {
                 Ok(json) => json,
                 Err(error) => {
@@ -244,7 +257,7 @@ async fn handle_request(
                 }
             };
 
-            emitter.emit(StreamEvent::Dump { json });
+            emitter.emit(StreamEvent::Dump { json, label });
         }
     }
 }
@@ -323,14 +336,30 @@ async fn check_timeout(stream: &mut Stream) {
     }
 }
 
-async fn connect(endpoint: &str) -> Result<WebSocket, anyhow::Error> {
+/// Connects and authenticates to the stream, then immediately closes the connection.
+pub async fn test_connection() -> anyhow::Result<()> {
+    let urls = &Config::get().urls;
+    let mut socket = connect(&urls.alpaca_stream_url, &urls.alpaca_stream_endpoint).await?;
+    socket.close(None).await?;
+    Ok(())
+}
+
+// The primary stream URL at index 0, followed by the fallbacks
+fn stream_url(index: usize) -> &'static str {
+    let urls = &Config::get().urls;
+    match index {
+        0 => &urls.alpaca_stream_url,
+        _ => &urls.alpaca_stream_fallback_urls[index - 1],
+    }
+}
+
+async fn connect(url: &str, endpoint: &str) -> Result<WebSocket, anyhow::Error> {
     debug!("Connecting stream");
 
     let config = Config::get();
 
     // Open the connection and obtain the socket
-    let socket_response =
-        connect_async(&format!("{}/{endpoint}", config.urls.alpaca_stream_url)).await?;
+    let socket_response = connect_async(&format!("{url}/{endpoint}")).await?;
     let status = socket_response.1.status();
     if !status.is_success() && !status.is_informational() {
         return Err(anyhow!(
@@ -495,6 +524,49 @@ struct Stream {
     actual_sub_state: SubscriptionState,
     #[serde(serialize_with = "serde_black_box")]
     last_message_recv_time: Instant,
+    // Index of the stream URL in use, where 0 is the primary URL
+    url_index: usize,
+    // Consecutive failed or short-lived connections to the current URL
+    url_failures: u32,
+    #[serde(serialize_with = "serde_black_box")]
+    connected_at: Option<Instant>,
+}
+
+impl Stream {
+    fn record_connection_failure(&mut self) {
+        self.url_failures += 1;
+
+        let config = Config::get();
+        let url_count = 1 + config.urls.alpaca_stream_fallback_urls.len();
+        if url_count > 1 && self.url_failures >= config.stream_failover_attempts {
+            let failed_url = stream_url(self.url_index);
+            self.url_index = (self.url_index + 1) % url_count;
+            self.url_failures = 0;
+            warn!(
+                "Stream connection to {failed_url} failed {} times in a row, switching to {}",
+                config.stream_failover_attempts,
+                stream_url(self.url_index)
+            );
+        }
+    }
+
+    // Long-lived connections count as successful and send the next reconnect back to the primary
+    // URL, while short-lived ones count as a failure of the current URL
+    fn record_connection_closed(&mut self) {
+        let stable_duration =
+            Duration::from_secs(60 * Config::get().stream_stable_connection_minutes);
+        match self.connected_at.take() {
+            Some(connected_at) if connected_at.elapsed() >= stable_duration => {
+                self.url_failures = 0;
+                if self.url_index != 0 {
+                    info!("Stream connection was stable, reconnecting to the primary URL");
+                    self.url_index = 0;
+                }
+            }
+            Some(_) => self.record_connection_failure(),
+            None => (),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -535,7 +607,9 @@ pub enum StreamRequest {
     #[allow(dead_code)]
     UnsubscribeBars(Vec<Symbol>),
     Close,
-    DumpState,
+    DumpState {
+        label: Option<String>,
+    },
 }
 
 #[derive(Serialize, Clone)]
@@ -18,6 +18,7 @@ use rustyline::ExternalPrinter;
 use std::io::Write;
 use std::{
     borrow::Cow,
+    collections::VecDeque,
     fmt::{self, Debug, Display, Formatter},
     fs::{read_dir, remove_file, rename, File},
     io::{self, Cursor},
@@ -29,6 +30,9 @@ use time::OffsetDateTime;
 
 const FILE_SIZE_LIMIT: u64 = 50_000_000;
 
+// The most recent formatted log records, oldest first
+static RECENT_RECORDS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
 macro_rules! format_record {
     ($record:expr) => {{
         let record = $record;
@@ -86,7 +90,7 @@ pub fn init_logger<P: ExternalPrinter + Send + 'static>(
         )?;
 
     // Build the log4rs config
-    let config = Config::builder()
+    let mut config = Config::builder()
         .appender(
             Appender::builder()
                 .filter(Box::new(CrateFilter))
@@ -96,19 +100,38 @@ pub fn init_logger<P: ExternalPrinter + Send + 'static>(
             Appender::builder()
                 .filter(Box::new(CrateFilter))
                 .build("log_file", Box::new(log_file)),
-        )
-        .build(
-            Root::builder()
-                .appender("console")
-                .appender("log_file")
-                .build(common::config::Config::get().log_level_filter),
-        )?;
+        );
+    let mut root = Root::builder().appender("console").appender("log_file");
+
+    // Keeps recent records in memory so they can be echoed back by the tail command
+    let log_buffer_lines = common::config::Config::get().log_buffer_lines;
+    if log_buffer_lines > 0 {
+        config = config.appender(Appender::builder().filter(Box::new(CrateFilter)).build(
+            "recent",
+            Box::new(RecentRecordsAppender {
+                capacity: log_buffer_lines,
+            }),
+        ));
+        root = root.appender("recent");
+    }
+
+    let config = config.build(root.build(common::config::Config::get().log_level_filter))?;
 
     log4rs::init_config(config)?;
 
     Ok(())
 }
 
+/// Returns up to the given number of the most recent log records, oldest first.
+pub fn recent_records(lines: usize) -> Vec<String> {
+    let records = RECENT_RECORDS.lock().unwrap();
+    records
+        .iter()
+        .skip(records.len().saturating_sub(lines))
+        .cloned()
+        .collect()
+}
+
 // Called at the end of main, compresses the last log file
 pub fn cleanup() {
     // There's no reason to handle an error here
@@ -184,6 +207,32 @@ impl<P> Debug for CustomConsoleAppender<P> {
     }
 }
 
+#[derive(Debug)]
+struct RecentRecordsAppender {
+    capacity: usize,
+}
+
+impl Append for RecentRecordsAppender {
+    fn append(&self, record: &Record) -> Result<(), anyhow::Error> {
+        let formatted = format_record!(record);
+
+        // Never block the logging thread; if the buffer is being read then the record is dropped
+        let mut records = match RECENT_RECORDS.try_lock() {
+            Ok(records) => records,
+            Err(_) => return Ok(()),
+        };
+
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(formatted);
+
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
 #[derive(Debug)]
 struct CustomLogRoller {
     name_info: Mutex<(u16, u32)>, // current day, log count for today
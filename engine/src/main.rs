@@ -2,6 +2,7 @@ mod engine;
 mod event;
 mod logging;
 mod portfolio;
+mod selftest;
 
 use anyhow::Context;
 use common::config::Config;
@@ -10,15 +11,41 @@ use log::error;
 use rest::AlpacaRestApi;
 use rustyline::history::FileHistory;
 use rustyline::Editor;
+use std::env;
 use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use tokio::{runtime::Builder, task};
 
 fn main() {
+    if env::args().skip(1).any(|arg| arg == "--selftest") {
+        if !selftest::run(profile_arg()) {
+            process::exit(1);
+        }
+        return;
+    }
+
     if let Err(error) = setup_and_launch() {
         println!("{error:?}");
     }
 }
 
+// Accepts either `--profile <name>` or `--profile=<name>`
+fn profile_arg() -> Option<String> {
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            return args.next();
+        }
+
+        if let Some(profile) = arg.strip_prefix("--profile=") {
+            return Some(profile.to_owned());
+        }
+    }
+
+    None
+}
+
 fn setup_and_launch() -> Result<(), anyhow::Error> {
     let (editor, logger_printer) = Editor::<(), FileHistory>::new()
         .and_then(|mut editor| {
@@ -27,7 +54,7 @@ fn setup_and_launch() -> Result<(), anyhow::Error> {
         })
         .context("Failed to setup CLI")?;
 
-    Config::init().context("Failed to initialize config")?;
+    Config::init(profile_arg()).context("Failed to initialize config")?;
 
     logging::init_logger(logger_printer).context("Failed to initialize loggger")?;
 
@@ -1,4 +1,4 @@
-use std::cmp::Reverse;
+use std::{cmp::Reverse, collections::HashMap};
 
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
@@ -6,7 +6,7 @@ use common::{
     config::Config,
     mwu::{mwu_multiplier, Delta},
 };
-use entity::data::Bar;
+use entity::data::{Bar, SymbolMetadata};
 use history::{LocalHistory, Timeframe};
 use log::info;
 use mwu::{RollingWeightedExpert, Weighted};
@@ -57,6 +57,110 @@ pub fn make_long_portfolio() -> anyhow::Result<Vec<Box<dyn LongPortfolioStrategy
     Ok(strategies)
 }
 
+// Filters the market down to the symbols which the market-wide strategies may select from
+fn retain_eligible_symbols(metadata: &mut HashMap<Symbol, SymbolMetadata>, engine: &Engine) {
+    let trading = &Config::get().trading;
+    let mut num_below_minimum_price = 0usize;
+    let mut always_included = Vec::new();
+
+    metadata.retain(|symbol, meta| {
+        if engine.intraday.blacklist.contains_key(symbol)
+            || engine.intraday.recent_loss_sales.contains(symbol)
+        {
+            return false;
+        }
+
+        if trading.always_include.contains(symbol) {
+            always_included.push(*symbol);
+            return true;
+        }
+
+        if (meta.median_volume as u64) < trading.minimum_median_volume {
+            return false;
+        }
+
+        if meta.last_close < trading.minimum_price {
+            num_below_minimum_price += 1;
+            return false;
+        }
+
+        true
+    });
+
+    info!(
+        "Filtered out {num_below_minimum_price} symbols with a last close below {:.2}",
+        trading.minimum_price
+    );
+
+    if !always_included.is_empty() {
+        always_included.sort_unstable();
+        info!(
+            "Always-included symbols bypassed filtering: {}",
+            always_included
+                .iter()
+                .map(Symbol::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+}
+
+pub struct SymbolEvaluation {
+    pub symbol: Symbol,
+    // Number of bars of local history considered
+    pub bars: usize,
+    // Weight the WMWU market top 5 strategy would give the symbol, or None with too little history
+    pub weight: Option<Decimal>,
+    // Mean daily return over the lookback, as a fraction
+    pub mean_return: Option<Decimal>,
+}
+
+/// Scores the given symbols the way the WMWU market top 5 strategy ranks its candidates, without
+/// changing the strategy itself.
+pub async fn evaluate_symbols(
+    engine: &Engine,
+    symbols: &[Symbol],
+) -> anyhow::Result<Vec<SymbolEvaluation>> {
+    let strategy = WmwuMarketTop5::new()?;
+    let timeframe = || Timeframe::DaysBeforeNow((strategy.lookback + 4).max(strategy.min_bars));
+
+    let mut evaluations = Vec::with_capacity(symbols.len());
+    for &symbol in symbols {
+        let bars = engine
+            .local_history
+            .get_symbol_history(symbol, timeframe())
+            .await
+            .with_context(|| format!("Failed to fetch history for {symbol}"))?;
+
+        let weight = if bars.len() >= strategy.min_bars {
+            Some(strategy.compute_weight_and_base(&bars).0)
+        } else {
+            None
+        };
+
+        let returns = bars
+            .windows(2)
+            .rev()
+            .take(strategy.lookback)
+            .map(|window| window[1].close / window[0].close - Decimal::ONE)
+            .collect::<Vec<_>>();
+        let mean_return = if returns.is_empty() {
+            None
+        } else {
+            Some(returns.iter().sum::<Decimal>() / Decimal::from(returns.len()))
+        };
+
+        evaluations.push(SymbolEvaluation {
+            symbol,
+            bars: bars.len(),
+            weight,
+            mean_return,
+        });
+    }
+
+    Ok(evaluations)
+}
+
 #[derive(Serialize)]
 struct MwuDow30 {
     mwu: Mwu,
@@ -210,12 +314,7 @@ impl LongPortfolioStrategy for MwuMarketTop5 {
             .await
             .context("Failed to fetch metadata")?;
 
-        let config = Config::get();
-
-        metadata.retain(|symbol, meta| {
-            meta.median_volume as u64 >= config.trading.minimum_median_volume
-                && !engine.intraday.blacklist.contains(symbol)
-        });
+        retain_eligible_symbols(&mut metadata, engine);
 
         let mut by_performance = metadata.into_iter().collect::<Vec<_>>();
         by_performance.sort_unstable_by_key(|&(_, meta)| Reverse(meta.performance));
@@ -240,6 +339,7 @@ impl LongPortfolioStrategy for MwuMarketTop5 {
 struct WmwuMarketTop5 {
     mwu: Wmwu,
     lookback: usize,
+    min_bars: usize,
 }
 
 impl WmwuMarketTop5 {
@@ -252,6 +352,11 @@ impl WmwuMarketTop5 {
         Ok(Self {
             mwu: Wmwu::new(config.eta),
             lookback: config.lookback,
+            // We need at least two bars to compute a single return
+            min_bars: config
+                .min_bars
+                .max(Config::get().trading.min_candidate_history_days)
+                .max(2),
         })
     }
 
@@ -322,24 +427,28 @@ impl LongPortfolioStrategy for WmwuMarketTop5 {
             .await
             .context("Failed to fetch metadata")?;
 
-        let config = Config::get();
-
-        metadata.retain(|symbol, meta| {
-            meta.median_volume as u64 >= config.trading.minimum_median_volume
-                && !engine.intraday.blacklist.contains(symbol)
-        });
+        retain_eligible_symbols(&mut metadata, engine);
 
         let history = engine
             .local_history
-            .get_market_history(Timeframe::DaysBeforeNow(self.lookback + 4))
+            .get_market_history(Timeframe::DaysBeforeNow(
+                (self.lookback + 4).max(self.min_bars),
+            ))
             .await
             .context("Failed to fetch market history")?;
 
         let mut experts = Vec::new();
+        let mut num_insufficient_history = 0usize;
         for (symbol, meta) in metadata {
+            // Recently listed symbols may not have a full lookback of history, in which case we
+            // weigh them over whatever history is available
             let bars = match history.get(&symbol) {
-                Some(bars) => &**bars,
-                None => return Err(anyhow!("No local history for {symbol}")),
+                Some(bars) if bars.len() >= self.min_bars => &**bars,
+                _ => {
+                    log::debug!("Skipping {symbol}; insufficient local history");
+                    num_insufficient_history += 1;
+                    continue;
+                }
             };
 
             let (weight, weight_base) = self.compute_weight_and_base(bars);
@@ -354,6 +463,11 @@ impl LongPortfolioStrategy for WmwuMarketTop5 {
             ));
         }
 
+        info!(
+            "Excluded {num_insufficient_history} symbols with fewer than {} bars of history",
+            self.min_bars
+        );
+
         experts.sort_unstable_by_key(|(_, meta)| Reverse(meta.weight));
         self.mwu.experts.clear();
         self.mwu.experts.extend(experts.into_iter().take(5));
@@ -375,6 +489,8 @@ impl LongPortfolioStrategy for WmwuMarketTop5 {
 struct WmwuMarketTop5Config {
     eta: Decimal,
     lookback: usize,
+    // Minimum number of bars a symbol needs to be considered
+    min_bars: usize,
 }
 
 impl Default for WmwuMarketTop5Config {
@@ -382,6 +498,7 @@ impl Default for WmwuMarketTop5Config {
         Self {
             eta: Config::get().trading.eta,
             lookback: 300,
+            min_bars: 2,
         }
     }
 }
@@ -205,7 +205,7 @@ where
             .sum::<Decimal>()
     }
 
-    fn latest_weights(&self, data_source: &E::DataSource) -> HashMap<&'_ K, Decimal> {
+    pub fn latest_weights(&self, data_source: &E::DataSource) -> HashMap<&'_ K, Decimal> {
         self.experts
             .iter()
             .map(|(key, we)| {
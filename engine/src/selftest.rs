@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Context};
+use common::config::Config;
+use entity::data::Bar;
+use rest::AlpacaRestApi;
+use std::path::Path;
+use stock_symbol::Symbol;
+use tokio::runtime::Builder;
+
+use crate::event::stream;
+
+// Checks everything the engine needs in order to run, printing a pass/fail line for each. Returns
+// whether every check passed.
+pub fn run(profile: Option<String>) -> bool {
+    // Everything else depends on the config, so there's no point continuing if it's invalid
+    if !report("Config", Config::init(profile)) {
+        return false;
+    }
+
+    let runtime = match Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(error) => return report("Runtime", Err(error.into())),
+    };
+
+    runtime.block_on(run_checks())
+}
+
+async fn run_checks() -> bool {
+    let mut passed = true;
+
+    // This also checks that the account is active
+    match AlpacaRestApi::new().await {
+        Ok(rest) => {
+            passed &= report("REST authentication and account status", Ok(()));
+            passed &= report("Market data access", check_market_data(&rest).await);
+        }
+        Err(error) => {
+            passed &= report("REST authentication and account status", Err(error));
+        }
+    }
+
+    passed &= report("Stream connection", stream::test_connection().await);
+    passed &= report("Local history", check_local_history().await);
+
+    println!("Self-test {}", if passed { "passed" } else { "failed" });
+    passed
+}
+
+async fn check_market_data(rest: &AlpacaRestApi) -> anyhow::Result<()> {
+    let symbol = Symbol::from_str("SPY").map_err(|error| anyhow!("{error}"))?;
+    match rest.latest_bar::<Bar>(symbol).await? {
+        Some(_) => Ok(()),
+        None => Err(anyhow!("No bar returned for {symbol}")),
+    }
+}
+
+// The self-test must not change anything, so a missing database is reported rather than created
+async fn check_local_history() -> anyhow::Result<()> {
+    let database_file = &Config::get().database_file;
+    if !Path::new(database_file).exists() {
+        return Err(anyhow!("Database file {database_file} does not exist"));
+    }
+
+    let symbols = history::count_local_symbols(database_file)
+        .await
+        .context("Failed to read symbols")?;
+    if symbols == 0 {
+        return Err(anyhow!("Database contains no symbols"));
+    }
+
+    Ok(())
+}
+
+fn report(check: &str, result: anyhow::Result<()>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("[PASS] {check}");
+            true
+        }
+        Err(error) => {
+            println!("[FAIL] {check}: {error:?}");
+            false
+        }
+    }
+}
@@ -19,6 +19,20 @@ pub struct Bar {
     pub volume: u64,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Quote {
+    #[serde(rename = "t", with = "rfc3339")]
+    pub time: OffsetDateTime,
+    #[serde(rename = "bp", with = "rust_decimal::serde::float")]
+    pub bid_price: Decimal,
+    #[serde(rename = "bs")]
+    pub bid_size: u64,
+    #[serde(rename = "ap", with = "rust_decimal::serde::float")]
+    pub ask_price: Decimal,
+    #[serde(rename = "as")]
+    pub ask_size: u64,
+}
+
 #[derive(Deserialize, Debug, Clone, Copy)]
 pub struct LossyBar {
     #[serde(rename = "t", with = "rfc3339")]
@@ -49,4 +63,28 @@ pub struct SymbolMetadata {
     pub median_volume: i64,
     pub performance: Decimal,
     pub last_close: Decimal,
+    // Stochastic oscillator %D as of the most recent market day, if it has been computed
+    pub stochastic_d: Option<Decimal>,
+}
+
+// Indicators as stored in the local history for a single symbol on a single market day
+#[derive(Debug, Clone, Copy)]
+pub struct Indicators {
+    pub obv: i64,
+    pub adl: i64,
+    pub di_up: f64,
+    pub di_down: f64,
+    pub dx: f64,
+    pub adx: f64,
+    pub aroon_up: i64,
+    pub aroon_down: i64,
+    pub ema12: f64,
+    pub ema26: f64,
+    pub macd: f64,
+    pub signal_line: f64,
+    pub avg_gain: f64,
+    pub avg_loss: f64,
+    pub rsi: i64,
+    pub stochastic_k: i64,
+    pub stochastic_d: Option<f64>,
 }
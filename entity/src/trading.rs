@@ -39,6 +39,17 @@ pub struct Account {
     pub regt_buying_power: Decimal,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct AccountConfig {
+    #[serde(default)]
+    pub no_shorting: bool,
+    #[serde(default)]
+    pub suspend_trade: bool,
+    #[serde(default)]
+    pub fractional_trading: bool,
+    // We don't need the other fields
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum AccountStatus {
@@ -139,6 +150,18 @@ pub struct Order {
     pub filled_qty: Option<Decimal>,
     #[serde(default)]
     pub filled_avg_price: Option<Decimal>,
+    #[serde(default)]
+    pub time_in_force: Option<OrderTimeInForce>,
+    #[serde(default, rename = "type")]
+    pub order_type: Option<OrderType>,
+    #[serde(default)]
+    pub qty: Option<Decimal>,
+    #[serde(default)]
+    pub notional: Option<Decimal>,
+    #[serde(default)]
+    pub limit_price: Option<Decimal>,
+    #[serde(default)]
+    pub stop_price: Option<Decimal>,
     // We don't need the other fields
 }
 
@@ -171,7 +194,7 @@ impl OrderStatus {
     }
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum OrderSide {
     Buy,
@@ -220,7 +243,7 @@ pub struct StopLoss {
     limit_price: Decimal,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum OrderType {
     Market,
@@ -230,7 +253,7 @@ pub enum OrderType {
     TrailingStop,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum OrderTimeInForce {
     #[serde(rename = "day")]
     Day,
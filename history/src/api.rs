@@ -4,10 +4,10 @@ use std::{
     num::NonZeroUsize,
 };
 use stock_symbol::Symbol;
-use time::OffsetDateTime;
+use time::{Date, OffsetDateTime};
 use tokio::sync::Mutex;
 
-use entity::data::{Bar, SymbolMetadata};
+use entity::data::{Bar, Indicators, SymbolMetadata};
 use rest::AlpacaRestApi;
 
 pub enum Timeframe {
@@ -42,13 +42,78 @@ pub trait LocalHistory: Send + Sync + 'static {
         timeframe: Timeframe,
     ) -> anyhow::Result<Vec<Bar>>;
 
-    async fn get_symbol_avg_span(&self, symbol: Symbol) -> anyhow::Result<f64>;
+    /// Returns the bar for each symbol recorded on the most recent market day in the database.
+    async fn latest_bars(&self) -> anyhow::Result<HashMap<Symbol, Bar>>;
+
+    /// Returns the inclusive ranges of market days within the timeframe for which the symbol has
+    /// no stored bar. Market days are taken to be those on which any symbol has a stored bar, and
+    /// days before the symbol's first stored bar are not considered missing.
+    async fn detect_gaps(
+        &self,
+        symbol: Symbol,
+        timeframe: Timeframe,
+    ) -> anyhow::Result<Vec<(Date, Date)>>;
+
+    /// Returns the indicators stored for the symbol on the given date, or `None` if there are none.
+    async fn get_indicators(
+        &self,
+        symbol: Symbol,
+        date: Date,
+    ) -> anyhow::Result<Option<Indicators>>;
+
+    /// Returns the average span of the given symbol, or `None` if the symbol has no metadata.
+    async fn get_symbol_avg_span(&self, symbol: Symbol) -> anyhow::Result<Option<f64>>;
+
+    /// Returns the average true range of the given symbol over the configured ATR period, or
+    /// `None` if the symbol has fewer than two stored bars.
+    async fn get_symbol_atr(&self, symbol: Symbol) -> anyhow::Result<Option<f64>>;
 
     async fn get_metadata(&self) -> anyhow::Result<HashMap<Symbol, SymbolMetadata>>;
 
-    async fn refresh_connection(&mut self) -> anyhow::Result<()>;
+    /// Reconnects to the database without waiting for queries in progress on the old connection.
+    /// Those queries may fail, but this doesn't require exclusive access to the history.
+    async fn refresh_connection(&self) -> anyhow::Result<()>;
+
+    /// Closes the underlying connections. This should be called before the history is dropped.
+    async fn close(self)
+    where
+        Self: Sized;
+
+    /// Enables or disables logging of the computed indicators for the given symbol whenever the
+    /// history is updated.
+    async fn set_indicator_debug(&self, symbol: Symbol, enable: bool) -> anyhow::Result<()>;
+
+    /// Sets the symbols which are known to be delisted. These are no longer updated, and are not
+    /// considered when judging whether the data for a market day is complete.
+    async fn set_delisted(&self, symbols: HashSet<Symbol>);
+
+    /// Drops any data held in memory so that the next read goes to the database. This is only
+    /// needed if the database was modified externally, since updates and repairs made through this
+    /// trait invalidate the cache themselves.
+    async fn invalidate_cache(&self);
+
+    /// Runs SQLite's integrity check over the database, and gathers the number of rows in each
+    /// table along with the range of dates covered by the stored bars.
+    async fn integrity_check(&self) -> anyhow::Result<DatabaseHealth>;
+}
+
+pub struct DatabaseHealth {
+    /// The messages reported by the integrity check. A healthy database reports a single "ok".
+    pub integrity: Vec<String>,
+    pub row_counts: Vec<(&'static str, i64)>,
+    /// The first and last dates with a stored bar, or `None` if there are no bars.
+    pub date_range: Option<(Date, Date)>,
+}
+
+impl DatabaseHealth {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.integrity.as_slice(), [line] if line == "ok")
+    }
 }
 
+/// Caches the symbol list, the average span and ATR of each symbol and the symbol metadata, which
+/// are read frequently but only change when the history is updated or repaired. Bars and
+/// indicators are always read from the underlying history.
 pub struct Cached<H> {
     history: H,
     cache: Mutex<LocalHistoryCache>,
@@ -57,7 +122,8 @@ pub struct Cached<H> {
 #[derive(Default)]
 struct LocalHistoryCache {
     symbols: Option<HashSet<Symbol>>,
-    spans: HashMap<Symbol, f64>,
+    spans: HashMap<Symbol, Option<f64>>,
+    atrs: HashMap<Symbol, Option<f64>>,
     metadata: Option<HashMap<Symbol, SymbolMetadata>>,
 }
 
@@ -119,7 +185,27 @@ impl<H: LocalHistory> LocalHistory for Cached<H> {
         self.history.get_symbol_history(symbol, timeframe).await
     }
 
-    async fn get_symbol_avg_span(&self, symbol: Symbol) -> anyhow::Result<f64> {
+    async fn latest_bars(&self) -> anyhow::Result<HashMap<Symbol, Bar>> {
+        self.history.latest_bars().await
+    }
+
+    async fn detect_gaps(
+        &self,
+        symbol: Symbol,
+        timeframe: Timeframe,
+    ) -> anyhow::Result<Vec<(Date, Date)>> {
+        self.history.detect_gaps(symbol, timeframe).await
+    }
+
+    async fn get_indicators(
+        &self,
+        symbol: Symbol,
+        date: Date,
+    ) -> anyhow::Result<Option<Indicators>> {
+        self.history.get_indicators(symbol, date).await
+    }
+
+    async fn get_symbol_avg_span(&self, symbol: Symbol) -> anyhow::Result<Option<f64>> {
         let mut cache = self.cache.lock().await;
         match cache.spans.entry(symbol) {
             Entry::Occupied(entry) => Ok(*entry.get()),
@@ -131,6 +217,18 @@ impl<H: LocalHistory> LocalHistory for Cached<H> {
         }
     }
 
+    async fn get_symbol_atr(&self, symbol: Symbol) -> anyhow::Result<Option<f64>> {
+        let mut cache = self.cache.lock().await;
+        match cache.atrs.entry(symbol) {
+            Entry::Occupied(entry) => Ok(*entry.get()),
+            Entry::Vacant(entry) => {
+                let atr = self.history.get_symbol_atr(symbol).await?;
+                entry.insert(atr);
+                Ok(atr)
+            }
+        }
+    }
+
     async fn get_metadata(&self) -> anyhow::Result<HashMap<Symbol, SymbolMetadata>> {
         let mut cache = self.cache.lock().await;
         let ret = if cache.metadata.is_some() {
@@ -143,7 +241,28 @@ impl<H: LocalHistory> LocalHistory for Cached<H> {
         Ok(ret)
     }
 
-    async fn refresh_connection(&mut self) -> anyhow::Result<()> {
+    async fn refresh_connection(&self) -> anyhow::Result<()> {
         self.history.refresh_connection().await
     }
+
+    async fn close(self) {
+        self.history.close().await
+    }
+
+    async fn set_indicator_debug(&self, symbol: Symbol, enable: bool) -> anyhow::Result<()> {
+        self.history.set_indicator_debug(symbol, enable).await
+    }
+
+    async fn set_delisted(&self, symbols: HashSet<Symbol>) {
+        self.history.set_delisted(symbols).await
+    }
+
+    async fn invalidate_cache(&self) {
+        self.invalidate().await;
+        self.history.invalidate_cache().await
+    }
+
+    async fn integrity_check(&self) -> anyhow::Result<DatabaseHealth> {
+        self.history.integrity_check().await
+    }
 }
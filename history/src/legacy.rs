@@ -5,30 +5,47 @@ use std::{
 
 use crate::Timeframe;
 
-use super::LocalHistory;
-use ::entity::data::{Bar, LossyBar, LossySymbolMetadata, SymbolMetadata};
-use anyhow::anyhow;
+use super::{DatabaseHealth, LocalHistory};
+use ::entity::data::{Bar, Indicators, LossyBar, LossySymbolMetadata, SymbolMetadata};
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
-use common::util::{f64_to_decimal, SECONDS_TO_DAYS};
+use common::util::{date_to_pulldate, f64_to_decimal, pulldate_of, pulldate_to_date};
 use common::{
-    config::{Config, IndicatorPeriodConfig},
+    config::{Config, IndicatorPeriodConfig, InterpolatedIndicators},
     mwu::Delta,
 };
-use futures::{executor::block_on, StreamExt};
+use futures::StreamExt;
 use log::{error, info, warn};
 use rest::AlpacaRestApi;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
 use sqlx::{
-    database::HasArguments, query::Query, sqlite::SqlitePool, Error as SqlxError, Row, Sqlite,
+    database::HasArguments,
+    query::Query,
+    sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool},
+    Connection, Error as SqlxError, Row, Sqlite,
+};
+use std::{
+    collections::HashSet,
+    panic,
+    sync::{PoisonError, RwLock},
+    thread,
+    time::Instant,
 };
-use std::collections::HashSet;
 use stock_symbol::Symbol;
 use time::{Date, Duration, OffsetDateTime};
 use tokio::sync::Mutex;
 
+// Upper bound on the number of symbols whose indicators are logged on each update
+const MAX_INDICATOR_DEBUG_SYMBOLS: usize = 10;
+
 pub struct SqliteLocalHistory {
     database_file: String,
-    connection_pool: SqlitePool,
+    // Replaced wholesale when the connection is refreshed. Queries clone the pool out of the lock
+    // rather than holding it, so a refresh never waits on, or is blocked by, work in progress.
+    connection_pool: RwLock<SqlitePool>,
     pulldates: Mutex<Option<Vec<i64>>>,
+    indicator_debug_symbols: Mutex<HashSet<Symbol>>,
+    delisted: Mutex<HashSet<Symbol>>,
 }
 
 impl SqliteLocalHistory {
@@ -56,7 +73,8 @@ impl SqliteLocalHistory {
                 avgGain FLOAT,
                 avgLoss FLOAT,
                 rsi TINYINT,
-                so TINYINT
+                so TINYINT,
+                so_d FLOAT
             );
             CREATE TABLE IF NOT EXISTS CS_Day (
                 symbol varchar(8),
@@ -66,7 +84,8 @@ impl SqliteLocalHistory {
                 low float,
                 close float,
                 volume int(4),
-                changePercent float
+                changePercent float,
+                interpolated TINYINT DEFAULT 0
             );
             CREATE TABLE IF NOT EXISTS CS_Metadata (
                 symbol varchar(8),
@@ -80,17 +99,73 @@ impl SqliteLocalHistory {
         .execute(&mut *conn)
         .await?;
 
+        // Columns added after the tables were first created. Databases created before then need
+        // these columns added to them.
+        let added_columns = [
+            ("CS_Indicators", "so_d", "FLOAT"),
+            ("CS_Day", "interpolated", "TINYINT DEFAULT 0"),
+        ];
+        for (table, column, column_type) in added_columns {
+            let has_column = sqlx::query_as::<_, (i64,)>(&format!(
+                "SELECT COUNT(*) FROM pragma_table_info('{table}') WHERE name = ?"
+            ))
+            .bind(column)
+            .fetch_one(&mut *conn)
+            .await?
+            .0 > 0;
+
+            if !has_column {
+                info!("Adding column {column} to {table}");
+                sqlx::query(&format!(
+                    "ALTER TABLE {table} ADD COLUMN {column} {column_type}"
+                ))
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+
         Ok(SqliteLocalHistory {
             database_file: database_file.to_owned(),
-            connection_pool: pool,
+            connection_pool: RwLock::new(pool),
             pulldates: Mutex::new(None),
+            indicator_debug_symbols: Mutex::new(
+                Config::get()
+                    .indicator_debug_symbols
+                    .iter()
+                    .copied()
+                    .take(MAX_INDICATOR_DEBUG_SYMBOLS)
+                    .collect(),
+            ),
+            delisted: Mutex::new(HashSet::new()),
         })
     }
 
+    // Pools are reference counted, so this is cheap
+    fn pool(&self) -> SqlitePool {
+        self.connection_pool
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Opens the database read-only, so unlike `new` this never creates the file or its tables
+    pub async fn count_symbols_read_only(database_file: &str) -> Result<i64, SqlxError> {
+        let options = database_file
+            .parse::<SqliteConnectOptions>()?
+            .read_only(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        let (count,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(DISTINCT symbol) FROM CS_Day")
+            .fetch_one(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        Ok(count)
+    }
+
     async fn symbols(&self) -> Result<impl Iterator<Item = Symbol>, SqlxError> {
         Ok(
             sqlx::query_as::<_, (Symbol,)>("SELECT DISTINCT symbol FROM CS_Day")
-                .fetch_all(&self.connection_pool)
+                .fetch_all(&self.pool())
                 .await?
                 .into_iter()
                 .map(|symbol_row| symbol_row.0),
@@ -102,10 +177,11 @@ impl SqliteLocalHistory {
         let ret = if cache.is_some() {
             cache.as_ref().unwrap().clone()
         } else {
+            let pool = self.pool();
             let mut pulldates_stream = sqlx::query_as::<_, (i64,)>(
                 "SELECT distinct(pulldate) FROM CS_Day ORDER BY pulldate DESC",
             )
-            .fetch(&self.connection_pool);
+            .fetch(&pool);
             let mut pulldates = Vec::new();
             while let Some((pulldate,)) = pulldates_stream.next().await.transpose()? {
                 pulldates.push(pulldate);
@@ -124,27 +200,30 @@ impl SqliteLocalHistory {
         info!("Fetching most recent market day from local history");
         // Find the last market day and add one to it
         let mut past_market_day = sqlx::query_as::<_, (i64,)>("SELECT MAX(pulldate) FROM CS_Day")
-            .fetch_one(&self.connection_pool)
+            .fetch_one(&self.pool())
             .await?
             .0
             + 1;
-        let today = OffsetDateTime::now_utc().unix_timestamp() / SECONDS_TO_DAYS;
+        let today = pulldate_of(OffsetDateTime::now_utc());
         let config = Config::get();
 
         info!("Fetching latest historical data");
-        let start_date = OffsetDateTime::from_unix_timestamp(past_market_day * SECONDS_TO_DAYS)?;
+        let start_date = pulldate_to_date(past_market_day)?.midnight().assume_utc();
         let history = alpaca_api
             .history::<LossyBar>(self.symbols().await?, start_date, None)
             .await?;
         let num_symbols = history.len();
 
-        let mut history_by_date: HashMap<Date, HashMap<Symbol, LossyBar>> = HashMap::new();
+        let mut history_by_pulldate: HashMap<i64, HashMap<Symbol, LossyBar>> = HashMap::new();
         for (symbol, bars) in history {
             for bar in bars {
-                match history_by_date.entry(bar.time.date()) {
+                // Bucket bars by the pulldate of their trading day, so that the host's timezone
+                // doesn't matter
+                let pulldate = pulldate_of(bar.time);
+                match history_by_pulldate.entry(pulldate) {
                     Entry::Occupied(mut entry) => {
                         if entry.get_mut().insert(symbol, bar).is_some() {
-                            warn!("Got duplicate bar for {symbol} on {}", bar.time.date());
+                            warn!("Got duplicate bar for {symbol} on pulldate {pulldate}");
                         }
                     }
                     Entry::Vacant(entry) => {
@@ -158,17 +237,16 @@ impl SqliteLocalHistory {
 
         let mut num_updates = 0usize;
         while past_market_day < today {
-            // Turn the timestamp into a date object
-            let date = OffsetDateTime::from_unix_timestamp(past_market_day * SECONDS_TO_DAYS)?;
+            let date = pulldate_to_date(past_market_day)?;
 
-            match history_by_date.remove(&date.date()) {
+            match history_by_pulldate.remove(&past_market_day) {
                 Some(bars) => {
                     self.update_history(
                         config,
                         alpaca_api,
                         bars,
-                        &format!("{}", date.date()),
-                        (date.unix_timestamp() / SECONDS_TO_DAYS) as i64,
+                        &format!("{date}"),
+                        past_market_day,
                     )
                     .await?;
                 }
@@ -200,9 +278,14 @@ impl SqliteLocalHistory {
         numeric_date: i64,
     ) -> Result<(), SqlxError> {
         let indicator_periods = &config.indicator_periods;
+        let indicator_debug_symbols = self.indicator_debug_symbols.lock().await.clone();
+        let pool = self.pool();
 
-        // Get the complete list of symbols
+        // Get the complete list of symbols, excluding those which will never receive new data
         let mut symbols = self.symbols().await?.collect::<HashSet<Symbol>>();
+        for symbol in self.delisted.lock().await.iter() {
+            symbols.remove(symbol);
+        }
 
         // The results count appears to be zero on days where the market is closed, however
         // sometimes erroneous data is sent. The size of the market should be larger than
@@ -218,7 +301,7 @@ impl SqliteLocalHistory {
 
         // Get the last market day
         let last_market_day: i64 = sqlx::query_as::<_, (i64,)>("SELECT MAX(pulldate) FROM CS_Day")
-            .fetch_one(&self.connection_pool)
+            .fetch_one(&pool)
             .await?
             .0;
 
@@ -235,7 +318,7 @@ impl SqliteLocalHistory {
             "SELECT DISTINCT pulldate FROM CS_Day ORDER BY pulldate DESC LIMIT ?",
         )
         .bind(max_indicator_period as i64)
-        .fetch_all(&self.connection_pool)
+        .fetch_all(&pool)
         .await?
         .into_iter()
         .map(|pulldate_row| pulldate_row.0)
@@ -259,7 +342,7 @@ impl SqliteLocalHistory {
              pulldate=?",
         )
         .bind(last_market_day as i64)
-        .fetch(&self.connection_pool);
+        .fetch(&pool);
         let mut all_indicator_data: HashMap<Symbol, entity::IndicatorDataInput> =
             HashMap::with_capacity(symbols.len());
         while let Some(row) = indicator_data_stream.next().await.transpose()? {
@@ -274,6 +357,7 @@ impl SqliteLocalHistory {
                     avg_gain: row.try_get("avgGain")?,
                     avg_loss: row.try_get("avgLoss")?,
                     dx_desc: Vec::with_capacity(indicator_periods.adx - 2),
+                    so_desc: Vec::with_capacity(indicator_periods.so_d - 1),
                     period_day_data_desc: Vec::with_capacity(max_indicator_period),
                     metadata: LossySymbolMetadata {
                         average_span: 0.1,
@@ -292,7 +376,7 @@ impl SqliteLocalHistory {
         )
         // This indexing is safe since we check to make sure the pulldate vec is the length we expect earlier
         .bind(pulldates_desc[indicator_periods.adx - 2])
-        .fetch(&self.connection_pool);
+        .fetch(&pool);
         while let Some(result_row) = dx_stream.next().await {
             let row = result_row?;
             match all_indicator_data.get_mut(&row.0) {
@@ -308,12 +392,26 @@ impl SqliteLocalHistory {
         }
         drop(dx_stream);
 
+        // Fill in the previous %K values for the stochastic oscillator %D
+        if indicator_periods.so_d > 1 {
+            let mut so_stream = sqlx::query_as::<_, (Symbol, i64)>(
+                "SELECT symbol,so FROM CS_Indicators WHERE pulldate >= ? ORDER BY pulldate DESC",
+            )
+            .bind(pulldates_desc[indicator_periods.so_d - 2])
+            .fetch(&pool);
+            while let Some((symbol, so)) = so_stream.next().await.transpose()? {
+                if let Some(indicator_data) = all_indicator_data.get_mut(&symbol) {
+                    indicator_data.so_desc.push(so);
+                }
+            }
+        }
+
         let mut period_day_data_stream = sqlx::query(
             "SELECT symbol,high,low,close,volume FROM CS_Day WHERE pulldate >= ? \
              ORDER BY pulldate DESC",
         )
         .bind(pulldates_desc[max_indicator_period - 1])
-        .fetch(&self.connection_pool);
+        .fetch(&pool);
         while let Some(row) = period_day_data_stream.next().await.transpose()? {
             let symbol: Symbol = row.try_get("symbol")?;
             match all_indicator_data.get_mut(&symbol) {
@@ -340,7 +438,7 @@ impl SqliteLocalHistory {
         let mut metadata_stream = sqlx::query(
             "SELECT symbol,avg_span,median_volume,performance,last_close FROM CS_Metadata",
         )
-        .fetch(&self.connection_pool);
+        .fetch(&pool);
         while let Some(row) = metadata_stream.next().await.transpose()? {
             let symbol: Symbol = row.try_get("symbol")?;
             match all_indicator_data.get_mut(&symbol) {
@@ -362,79 +460,117 @@ impl SqliteLocalHistory {
         }
         drop(metadata_stream);
 
-        let mut transaction = self.connection_pool.begin().await?;
-        let mut metadata: HashMap<Symbol, LossySymbolMetadata> = HashMap::new();
-
         // Filter the bars which have valid data and whose symbols are already in the record
-        // Note: all unwraps on bar fields in this loop are safe since the bars are checked by the filter
-        for (symbol, bar) in bars.iter().filter(|&(symbol, _)| symbols.remove(symbol)) {
-            match all_indicator_data.get(symbol) {
+        let mut updates = Vec::with_capacity(bars.len());
+        for (symbol, bar) in bars
+            .into_iter()
+            .filter(|(symbol, _)| symbols.remove(symbol))
+        {
+            // The symbols being updated aren't interpolated below, so their indicator data can be
+            // moved into the update
+            match all_indicator_data.remove(&symbol) {
                 Some(indicator_data) => {
                     if indicator_data.period_day_data_desc.len() < max_indicator_period {
                         error!("Invalid record encountered for symbol {}", symbol);
-                        repair_list.push(symbol.to_owned());
+                        repair_list.push(symbol);
                         continue;
                     }
 
                     let prev_close = indicator_data.period_day_data_desc[0].close;
-                    let close = bar.close;
-                    let change_percent = if prev_close == 0.0 {
-                        0.0
-                    } else {
-                        100.0 * (close - prev_close) / prev_close
-                    };
-
-                    // Insert the day data
-                    let query_result = sqlx::query(
-                        "
-                        INSERT INTO CS_Day \
-                         (symbol,pulldate,open,high,low,close,volume,changePercent)
-                        VALUES (?,?,?,?,?,?,?,?)
-                        ",
-                    )
-                    .bind(symbol.as_str())
-                    .bind(numeric_date)
-                    .bind(bar.open)
-                    .bind(bar.high)
-                    .bind(bar.low)
-                    .bind(close)
-                    .bind(bar.volume as i64)
-                    .bind(change_percent)
-                    .execute(&mut *transaction)
-                    .await;
-
-                    // Check the day data insertion
-                    if let Err(e) = query_result {
-                        error!("Failed to insert day data for {}: {}", symbol, e);
-                        repair_list.push(symbol.to_owned());
-                        continue;
-                    }
-
-                    let (insert_indicators, symbol_meta) = Self::update_indicators_and_metadata(
+                    let (change_percent, performance_multiplier) =
+                        Self::daily_change(prev_close, bar.close);
+                    updates.push((
                         symbol,
-                        indicator_periods,
                         bar,
+                        indicator_data,
                         change_percent,
+                        performance_multiplier,
+                    ));
+                }
+                None => {
+                    error!("Missing record encountered for symbol {}", symbol);
+                    repair_list.push(symbol);
+                }
+            }
+        }
+
+        // The indicator math is CPU-bound and independent per symbol, so compute it up front in
+        // parallel and only do the inserts in the transaction. It runs on the blocking pool so that
+        // the async runtime, which the engine's event loop shares, isn't stalled in the meantime.
+        let threads = match config.history_update_threads {
+            0 => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        };
+        let start = Instant::now();
+        let blocking_periods = indicator_periods.clone();
+        let blocking_debug_symbols = indicator_debug_symbols.clone();
+        let (updates, computed) = tokio::task::spawn_blocking(move || {
+            let computed = parallel_map(
+                &updates,
+                threads,
+                |(symbol, bar, indicator_data, change_percent, performance_multiplier)| {
+                    Self::update_indicators_and_metadata(
+                        *symbol,
+                        &blocking_periods,
+                        bar,
+                        *change_percent,
                         indicator_data,
                         numeric_date,
-                        false,
+                        *performance_multiplier,
+                        blocking_debug_symbols.contains(symbol),
                     )
-                    .await;
+                },
+            );
+            (updates, computed)
+        })
+        .await
+        .unwrap_or_else(|error| panic::resume_unwind(error.into_panic()));
+        info!(
+            "Computed indicators for {} symbols in {:.2?} using {threads} thread(s)",
+            updates.len(),
+            start.elapsed()
+        );
 
-                    // Check the indicator data insertion
-                    if let Err(e) = insert_indicators.execute(&mut *transaction).await {
-                        error!("Failed to insert indicator data for {}: {}", symbol, e);
-                        repair_list.push(symbol.to_owned());
-                        continue;
-                    }
+        let mut transaction = pool.begin().await?;
+        let mut metadata: HashMap<Symbol, LossySymbolMetadata> = HashMap::new();
 
-                    metadata.insert(symbol.to_owned(), symbol_meta);
-                }
-                None => {
-                    error!("Missing record encountered for symbol {}", symbol);
-                    repair_list.push(symbol.to_owned());
-                }
+        for ((symbol, bar, _, change_percent, _), (insert_indicators, symbol_meta)) in
+            updates.into_iter().zip(computed)
+        {
+            // Insert the day data
+            let query_result = sqlx::query(
+                "
+                INSERT INTO CS_Day \
+                 (symbol,pulldate,open,high,low,close,volume,changePercent)
+                VALUES (?,?,?,?,?,?,?,?)
+                ",
+            )
+            .bind(symbol.as_str())
+            .bind(numeric_date)
+            .bind(bar.open)
+            .bind(bar.high)
+            .bind(bar.low)
+            .bind(bar.close)
+            .bind(bar.volume as i64)
+            .bind(change_percent)
+            .execute(&mut *transaction)
+            .await;
+
+            // Check the day data insertion
+            if let Err(e) = query_result {
+                error!("Failed to insert day data for {}: {}", symbol, e);
+                repair_list.push(symbol.to_owned());
+                continue;
+            }
+
+            // Check the indicator data insertion
+            if let Err(e) = insert_indicators.execute(&mut *transaction).await {
+                error!("Failed to insert indicator data for {}: {}", symbol, e);
+                repair_list.push(symbol.to_owned());
+                continue;
             }
+
+            metadata.insert(symbol.to_owned(), symbol_meta);
         }
 
         // Commit the changes
@@ -445,7 +581,7 @@ impl SqliteLocalHistory {
                 "SELECT symbol,open,high,low,close,volume FROM CS_Day WHERE pulldate=?",
             )
             .bind(last_market_day)
-            .fetch(&self.connection_pool);
+            .fetch(&pool);
 
         let mut last_day_data = HashMap::new();
 
@@ -492,8 +628,8 @@ impl SqliteLocalHistory {
                     let query_result = sqlx::query(
                         "
                         INSERT INTO CS_Day \
-                         (symbol,pulldate,open,high,low,close,volume,changePercent)
-                        VALUES (?,?,?,?,?,?,?,?)
+                         (symbol,pulldate,open,high,low,close,volume,changePercent,interpolated)
+                        VALUES (?,?,?,?,?,?,?,?,1)
                         ",
                     )
                     .bind(symbol.as_str())
@@ -504,7 +640,7 @@ impl SqliteLocalHistory {
                     .bind(row.close)
                     .bind(0i64)
                     .bind(0f64)
-                    .execute(&self.connection_pool)
+                    .execute(&pool)
                     .await;
 
                     // Check the day data insertion
@@ -517,6 +653,22 @@ impl SqliteLocalHistory {
                         continue;
                     }
 
+                    if Config::get().interpolated_indicators == InterpolatedIndicators::CarryForward
+                    {
+                        // The metadata is left as it was, so it isn't updated below
+                        if let Err(e) = self
+                            .carry_forward_indicators(symbol.as_str(), numeric_date)
+                            .await
+                        {
+                            error!(
+                                "Failed to store interpolated indicator data for {}: {}",
+                                symbol, e
+                            );
+                            repair_list.push(symbol.to_owned());
+                        }
+                        continue;
+                    }
+
                     // Construct the bar
                     let bar = LossyBar {
                         time: OffsetDateTime::now_utc(),
@@ -529,18 +681,19 @@ impl SqliteLocalHistory {
 
                     // Update the indicators with the interpolated bar
                     let (insert_indicators, symbol_meta) = Self::update_indicators_and_metadata(
-                        symbol,
+                        *symbol,
                         indicator_periods,
                         &bar,
                         0.0,
                         indicator_data,
                         numeric_date,
-                        true,
-                    )
-                    .await;
+                        // Interpolated bars shouldn't affect performance
+                        1.0,
+                        indicator_debug_symbols.contains(symbol),
+                    );
 
                     // Check the indicator insertion
-                    if let Err(e) = insert_indicators.execute(&self.connection_pool).await {
+                    if let Err(e) = insert_indicators.execute(&pool).await {
                         error!(
                             "Failed to store interpolated day data for {}: {}",
                             symbol, e
@@ -569,7 +722,7 @@ impl SqliteLocalHistory {
             .bind(symbol_meta.performance)
             .bind(symbol_meta.last_close)
             .bind(symbol.as_str())
-            .execute(&self.connection_pool)
+            .execute(&pool)
             .await;
 
             if let Err(e) = update_meta_result {
@@ -590,17 +743,71 @@ impl SqliteLocalHistory {
         Ok(())
     }
 
+    // The day's range as a fraction of its low
+    fn span(high: f64, low: f64) -> f64 {
+        if low == 0.0 {
+            0.0
+        } else {
+            ((high - low) / low).abs()
+        }
+    }
+
+    // One step of the average span's EMA with the smoothing factor 2/N. The config requires a period
+    // of at least 2, so the factor stays within (0, 1].
+    fn update_average_span(average_span: f64, span: f64, period: usize) -> f64 {
+        let factor = 2.0 / period as f64;
+        span * factor + average_span * (1.0 - factor)
+    }
+
+    // Returns the percent change from the previous close and the performance multiplier it implies.
+    // If configured, both are computed in Decimal and only converted to f64 for storage.
+    fn daily_change(prev_close: f64, close: f64) -> (f64, f64) {
+        if Config::get().decimal_change_percent {
+            match (f64_to_decimal(prev_close), f64_to_decimal(close)) {
+                (Ok(prev_close), Ok(close)) => {
+                    let change_percent = if prev_close.is_zero() {
+                        Decimal::ZERO
+                    } else {
+                        Decimal::ONE_HUNDRED * (close - prev_close) / prev_close
+                    };
+                    let multiplier = Config::mwu_multiplier(Delta::ChangePercent(change_percent));
+
+                    if let (Some(change_percent), Some(multiplier)) =
+                        (change_percent.to_f64(), multiplier.to_f64())
+                    {
+                        return (change_percent, multiplier);
+                    }
+                }
+                (Err(error), _) | (_, Err(error)) => {
+                    warn!("Falling back to f64 change percent: {error}")
+                }
+            }
+        }
+
+        let change_percent = if prev_close == 0.0 {
+            0.0
+        } else {
+            100.0 * (close - prev_close) / prev_close
+        };
+        (
+            change_percent,
+            Config::mwu_multiplier(Delta::ChangePercent(change_percent)),
+        )
+    }
+
     // Note: this function assumes the day bar provided is complete
-    async fn update_indicators_and_metadata<'a>(
-        symbol: &'a str,
+    #[allow(clippy::too_many_arguments)]
+    fn update_indicators_and_metadata(
+        symbol: Symbol,
         indicator_periods: &IndicatorPeriodConfig,
         day_data: &LossyBar,
         change_percent: f64,
         indicator_data: &entity::IndicatorDataInput,
         numeric_date: i64,
-        override_error: bool,
+        performance_multiplier: f64,
+        log_indicators: bool,
     ) -> (
-        Query<'a, Sqlite, <Sqlite as HasArguments<'a>>::Arguments>,
+        Query<'static, Sqlite, <Sqlite as HasArguments<'static>>::Arguments>,
         LossySymbolMetadata,
     ) {
         // These will be used multiple times during computation
@@ -752,25 +959,36 @@ impl SqliteLocalHistory {
             .max(0)
             .min(100);
 
+        // %D is the simple moving average of %K
+        let so_k_values = indicator_data
+            .so_desc
+            .iter()
+            .take(indicator_periods.so_d - 1)
+            .chain(std::iter::once(&so));
+        let so_d = so_k_values.clone().sum::<i64>() as f64 / so_k_values.count() as f64;
+
+        if log_indicators {
+            info!(
+                "Indicators for {symbol} on pulldate {numeric_date}: OBV: {obv}, ADL: {adl}, \
+                +DI: {diu:.4}, -DI: {did:.4}, DX: {dx:.4}, ADX: {adx:.4}, Aroon up: {aroonu}, \
+                Aroon down: {aroond}, EMA12: {ema12:.4}, EMA26: {ema26:.4}, MACD: {macd:.4}, \
+                signal: {sl:.4}, avg gain: {avg_gain:.4}, avg loss: {avg_loss:.4}, RSI: {rsi}, \
+                %K: {so}, %D: {so_d:.2}"
+            );
+        }
+
         /************/
         /* Metadata */
         /************/
 
-        let mul = if override_error {
-            1.0
-        } else {
-            Config::mwu_multiplier(Delta::ChangePercent(change_percent))
-        };
-        let performance = indicator_data.metadata.performance * mul;
+        let performance =
+            Config::decay_performance(indicator_data.metadata.performance * performance_multiplier);
 
-        let low = day_data.low;
-        let span = if low == 0.0 {
-            0.0
-        } else {
-            (day_data.high - low) / low
-        };
-        let average_span =
-            span.abs() * (2.0 / 30.0) + indicator_data.metadata.average_span * (1.0 - (2.0 / 30.0));
+        let average_span = Self::update_average_span(
+            indicator_data.metadata.average_span,
+            Self::span(day_data.high, day_data.low),
+            indicator_periods.avg_span_period,
+        );
 
         let mut volumes: Vec<i64> = Vec::with_capacity(indicator_periods.obv);
         volumes.push(day_data.volume as i64);
@@ -790,12 +1008,12 @@ impl SqliteLocalHistory {
 
         let insert_indicators = sqlx::query::<Sqlite>(
             "
-            INSERT INTO CS_Indicators (symbol,pulldate,obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,so)
-            VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)
+            INSERT INTO CS_Indicators (symbol,pulldate,obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,so,so_d)
+            VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)
             "
         )
         // Identifiers
-        .bind(symbol).bind(numeric_date)
+        .bind(symbol.as_str().to_owned()).bind(numeric_date)
         // Volume measures
         .bind(obv).bind(adl)
         // ADX components
@@ -807,7 +1025,7 @@ impl SqliteLocalHistory {
         // Relative strength index
         .bind(avg_gain).bind(avg_loss).bind(rsi)
         // Stochastic oscillator
-        .bind(so);
+        .bind(so).bind(so_d);
 
         let symbol_meta = LossySymbolMetadata {
             average_span,
@@ -819,6 +1037,31 @@ impl SqliteLocalHistory {
         (insert_indicators, symbol_meta)
     }
 
+    // Copies the most recent indicators of the symbol to the given day
+    async fn carry_forward_indicators(
+        &self,
+        symbol: &str,
+        numeric_date: i64,
+    ) -> Result<(), SqlxError> {
+        sqlx::query(
+            "
+            INSERT INTO CS_Indicators (symbol,pulldate,obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,so,so_d)
+            SELECT symbol,?,obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,so,so_d
+            FROM CS_Indicators WHERE symbol=? AND pulldate=(
+                SELECT MAX(pulldate) FROM CS_Indicators WHERE symbol=? AND pulldate<?
+            )
+            ",
+        )
+        .bind(numeric_date)
+        .bind(symbol)
+        .bind(symbol)
+        .bind(numeric_date)
+        .execute(&self.pool())
+        .await?;
+
+        Ok(())
+    }
+
     async fn repair_records(
         &self,
         alpaca_api: &AlpacaRestApi,
@@ -828,22 +1071,41 @@ impl SqliteLocalHistory {
         let now = OffsetDateTime::now_utc();
         // About 120 market days
         let start_date = now - Duration::days(5 * 365);
-        let mut history = alpaca_api
-            .history::<LossyBar>(symbols.iter().copied(), start_date, None)
-            .await?;
 
-        for symbol in symbols {
-            let bars = match history.remove(symbol) {
-                Some(bars) => bars,
-                None => {
-                    warn!("Could not repair record for {symbol}; insufficient market data");
+        // Five years of history for a long repair list is a very large request, so it's fetched
+        // and repaired in batches. A batch which fails to fetch doesn't affect the others.
+        let mut repaired = 0;
+        for batch in symbols.chunks(Config::get().repair_batch_size) {
+            let mut history = match alpaca_api
+                .history::<LossyBar>(batch.iter().copied(), start_date, None)
+                .await
+            {
+                Ok(history) => history,
+                Err(error) => {
+                    error!(
+                        "Failed to fetch history for {} symbols: {error:?}",
+                        batch.len()
+                    );
                     continue;
                 }
             };
 
-            if let Err(error) = self.repair_record(*symbol, bars, indicator_periods).await {
-                error!("Failed to repair record for {symbol}: {error:?}");
+            for symbol in batch {
+                let bars = match history.remove(symbol) {
+                    Some(bars) => bars,
+                    None => {
+                        warn!("Could not repair record for {symbol}; insufficient market data");
+                        continue;
+                    }
+                };
+
+                match self.repair_record(*symbol, bars, indicator_periods).await {
+                    Ok(()) => repaired += 1,
+                    Err(error) => error!("Failed to repair record for {symbol}: {error:?}"),
+                }
             }
+
+            info!("Repaired {repaired}/{} records", symbols.len());
         }
 
         Ok(())
@@ -858,15 +1120,15 @@ impl SqliteLocalHistory {
         // Clean out any old stuff
         sqlx::query("DELETE FROM CS_Day WHERE symbol=?")
             .bind(symbol.as_str())
-            .execute(&self.connection_pool)
+            .execute(&self.pool())
             .await?;
         sqlx::query("DELETE FROM CS_Indicators WHERE symbol=?")
             .bind(symbol.as_str())
-            .execute(&self.connection_pool)
+            .execute(&self.pool())
             .await?;
         sqlx::query("DELETE FROM CS_Metadata WHERE symbol=?")
             .bind(symbol.as_str())
-            .execute(&self.connection_pool)
+            .execute(&self.pool())
             .await?;
 
         let lead_time = [
@@ -887,18 +1149,23 @@ impl SqliteLocalHistory {
         }
 
         let mut performance = 1.0;
+        // The same EMA the daily update maintains, seeded with the first bar's span and run over
+        // the whole fetched history rather than only the most recent bars
+        let mut average_span = Self::span(bars[0].high, bars[0].low);
         let indicator_start_index = bars.len() - lead_time;
         for (index, bar) in bars.iter().enumerate().skip(1) {
             let prev_close = bars[index - 1].close;
-            let change_percent = if prev_close == 0.0 {
-                0.0
-            } else {
-                100.0 * (bar.close - prev_close) / prev_close
-            };
-
-            performance *= Config::mwu_multiplier(Delta::ChangePercent(change_percent));
+            let (change_percent, performance_multiplier) =
+                Self::daily_change(prev_close, bar.close);
+
+            performance = Config::decay_performance(performance * performance_multiplier);
+            average_span = Self::update_average_span(
+                average_span,
+                Self::span(bar.high, bar.low),
+                indicator_periods.avg_span_period,
+            );
 
-            let pulldate = bar.time.unix_timestamp() / SECONDS_TO_DAYS;
+            let pulldate = pulldate_of(bar.time);
             sqlx::query(
                 "
                 INSERT INTO CS_Day \
@@ -914,14 +1181,14 @@ impl SqliteLocalHistory {
             .bind(bar.close)
             .bind(bar.volume as i64)
             .bind(change_percent)
-            .execute(&self.connection_pool)
+            .execute(&self.pool())
             .await?;
 
             if index >= indicator_start_index {
                 sqlx::query(
                     "
-                    INSERT INTO CS_Indicators (symbol,pulldate,obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,so)
-                    VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)
+                    INSERT INTO CS_Indicators (symbol,pulldate,obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,so,so_d)
+                    VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)
                     "
                 )
                 // Identifiers
@@ -937,24 +1204,14 @@ impl SqliteLocalHistory {
                 // Relative strength index
                 .bind(0.0f64).bind(0.0f64).bind(50i64)
                 // Stochastic oscillator
-                .bind(50i64)
-                .execute(&self.connection_pool)
+                .bind(50i64).bind(50.0f64)
+                .execute(&self.pool())
                 .await?;
             }
         }
 
         let tail = &bars[bars.len() - indicator_periods.obv..];
-        let mut volumes = Vec::with_capacity(tail.len());
-        let mut span_sum = 0.0;
-        for bar in tail {
-            volumes.push(bar.volume);
-            let span = if bar.low == 0.0 {
-                0.0
-            } else {
-                (bar.high - bar.low) / bar.low
-            };
-            span_sum += span;
-        }
+        let mut volumes = tail.iter().map(|bar| bar.volume).collect::<Vec<_>>();
 
         volumes.sort_unstable();
         let median_volume = volumes[volumes.len() / 2];
@@ -966,11 +1223,11 @@ impl SqliteLocalHistory {
             VALUES (?,?,?,?,?)",
         )
         .bind(symbol.as_str())
-        .bind(span_sum / tail.len() as f64)
+        .bind(average_span)
         .bind(median_volume as i64)
         .bind(performance)
         .bind(last_close)
-        .execute(&self.connection_pool)
+        .execute(&self.pool())
         .await?;
 
         info!("Finished repairing record of {symbol}");
@@ -1016,7 +1273,11 @@ impl SqliteLocalHistory {
 
 impl Drop for SqliteLocalHistory {
     fn drop(&mut self) {
-        block_on(self.connection_pool.close());
+        // Blocking on the pool closing here can deadlock the runtime, so we let the pool clean up
+        // its connections in the background instead
+        if !self.pool().is_closed() {
+            warn!("Local history dropped without being closed");
+        }
     }
 }
 
@@ -1033,6 +1294,7 @@ mod entity {
         pub avg_gain: f64,
         pub avg_loss: f64,
         pub dx_desc: Vec<f64>,
+        pub so_desc: Vec<i64>,
         pub period_day_data_desc: Vec<DayDataInput>,
         pub metadata: LossySymbolMetadata,
     }
@@ -1063,19 +1325,12 @@ mod entity {
 
 impl SqliteLocalHistory {
     async fn timeframe_to_pulldates(&self, timeframe: Timeframe) -> anyhow::Result<(i64, i64)> {
-        // We add 2 here to avoid timezone weirdness. This pulldate should be greater than
-        // any pulldate in the database.
-        let default_end_pulldate = OffsetDateTime::now_utc().unix_timestamp() / SECONDS_TO_DAYS + 2;
+        // Pulldates are trading days in the market timezone, so none can be later than today's
+        let default_end_pulldate = pulldate_of(OffsetDateTime::now_utc());
 
         match timeframe {
-            Timeframe::After(start) => Ok((
-                start.unix_timestamp() / SECONDS_TO_DAYS,
-                default_end_pulldate,
-            )),
-            Timeframe::Within { start, end } => Ok((
-                start.unix_timestamp() / SECONDS_TO_DAYS,
-                end.unix_timestamp() / SECONDS_TO_DAYS,
-            )),
+            Timeframe::After(start) => Ok((pulldate_of(start), default_end_pulldate)),
+            Timeframe::Within { start, end } => Ok((pulldate_of(start), pulldate_of(end))),
             Timeframe::DaysBeforeNow(days) => {
                 let pulldates = self.pulldates().await?;
 
@@ -1096,12 +1351,12 @@ impl SqliteLocalHistory {
         close: f64,
         volume: i64,
     ) -> anyhow::Result<Bar> {
-        let time = OffsetDateTime::from_unix_timestamp(pulldate * SECONDS_TO_DAYS)?;
-        let open = f64_to_decimal(open)?;
-        let high = f64_to_decimal(high)?;
-        let low = f64_to_decimal(low)?;
-        let close = f64_to_decimal(close)?;
-        let volume = u64::try_from(volume)?;
+        let time = pulldate_to_date(pulldate)?.midnight().assume_utc();
+        let open = f64_to_decimal(open).context("Invalid open")?;
+        let high = f64_to_decimal(high).context("Invalid high")?;
+        let low = f64_to_decimal(low).context("Invalid low")?;
+        let close = f64_to_decimal(close).context("Invalid close")?;
+        let volume = u64::try_from(volume).context("Invalid volume")?;
 
         Ok(Bar {
             time,
@@ -1145,6 +1400,7 @@ impl LocalHistory for SqliteLocalHistory {
         let (start_pulldate, end_pulldate) = self.timeframe_to_pulldates(timeframe).await?;
         let estimated_capacity = usize::try_from(end_pulldate - start_pulldate)?;
 
+        let pool = self.pool();
         let mut last_market_day_data_stream =
             sqlx::query_as::<_, (Symbol, i64, f64, f64, f64, f64, i64)>(
                 "SELECT symbol,pulldate,open,high,low,close,volume \
@@ -1153,13 +1409,20 @@ impl LocalHistory for SqliteLocalHistory {
             )
             .bind(start_pulldate)
             .bind(end_pulldate)
-            .fetch(&self.connection_pool);
+            .fetch(&pool);
 
         let mut result = HashMap::<Symbol, Vec<Bar>>::new();
         while let Some((symbol, pulldate, open, high, low, close, volume)) =
             last_market_day_data_stream.next().await.transpose()?
         {
-            let bar = Self::pohlcv_to_bar(pulldate, open, high, low, close, volume)?;
+            // A single corrupt row shouldn't prevent the rest of the market from being read
+            let bar = match Self::pohlcv_to_bar(pulldate, open, high, low, close, volume) {
+                Ok(bar) => bar,
+                Err(error) => {
+                    warn!("Skipping corrupt bar for {symbol} on pulldate {pulldate}: {error:#}");
+                    continue;
+                }
+            };
 
             match result.entry(symbol) {
                 Entry::Occupied(mut entry) => {
@@ -1183,6 +1446,7 @@ impl LocalHistory for SqliteLocalHistory {
     ) -> anyhow::Result<Vec<Bar>> {
         let (start_pulldate, end_pulldate) = self.timeframe_to_pulldates(timeframe).await?;
 
+        let pool = self.pool();
         let mut last_market_day_data_stream = sqlx::query_as::<_, (i64, f64, f64, f64, f64, i64)>(
             "SELECT pulldate,open,high,low,close,volume \
                 FROM CS_Day WHERE pulldate >= ? AND pulldate <= ? AND symbol = ?\
@@ -1191,57 +1455,343 @@ impl LocalHistory for SqliteLocalHistory {
         .bind(start_pulldate)
         .bind(end_pulldate)
         .bind(symbol.as_str())
-        .fetch(&self.connection_pool);
+        .fetch(&pool);
 
         let mut result = Vec::new();
 
         while let Some((pulldate, open, high, low, close, volume)) =
             last_market_day_data_stream.next().await.transpose()?
         {
-            let bar = Self::pohlcv_to_bar(pulldate, open, high, low, close, volume)?;
-            result.push(bar);
+            match Self::pohlcv_to_bar(pulldate, open, high, low, close, volume) {
+                Ok(bar) => result.push(bar),
+                Err(error) => {
+                    warn!("Skipping corrupt bar for {symbol} on pulldate {pulldate}: {error:#}")
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn latest_bars(&self) -> anyhow::Result<HashMap<Symbol, Bar>> {
+        let pool = self.pool();
+        let mut latest_bar_stream = sqlx::query_as::<_, (Symbol, i64, f64, f64, f64, f64, i64)>(
+            "SELECT symbol,pulldate,open,high,low,close,volume FROM CS_Day \
+                WHERE pulldate = (SELECT MAX(pulldate) FROM CS_Day)",
+        )
+        .fetch(&pool);
+
+        let mut result = HashMap::new();
+
+        while let Some((symbol, pulldate, open, high, low, close, volume)) =
+            latest_bar_stream.next().await.transpose()?
+        {
+            match Self::pohlcv_to_bar(pulldate, open, high, low, close, volume) {
+                Ok(bar) => {
+                    result.insert(symbol, bar);
+                }
+                Err(error) => {
+                    warn!("Skipping corrupt bar for {symbol} on pulldate {pulldate}: {error:#}")
+                }
+            }
         }
 
         Ok(result)
     }
 
-    async fn get_symbol_avg_span(&self, symbol: Symbol) -> anyhow::Result<f64> {
+    async fn detect_gaps(
+        &self,
+        symbol: Symbol,
+        timeframe: Timeframe,
+    ) -> anyhow::Result<Vec<(Date, Date)>> {
+        let (start_pulldate, end_pulldate) = self.timeframe_to_pulldates(timeframe).await?;
+
+        let symbol_pulldates = sqlx::query_as::<_, (i64,)>(
+            "SELECT pulldate FROM CS_Day WHERE pulldate >= ? AND pulldate <= ? AND symbol = ?",
+        )
+        .bind(start_pulldate)
+        .bind(end_pulldate)
+        .bind(symbol.as_str())
+        .fetch_all(&self.pool())
+        .await?
+        .into_iter()
+        .map(|(pulldate,)| pulldate)
+        .collect::<HashSet<_>>();
+
+        let first_pulldate = match symbol_pulldates.iter().min() {
+            Some(&pulldate) => pulldate,
+            None => return Err(anyhow!("No stored bars for {symbol} in timeframe")),
+        };
+
+        // Pulldates are cached in descending order
+        let mut market_pulldates = self
+            .pulldates()
+            .await?
+            .into_iter()
+            .filter(|&pulldate| pulldate >= first_pulldate && pulldate <= end_pulldate)
+            .collect::<Vec<_>>();
+        market_pulldates.reverse();
+
+        let mut gaps = Vec::new();
+        let mut current_gap: Option<(i64, i64)> = None;
+
+        for pulldate in market_pulldates {
+            if symbol_pulldates.contains(&pulldate) {
+                if let Some((start, end)) = current_gap.take() {
+                    gaps.push((pulldate_to_date(start)?, pulldate_to_date(end)?));
+                }
+            } else {
+                current_gap = Some(match current_gap {
+                    Some((start, _)) => (start, pulldate),
+                    None => (pulldate, pulldate),
+                });
+            }
+        }
+
+        if let Some((start, end)) = current_gap {
+            gaps.push((pulldate_to_date(start)?, pulldate_to_date(end)?));
+        }
+
+        Ok(gaps)
+    }
+
+    async fn get_indicators(
+        &self,
+        symbol: Symbol,
+        date: Date,
+    ) -> anyhow::Result<Option<Indicators>> {
+        let pulldate = date_to_pulldate(date);
+
+        let row = sqlx::query(
+            "SELECT obv,adl,diu,did,dx,adx,aroonu,aroond,ema12,ema26,macd,sl,avgGain,avgLoss,rsi,\
+            so,so_d FROM CS_Indicators WHERE symbol = ? AND pulldate = ?",
+        )
+        .bind(symbol.as_str())
+        .bind(pulldate)
+        .fetch_optional(&self.pool())
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Indicators {
+            obv: row.try_get("obv")?,
+            adl: row.try_get("adl")?,
+            di_up: row.try_get("diu")?,
+            di_down: row.try_get("did")?,
+            dx: row.try_get("dx")?,
+            adx: row.try_get("adx")?,
+            aroon_up: row.try_get("aroonu")?,
+            aroon_down: row.try_get("aroond")?,
+            ema12: row.try_get("ema12")?,
+            ema26: row.try_get("ema26")?,
+            macd: row.try_get("macd")?,
+            signal_line: row.try_get("sl")?,
+            avg_gain: row.try_get("avgGain")?,
+            avg_loss: row.try_get("avgLoss")?,
+            rsi: row.try_get("rsi")?,
+            stochastic_k: row.try_get("so")?,
+            stochastic_d: row.try_get("so_d")?,
+        }))
+    }
+
+    async fn get_symbol_avg_span(&self, symbol: Symbol) -> anyhow::Result<Option<f64>> {
         sqlx::query_as::<_, (f64,)>("SELECT avg_span FROM CS_Metadata WHERE symbol = ?")
             .bind(symbol.as_str())
-            .fetch_one(&self.connection_pool)
+            .fetch_optional(&self.pool())
             .await
-            .map(|(span,)| span)
+            .map(|row| row.map(|(span,)| span))
             .map_err(Into::into)
     }
 
+    async fn get_symbol_atr(&self, symbol: Symbol) -> anyhow::Result<Option<f64>> {
+        let period = Config::get().trading.atr_period;
+        let mut bars = sqlx::query_as::<_, (f64, f64, f64)>(
+            "SELECT high,low,close FROM CS_Day WHERE symbol = ? ORDER BY pulldate DESC LIMIT ?",
+        )
+        .bind(symbol.as_str())
+        .bind(i64::try_from(period + 1)?)
+        .fetch_all(&self.pool())
+        .await?;
+
+        if bars.len() < 2 {
+            return Ok(None);
+        }
+
+        // Simple average of the true range, which needs the previous close
+        bars.reverse();
+        let true_range_sum = bars
+            .windows(2)
+            .map(|window| {
+                let (_, _, prev_close) = window[0];
+                let (high, low, _) = window[1];
+                (high - low)
+                    .max((high - prev_close).abs())
+                    .max((low - prev_close).abs())
+            })
+            .sum::<f64>();
+
+        Ok(Some(true_range_sum / (bars.len() - 1) as f64))
+    }
+
     async fn get_metadata(&self) -> anyhow::Result<HashMap<Symbol, SymbolMetadata>> {
-        let mut meta_iter = sqlx::query_as::<_, (Symbol, f64, i64, f64, f64)>(
-            "SELECT symbol,avg_span,median_volume,performance,last_close FROM CS_Metadata",
+        let pool = self.pool();
+        let mut meta_iter = sqlx::query_as::<_, (Symbol, f64, i64, f64, f64, Option<f64>)>(
+            "SELECT m.symbol,m.avg_span,m.median_volume,m.performance,m.last_close,i.so_d \
+            FROM CS_Metadata m LEFT JOIN CS_Indicators i ON i.symbol = m.symbol \
+            AND i.pulldate = (SELECT MAX(pulldate) FROM CS_Indicators)",
         )
-        .fetch(&self.connection_pool);
+        .fetch(&pool);
 
-        let mut meta = HashMap::new();
+        let mut rows = Vec::new();
+        while let Some(row) = meta_iter.next().await.transpose()? {
+            rows.push(row);
+        }
+
+        // The stored performance is the raw running product, and the normalized view is derived
+        // from it here so that repeated updates don't normalize already normalized values
+        let mut performances = rows.iter().map(|row| row.3).collect::<Vec<_>>();
+        Config::get()
+            .performance_normalization
+            .normalize(&mut performances);
 
-        while let Some((symbol, average_span, median_volume, performance, last_close)) =
-            meta_iter.next().await.transpose()?
+        let mut meta = HashMap::new();
+        for ((symbol, average_span, median_volume, _, last_close, so_d), performance) in
+            rows.into_iter().zip(performances)
         {
-            meta.insert(
-                symbol,
-                SymbolMetadata {
-                    average_span: f64_to_decimal(average_span)?,
+            let symbol_meta = (|| -> anyhow::Result<SymbolMetadata> {
+                Ok(SymbolMetadata {
+                    average_span: f64_to_decimal(average_span).context("Invalid avg_span")?,
                     median_volume,
-                    performance: f64_to_decimal(performance)?,
-                    last_close: f64_to_decimal(last_close)?,
-                },
-            );
+                    performance: f64_to_decimal(performance).context("Invalid performance")?,
+                    last_close: f64_to_decimal(last_close).context("Invalid last_close")?,
+                    stochastic_d: so_d
+                        .map(f64_to_decimal)
+                        .transpose()
+                        .context("Invalid so_d")?,
+                })
+            })();
+
+            match symbol_meta {
+                Ok(symbol_meta) => {
+                    meta.insert(symbol, symbol_meta);
+                }
+                Err(error) => warn!("Skipping corrupt metadata for {symbol}: {error:#}"),
+            }
         }
 
         Ok(meta)
     }
 
-    async fn refresh_connection(&mut self) -> anyhow::Result<()> {
-        self.connection_pool.close().await;
-        self.connection_pool = SqlitePool::connect(&self.database_file).await?;
+    // The old pool is dropped rather than closed, since closing waits for every connection to be
+    // returned. Updates still in flight keep their own handle to it, so its connections are only
+    // closed once they finish, and they may fail if the old connection is what went bad.
+    async fn refresh_connection(&self) -> anyhow::Result<()> {
+        let pool = SqlitePool::connect(&self.database_file).await?;
+        *self
+            .connection_pool
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = pool;
+        Ok(())
+    }
+
+    async fn close(self) {
+        self.pool().close().await;
+    }
+
+    async fn set_indicator_debug(&self, symbol: Symbol, enable: bool) -> anyhow::Result<()> {
+        let mut symbols = self.indicator_debug_symbols.lock().await;
+
+        if !enable {
+            symbols.remove(&symbol);
+            return Ok(());
+        }
+
+        if symbols.len() >= MAX_INDICATOR_DEBUG_SYMBOLS && !symbols.contains(&symbol) {
+            return Err(anyhow!(
+                "At most {MAX_INDICATOR_DEBUG_SYMBOLS} symbols may have indicator logging enabled"
+            ));
+        }
+
+        symbols.insert(symbol);
         Ok(())
     }
+
+    async fn set_delisted(&self, symbols: HashSet<Symbol>) {
+        *self.delisted.lock().await = symbols;
+    }
+
+    // The market days present in the database are the only thing cached at this layer
+    async fn invalidate_cache(&self) {
+        *self.pulldates.lock().await = None;
+    }
+
+    async fn integrity_check(&self) -> anyhow::Result<DatabaseHealth> {
+        let integrity = sqlx::query_as::<_, (String,)>("PRAGMA integrity_check")
+            .fetch_all(&self.pool())
+            .await
+            .context("Failed to run integrity check")?
+            .into_iter()
+            .map(|(line,)| line)
+            .collect();
+
+        let tables = ["CS_Day", "CS_Indicators", "CS_Metadata"];
+        let mut row_counts = Vec::with_capacity(tables.len());
+        for table in tables {
+            let (count,) = sqlx::query_as::<_, (i64,)>(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(&self.pool())
+                .await
+                .with_context(|| format!("Failed to count rows in {table}"))?;
+            row_counts.push((table, count));
+        }
+
+        let pulldate_range = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(
+            "SELECT MIN(pulldate), MAX(pulldate) FROM CS_Day",
+        )
+        .fetch_one(&self.pool())
+        .await
+        .context("Failed to fetch stored date range")?;
+
+        let date_range = match pulldate_range {
+            (Some(first), Some(last)) => Some((pulldate_to_date(first)?, pulldate_to_date(last)?)),
+            _ => None,
+        };
+
+        Ok(DatabaseHealth {
+            integrity,
+            row_counts,
+            date_range,
+        })
+    }
+}
+
+// Maps the items on up to the given number of threads, preserving their order
+fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if threads <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let f = &f;
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|payload| panic::resume_unwind(payload))
+            })
+            .collect()
+    })
 }
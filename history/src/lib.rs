@@ -3,11 +3,31 @@ mod legacy;
 
 pub use api::*;
 
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use log::info;
+
 pub type LocalHistoryImpl = Cached<legacy::SqliteLocalHistory>;
 
-pub async fn init_local_history() -> anyhow::Result<LocalHistoryImpl> {
-    legacy::SqliteLocalHistory::new("./market-data.db")
+pub async fn init_local_history(database_file: &str) -> anyhow::Result<LocalHistoryImpl> {
+    if let Some(parent) = Path::new(database_file).parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+    }
+
+    info!("Opening market data database at {database_file}");
+    legacy::SqliteLocalHistory::new(database_file)
         .await
         .map(Cached::new)
         .map_err(Into::into)
 }
+
+/// Counts the symbols in the local history without creating or migrating the database.
+pub async fn count_local_symbols(database_file: &str) -> anyhow::Result<i64> {
+    legacy::SqliteLocalHistory::count_symbols_read_only(database_file)
+        .await
+        .map_err(Into::into)
+}
@@ -1,15 +1,21 @@
 mod rate_limit;
+mod stats;
 
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use common::config::{ApiKeys, Config, Urls};
+use entity::data::Quote;
 use entity::trading::*;
 use rate_limit::RateLimiter;
-use reqwest::{Client, Method, RequestBuilder};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
 use rust_decimal::Decimal;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
@@ -21,6 +27,8 @@ use time::Duration;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+pub use stats::{EndpointStats, RequestStats};
+
 const KEY_ID_HEADER: &str = "APCA-API-KEY-ID";
 const SECRET_KEY_HEADER: &str = "APCA-API-SECRET-KEY";
 
@@ -30,6 +38,7 @@ pub struct AlpacaRestApi {
     keys: &'static ApiKeys,
     urls: &'static Urls,
     rate_limiter: Arc<RateLimiter>,
+    stats: Arc<RequestStats>,
 }
 
 impl AlpacaRestApi {
@@ -45,6 +54,7 @@ impl AlpacaRestApi {
                 config.request_rate_limit,
                 config.minimum_request_rate,
             )),
+            stats: Arc::new(RequestStats::default()),
         };
 
         let account = me
@@ -62,6 +72,10 @@ impl AlpacaRestApi {
         Ok(me)
     }
 
+    pub fn request_stats(&self) -> &RequestStats {
+        &self.stats
+    }
+
     fn trading_endpoint(&self, method: Method, endpoint: &str) -> RequestBuilder {
         self.client
             .request(method, format!("{}{endpoint}", self.urls.alpaca_api_base))
@@ -76,9 +90,69 @@ impl AlpacaRestApi {
             .header(SECRET_KEY_HEADER, &self.keys.alpaca_secret_key)
     }
 
-    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+    // Sends the request once the rate limiter allows it, and records the rate limit reported by
+    // Alpaca in the response along with the request stats
+    async fn execute(&self, request: RequestBuilder) -> anyhow::Result<Response> {
+        let (client, request) = request.build_split();
+        let request = request?;
+        let path = request.url().path().to_owned();
+
         self.rate_limiter.throttle_request().await;
-        let text = request.send().await?.text().await?;
+        let start = Instant::now();
+        let result = client.execute(request).await;
+        self.stats.record(
+            &path,
+            result.as_ref().ok().map(Response::status),
+            start.elapsed(),
+        );
+        let response = result?;
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        if let (Some(remaining), Some(reset)) =
+            (header("X-RateLimit-Remaining"), header("X-RateLimit-Reset"))
+        {
+            log::trace!("Rate limit: {remaining} requests remaining, resets at {reset}");
+            if Config::get().adaptive_rate_limit {
+                self.rate_limiter.record_remaining(remaining, reset);
+            }
+        }
+
+        Ok(response)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        let text = self.execute(request).await?.text().await?;
+        let res = serde_json::from_str(&text)
+            .context("Failed to parse response")
+            .map_err(Into::into);
+        if res.is_err() {
+            log::debug!("{text}");
+        }
+        res
+    }
+
+    // Like `send`, but the raw response is first written to the bar audit file if one is
+    // configured
+    async fn send_bars<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> anyhow::Result<T> {
+        let text = self.execute(request).await?.text().await?;
+
+        if let Some(dir) = &Config::get().bar_audit_dir {
+            if let Err(error) = audit_bars(dir, endpoint, params, &text) {
+                log::warn!("Failed to write bar audit record: {error:?}");
+            }
+        }
+
         let res = serde_json::from_str(&text)
             .context("Failed to parse response")
             .map_err(Into::into);
@@ -93,6 +167,55 @@ impl AlpacaRestApi {
             .await
     }
 
+    pub async fn account_config(&self) -> anyhow::Result<AccountConfig> {
+        self.send(self.trading_endpoint(Method::GET, "/account/configurations"))
+            .await
+    }
+
+    pub async fn set_fractional_trading(&self, enabled: bool) -> anyhow::Result<AccountConfig> {
+        self.send(
+            self.trading_endpoint(Method::PATCH, "/account/configurations")
+                .body(
+                    serde_json::json!({ "fractional_trading": enabled })
+                        .to_string()
+                        .into_bytes(),
+                ),
+        )
+        .await
+    }
+
+    /// Posts the message to the configured notification webhook. This bypasses the rate limiter
+    /// since the webhook isn't an Alpaca endpoint.
+    pub async fn notify(&self, message: &str) -> anyhow::Result<()> {
+        let url = Config::get()
+            .notify_webhook_url
+            .as_deref()
+            .ok_or_else(|| anyhow!("No notification webhook is configured"))?;
+
+        let response = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(
+                serde_json::json!({ "text": message })
+                    .to_string()
+                    .into_bytes(),
+            )
+            .send()
+            .await
+            .context("Failed to reach notification webhook")?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Notification webhook responded with {status}: {}",
+                response.text().await?
+            ))
+        }
+    }
+
     pub async fn clock(&self) -> anyhow::Result<Clock> {
         self.send(self.trading_endpoint(Method::GET, "/clock"))
             .await
@@ -106,6 +229,22 @@ impl AlpacaRestApi {
         .await
     }
 
+    /// Fetches the asset for the given symbol, or `None` if Alpaca no longer knows of it.
+    pub async fn asset(&self, symbol: Symbol) -> anyhow::Result<Option<Equity>> {
+        let response = self
+            .execute(self.trading_endpoint(Method::GET, &format!("/assets/{symbol}")))
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let text = response.text().await?;
+        serde_json::from_str(&text)
+            .map(Some)
+            .with_context(|| format!("Failed to parse response: {text}"))
+    }
+
     pub async fn positions(&self) -> anyhow::Result<Vec<Position>> {
         self.send(self.trading_endpoint(Method::GET, "/positions"))
             .await
@@ -128,19 +267,45 @@ impl AlpacaRestApi {
             .await
     }
 
-    pub async fn liquidate_position(&self, symbol: Symbol) -> anyhow::Result<Order> {
-        self.send(self.trading_endpoint(Method::DELETE, &format!("/positions/{symbol}")))
+    /// Closes the entire position. Returns `None` if Alpaca rejects the request because the
+    /// quantity to sell no longer matches the position.
+    pub async fn liquidate_position(&self, symbol: Symbol) -> anyhow::Result<Option<Order>> {
+        self.close_position(self.trading_endpoint(Method::DELETE, &format!("/positions/{symbol}")))
             .await
     }
 
-    pub async fn sell_position(&self, symbol: Symbol, qty: Decimal) -> anyhow::Result<Order> {
-        self.send(
+    /// Sells `qty` shares of the position. Returns `None` if Alpaca rejects the request because the
+    /// quantity to sell no longer matches the position. The quantity is sent as given, so it should
+    /// already be rounded to the precision the asset accepts.
+    pub async fn sell_position(
+        &self,
+        symbol: Symbol,
+        qty: Decimal,
+    ) -> anyhow::Result<Option<Order>> {
+        self.close_position(
             self.trading_endpoint(Method::DELETE, &format!("/positions/{symbol}"))
-                .query(&[("qty", qty.round_dp(9))]),
+                .query(&[("qty", qty)]),
         )
         .await
     }
 
+    // Alpaca responds with 422 when the position changed between fetching it and closing it, such
+    // as after a partial fill elsewhere
+    async fn close_position(&self, request: RequestBuilder) -> anyhow::Result<Option<Order>> {
+        let response = self.execute(request).await?;
+        let status = response.status();
+        let text = response.text().await?;
+
+        if status == StatusCode::UNPROCESSABLE_ENTITY {
+            log::warn!("Request to close position was rejected: {text}");
+            return Ok(None);
+        }
+
+        serde_json::from_str(&text)
+            .map(Some)
+            .with_context(|| format!("Failed to parse response: {text}"))
+    }
+
     pub async fn submit_order(&self, order: &OrderRequest) -> anyhow::Result<Order> {
         self.send(
             self.trading_endpoint(Method::POST, "/orders")
@@ -154,6 +319,23 @@ impl AlpacaRestApi {
             .await
     }
 
+    pub async fn cancel_order(&self, id: Uuid) -> anyhow::Result<()> {
+        let response = self
+            .execute(self.trading_endpoint(Method::DELETE, &format!("/orders/{}", id.hyphenated())))
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Failed to cancel order {} ({status}): {}",
+                id.hyphenated(),
+                response.text().await?
+            ))
+        }
+    }
+
     pub async fn get_orders(
         &self,
         status: RequestOrderStatus,
@@ -180,6 +362,27 @@ impl AlpacaRestApi {
         .await
     }
 
+    /// Fetches the most recent minute bar for the given symbol.
+    pub async fn latest_bar<B: DeserializeOwned>(
+        &self,
+        symbol: Symbol,
+    ) -> anyhow::Result<Option<B>> {
+        self.send::<LatestBarResponse<B>>(
+            self.data_endpoint(&format!("/stocks/{symbol}/bars/latest")),
+        )
+        .await
+        .map(|response| response.bar)
+    }
+
+    /// Fetches the most recent quote for the given symbol.
+    pub async fn latest_quote(&self, symbol: Symbol) -> anyhow::Result<Option<Quote>> {
+        self.send::<LatestQuoteResponse>(
+            self.data_endpoint(&format!("/stocks/{symbol}/quotes/latest")),
+        )
+        .await
+        .map(|response| response.quote)
+    }
+
     pub async fn day_bar<B: DeserializeOwned>(
         &self,
         stock: Symbol,
@@ -187,15 +390,18 @@ impl AlpacaRestApi {
     ) -> Result<Option<B>, anyhow::Error> {
         let start_date = date.format(&Rfc3339)?;
         let end_date = (date + Duration::days(1)).format(&Rfc3339)?;
+        let endpoint = format!("/stocks/{}/bars", stock);
+        let params = [
+            ("start", start_date.as_str()),
+            ("end", &end_date),
+            ("limit", "1"),
+            ("timeframe", "1Day"),
+        ];
         let mut response = self
-            .send::<AlpacaBarsResponse<B>>(
-                self.data_endpoint(&format!("/stocks/{}/bars", stock))
-                    .query(&[
-                        ("start", start_date.as_str()),
-                        ("end", &end_date),
-                        ("limit", "1"),
-                        ("timeframe", "1Day"),
-                    ]),
+            .send_bars::<AlpacaBarsResponse<B>>(
+                self.data_endpoint(&endpoint).query(&params),
+                &endpoint,
+                &params,
             )
             .await?;
 
@@ -215,48 +421,65 @@ impl AlpacaRestApi {
 
     pub async fn history<B: DeserializeOwned>(
         &self,
-        mut symbols: impl Iterator<Item = Symbol>,
+        symbols: impl Iterator<Item = Symbol>,
         start: OffsetDateTime,
         end: Option<OffsetDateTime>,
     ) -> anyhow::Result<HashMap<Symbol, Vec<B>>> {
-        let first = match symbols.next() {
-            Some(symbol) => symbol,
-            None => return Ok(HashMap::new()),
-        };
-
-        let symbols_string = symbols.fold(first.as_str().to_owned(), |mut string, symbol| {
-            string.push(',');
-            string.push_str(&symbol);
-            string
-        });
-
+        let symbols = symbols.collect::<Vec<_>>();
         let start_date = start.format(&Rfc3339)?;
         let end_date = end.map(|end| end.format(&Rfc3339)).transpose()?;
 
         let mut agg_history = HashMap::<Symbol, Vec<B>>::new();
+
+        // Requesting every symbol at once can exceed URL length limits, so split them into batches
+        for batch in symbols.chunks(Config::get().history_batch_size) {
+            let symbols_string = batch
+                .iter()
+                .map(|symbol| symbol.as_str())
+                .collect::<Vec<_>>()
+                .join(",");
+
+            self.history_batch(
+                &symbols_string,
+                &start_date,
+                end_date.as_deref(),
+                &mut agg_history,
+            )
+            .await?;
+        }
+
+        Ok(agg_history)
+    }
+
+    async fn history_batch<B: DeserializeOwned>(
+        &self,
+        symbols_string: &str,
+        start_date: &str,
+        end_date: Option<&str>,
+        agg_history: &mut HashMap<Symbol, Vec<B>>,
+    ) -> anyhow::Result<()> {
         let mut next_page_token = None;
 
         loop {
-            let request = self.data_endpoint("/stocks/bars").query(&[
-                ("symbols", &*symbols_string),
+            let mut params = vec![
+                ("symbols", symbols_string),
                 ("timeframe", "1Day"),
                 ("limit", "10000"),
-                ("start", &*start_date),
-            ]);
+                ("start", start_date),
+            ];
 
-            let request = if let Some(end) = &end_date {
-                request.query(&[("end", end)])
-            } else {
-                request
-            };
+            if let Some(end) = end_date {
+                params.push(("end", end));
+            }
 
-            let request = if let Some(page_token) = &next_page_token {
-                request.query(&[("page_token", page_token)])
-            } else {
-                request
-            };
+            if let Some(page_token) = &next_page_token {
+                params.push(("page_token", page_token.as_str()));
+            }
 
-            let response: History<B> = self.send(request).await?;
+            // Pages aren't spaced out here; every request already goes through the rate limiter,
+            // which only delays when the request budget runs low
+            let request = self.data_endpoint("/stocks/bars").query(&params);
+            let response: History<B> = self.send_bars(request, "/stocks/bars", &params).await?;
 
             for (symbol, bars) in response.bars {
                 match agg_history.entry(symbol) {
@@ -273,10 +496,38 @@ impl AlpacaRestApi {
             }
         }
 
-        Ok(agg_history)
+        Ok(())
     }
 }
 
+// Appends the raw response to today's audit file as a single JSON line. The response is kept as a
+// string so that it's recorded exactly as received.
+fn audit_bars(
+    dir: &str,
+    endpoint: &str,
+    params: &[(&str, &str)],
+    response: &str,
+) -> anyhow::Result<()> {
+    let now = Config::localize(OffsetDateTime::now_utc());
+    fs::create_dir_all(dir)?;
+    let path = Path::new(dir).join(format!("{}.jsonl", now.date()));
+
+    let record = serde_json::json!({
+        "requested_at": now.format(&Rfc3339)?,
+        "endpoint": endpoint,
+        "params": params.iter().copied().collect::<HashMap<_, _>>(),
+        "response": response,
+    });
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{record}")?;
+    Ok(())
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum RequestOrderStatus {
@@ -291,6 +542,16 @@ struct History<B> {
     next_page_token: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct LatestBarResponse<B> {
+    bar: Option<B>,
+}
+
+#[derive(Deserialize)]
+struct LatestQuoteResponse {
+    quote: Option<Quote>,
+}
+
 #[derive(Deserialize)]
 struct AlpacaBarsResponse<B: DeserializeOwned> {
     #[serde(
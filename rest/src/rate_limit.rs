@@ -1,6 +1,7 @@
 use std::{
     collections::VecDeque,
-    time::{Duration, Instant},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use tokio::{sync::Mutex, time::sleep};
@@ -13,6 +14,10 @@ pub struct RateLimiter {
     rate_limit: usize,
     unthrottled_budget: usize,
     throttling_duration: Duration,
+    // The number of requests Alpaca last reported as remaining, and the unix time at which that
+    // budget resets. The reset time is 0 if nothing has been reported.
+    server_remaining: AtomicU64,
+    server_reset: AtomicU64,
 }
 
 impl RateLimiter {
@@ -25,9 +30,32 @@ impl RateLimiter {
             rate_limit,
             unthrottled_budget: rate_limit - min_rate,
             throttling_duration,
+            server_remaining: AtomicU64::new(0),
+            server_reset: AtomicU64::new(0),
         }
     }
 
+    pub fn record_remaining(&self, remaining: u64, reset: u64) {
+        self.server_remaining.store(remaining, Ordering::Relaxed);
+        self.server_reset.store(reset, Ordering::Relaxed);
+    }
+
+    // Whether Alpaca reported that fewer requests remain in the current window than our own
+    // unthrottled budget allows for, such as when another client shares the same account
+    fn server_budget_low(&self) -> bool {
+        let reset = self.server_reset.load(Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(u64::MAX);
+        if now >= reset {
+            return false;
+        }
+
+        let remaining = self.server_remaining.load(Ordering::Relaxed);
+        remaining <= (self.rate_limit - self.unthrottled_budget) as u64
+    }
+
     pub async fn throttle_request(&self) {
         let mut guard = self.request_queue.lock().await;
         assert!(guard.len() <= self.rate_limit);
@@ -61,7 +89,9 @@ impl RateLimiter {
             // same time the instant before this function call, then we'd need to spread out the
             // remaining requests in our budget over the next minute. The spacing required is
             // exactly `self.throttling_duration`
-            (_, len) if len >= self.unthrottled_budget => sleep(self.throttling_duration).await,
+            (_, len) if len >= self.unthrottled_budget || self.server_budget_low() => {
+                sleep(self.throttling_duration).await
+            }
             // We're not in danger of approaching the rate limit, so we don't need to throttle
             // requests.
             _ => (),
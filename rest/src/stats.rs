@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+/// Counters for the requests sent through the REST client, broken down by endpoint.
+#[derive(Default)]
+pub struct RequestStats {
+    endpoints: RwLock<HashMap<String, EndpointCounters>>,
+    retries: AtomicU64,
+}
+
+#[derive(Default)]
+struct EndpointCounters {
+    requests: AtomicU64,
+    // Requests which failed to send or received a non-success status, including 429s
+    errors: AtomicU64,
+    rate_limited: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+pub struct EndpointStats {
+    pub endpoint: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub rate_limited: u64,
+    pub average_latency: Duration,
+}
+
+impl RequestStats {
+    pub(crate) fn record(&self, path: &str, status: Option<StatusCode>, latency: Duration) {
+        let endpoint = normalize_path(path);
+        let record = |counters: &EndpointCounters| {
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+            if !status.is_some_and(|status| status.is_success()) {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            if status == Some(StatusCode::TOO_MANY_REQUESTS) {
+                counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+            }
+            let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+            counters.latency_micros.fetch_add(micros, Ordering::Relaxed);
+        };
+
+        // The stats are never read while a panic could leave them half-written, so a poisoned lock
+        // is still safe to use
+        let endpoints = self
+            .endpoints
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(counters) = endpoints.get(&endpoint) {
+            record(counters);
+            return;
+        }
+        drop(endpoints);
+
+        let mut endpoints = self
+            .endpoints
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        record(endpoints.entry(endpoint).or_default());
+    }
+
+    /// Records that a caller is retrying a failed request.
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// The stats of every endpoint requested so far, busiest first.
+    pub fn snapshot(&self) -> Vec<EndpointStats> {
+        let endpoints = self
+            .endpoints
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let mut stats = endpoints
+            .iter()
+            .map(|(endpoint, counters)| {
+                let requests = counters.requests.load(Ordering::Relaxed);
+                let latency_micros = counters.latency_micros.load(Ordering::Relaxed);
+                EndpointStats {
+                    endpoint: endpoint.clone(),
+                    requests,
+                    errors: counters.errors.load(Ordering::Relaxed),
+                    rate_limited: counters.rate_limited.load(Ordering::Relaxed),
+                    average_latency: Duration::from_micros(latency_micros / requests.max(1)),
+                }
+            })
+            .collect::<Vec<_>>();
+        stats.sort_unstable_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then_with(|| a.endpoint.cmp(&b.endpoint))
+        });
+        stats
+    }
+}
+
+// Replaces the order IDs and symbols in a path with placeholders so that requests for different
+// orders or positions are counted under the same endpoint
+fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if Uuid::parse_str(segment).is_ok() {
+                "{id}"
+            } else if !segment.is_empty() && !segment.chars().any(|ch| ch.is_ascii_lowercase()) {
+                "{symbol}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}